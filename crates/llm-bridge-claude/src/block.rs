@@ -0,0 +1,152 @@
+//! Usage blocks across every transcript under `~/.claude/projects`.
+//!
+//! A block's limit is shared by all sessions on the account, so the
+//! tracker follows every transcript written to recently, not only those of
+//! sessions the bridge has seen. Each update only parses lines appended
+//! since the previous one.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use llm_bridge_core::block::{self, Block};
+use llm_bridge_core::provider::ProviderError;
+
+use crate::transcript::TranscriptCursor;
+
+/// How far back messages are kept: enough to follow a chain of blocks
+/// through a long day and to find the largest recent block
+const LOOKBACK_SECS: i64 = 7 * 86_400;
+
+/// Messages of the last week from every transcript under a directory
+#[derive(Debug, Default)]
+pub struct BlockTracker {
+    dir: PathBuf,
+    cursors: HashMap<PathBuf, TranscriptCursor>,
+    /// (Unix time, input + output tokens) per response, in time order
+    messages: Vec<(i64, u64)>,
+    /// Dedup keys of the responses in `messages` with their time; resumed
+    /// sessions repeat earlier responses
+    seen: HashMap<String, i64>,
+}
+
+impl BlockTracker {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Read what was appended to transcripts written to in the last week,
+    /// and forget messages older than that as of Unix time `now`
+    pub fn update(&mut self, now: i64) -> Result<(), ProviderError> {
+        let since = now - LOOKBACK_SECS;
+        let mut added = false;
+
+        // Unreadable entries are skipped rather than ending the scan, which
+        // would leave what was read so far unsorted
+        for project in fs::read_dir(&self.dir)? {
+            let Ok(project) = project.map(|entry| entry.path()) else {
+                continue;
+            };
+            let Ok(files) = fs::read_dir(&project) else {
+                continue;
+            };
+            for file in files {
+                let Ok(path) = file.map(|entry| entry.path()) else {
+                    continue;
+                };
+                if path.extension().is_none_or(|ext| ext != "jsonl") || modified(&path) < since {
+                    self.cursors.remove(&path);
+                    continue;
+                }
+                // A transcript removed since the listing is simply skipped
+                let Ok(read) = self.cursors.entry(path.clone()).or_default().read_new(&path) else {
+                    continue;
+                };
+                for entry in &read.entries {
+                    let (Some(time), Some(usage)) = (entry.time(), entry.usage()) else {
+                        continue;
+                    };
+                    if time < since {
+                        continue;
+                    }
+                    if let Some(key) = entry.dedup_key() {
+                        if self.seen.insert(key, time).is_some() {
+                            continue;
+                        }
+                    }
+                    self.messages.push((time, usage.input_tokens + usage.output_tokens));
+                    added = true;
+                }
+            }
+        }
+
+        if added {
+            self.messages.sort_unstable();
+        }
+        self.messages.retain(|&(time, _)| time >= since);
+        self.seen.retain(|_, time| *time >= since);
+        Ok(())
+    }
+
+    /// Blocks of the last week, oldest first
+    pub fn blocks(&self) -> Vec<Block> {
+        block::group(&self.messages)
+    }
+}
+
+/// Modification time of `path` as a Unix time, or 0 if unknown
+fn modified(path: &Path) -> i64 {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcript::test_lines::response;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    fn append(path: &Path, data: &str) {
+        let mut file = OpenOptions::new().create(true).append(true).open(path).unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_tracks_blocks_across_transcripts() {
+        let dir = std::env::temp_dir().join(format!("llm-bridge-blocks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("-src-a")).unwrap();
+        fs::create_dir_all(dir.join("-src-b")).unwrap();
+        let a = dir.join("-src-a/one.jsonl");
+        let b = dir.join("-src-b/two.jsonl");
+        // 2025-10-16 09:20 and 10:00 UTC
+        let now = 1_760_608_800;
+
+        append(&a, &response("m1", "2025-10-16T09:20:00Z", 100, 900));
+        append(&b, &response("m2", "2025-10-16T09:40:00.000Z", 100, 400));
+        let mut tracker = BlockTracker::new(&dir);
+        tracker.update(now).unwrap();
+        let current = block::current(&tracker.blocks(), now).unwrap();
+        assert_eq!((current.start, current.tokens), (now - 3600, 1500));
+
+        // Appended lines are read once; a resumed copy is not counted again
+        append(&a, &response("m3", "2025-10-16T09:50:00Z", 100, 400));
+        append(&b, &response("m1", "2025-10-16T09:20:00Z", 100, 900));
+        tracker.update(now).unwrap();
+        assert_eq!(block::current(&tracker.blocks(), now).unwrap().tokens, 2000);
+
+        // A week later the messages are forgotten
+        tracker.update(now + LOOKBACK_SECS).unwrap();
+        assert!(tracker.blocks().is_empty());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
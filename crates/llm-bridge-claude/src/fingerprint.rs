@@ -0,0 +1,30 @@
+use std::hash::Hasher;
+
+/// FNV-1a hasher. Unlike `DefaultHasher` its output is stable across Rust
+/// releases, so hashes can be persisted in cursor files.
+pub(crate) struct Fnv64(u64);
+
+impl Default for Fnv64 {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv64 {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv64::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
@@ -1,15 +1,37 @@
 use serde::Deserialize;
+use serde_json::{json, Value};
 
+/// The JSON object Claude Code pipes to every hook command on stdin
 #[derive(Debug, Deserialize, Default)]
 pub struct ClaudeHookPayload {
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(default)]
+    pub transcript_path: Option<String>,
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// `UserPromptSubmit`, `PreToolUse`, `PostToolUse`, `Stop`, ...
+    #[serde(default)]
+    pub hook_event_name: Option<String>,
     #[serde(default)]
     pub tool_name: Option<String>,
     #[serde(default)]
     pub tool_input: Option<serde_json::Value>,
     #[serde(default)]
+    pub tool_response: Option<serde_json::Value>,
+    #[serde(default)]
     pub prompt: Option<String>,
     #[serde(default)]
     pub error: Option<String>,
+    /// Notification text
+    #[serde(default)]
+    pub message: Option<String>,
+    /// How a session started (`startup`, `resume`, `clear`, `compact`)
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Why a session ended (`clear`, `logout`, `prompt_input_exit`, ...)
+    #[serde(default)]
+    pub reason: Option<String>,
 }
 
 impl ClaudeHookPayload {
@@ -19,4 +41,52 @@ impl ClaudeHookPayload {
         }
         serde_json::from_str(json)
     }
+
+    /// Why the tool call failed, if it did: the hook's own `error`, or an
+    /// error, failure flag or non-zero exit code in `tool_response`.
+    /// Only the first line of a message is kept.
+    pub fn tool_error(&self) -> Option<String> {
+        if let Some(error) = self.error.as_deref().and_then(first_line) {
+            return Some(error.to_string());
+        }
+        let response = self.tool_response.as_ref()?.as_object()?;
+
+        let text = |key: &str| response.get(key).and_then(Value::as_str).and_then(first_line);
+        let exit_code = ["exit_code", "exitCode", "returnCode"]
+            .iter()
+            .find_map(|key| response.get(*key)?.as_i64())
+            .filter(|&code| code != 0);
+        let failed = response.get("is_error").and_then(Value::as_bool) == Some(true)
+            || response.get("success").and_then(Value::as_bool) == Some(false);
+
+        if let Some(error) = text("error") {
+            Some(error.to_string())
+        } else if let Some(code) = exit_code {
+            Some(format!("exit code {}", code))
+        } else if failed {
+            Some(text("stderr").or(text("message")).unwrap_or("failed").to_string())
+        } else {
+            None
+        }
+    }
+}
+
+/// What a hook prints to stop the prompt (`UserPromptSubmit`) or tool call
+/// (`PreToolUse`) it was called for. Claude Code shows `reason` to the user
+/// for a prompt and to the model for a tool call.
+pub fn block_decision(hook_event_name: &str, reason: &str) -> Value {
+    match hook_event_name {
+        "PreToolUse" => json!({
+            "hookSpecificOutput": {
+                "hookEventName": "PreToolUse",
+                "permissionDecision": "deny",
+                "permissionDecisionReason": reason,
+            }
+        }),
+        _ => json!({ "decision": "block", "reason": reason }),
+    }
+}
+
+fn first_line(text: &str) -> Option<&str> {
+    text.lines().map(str::trim).find(|line| !line.is_empty())
 }
@@ -0,0 +1,322 @@
+//! Backfill the ledger from transcripts already on disk.
+//!
+//! Claude Code keeps every session's transcript under `~/.claude/projects`,
+//! in one directory per project named after the project's path with every
+//! character other than a letter or digit replaced by `-`:
+//!
+//! ```text
+//! ~/.claude/projects/-home-me-src-my-app/0b4c1f9e-....jsonl
+//! ```
+//!
+//! Each transcript becomes one ledger snapshot per local day the session was
+//! active, holding its cumulative usage up to its last message that day.
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use llm_bridge_core::ledger::{local_day, Ledger, LedgerEntry};
+use llm_bridge_core::provider::{ProviderError, UsageMetrics};
+
+use crate::pricing::{strip_snapshot_date, PricingTable};
+use crate::transcript::{TranscriptCursor, TranscriptEntry};
+use crate::usage::{accumulate, count_prompts};
+
+/// What an import added to the ledger
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportSummary {
+    /// Transcripts read
+    pub transcripts: usize,
+    /// Sessions that gained snapshots
+    pub sessions: usize,
+    pub snapshots: usize,
+    /// Cost the new snapshots add, in USD
+    pub cost: f64,
+    /// Sessions left alone because they were recorded while running
+    pub skipped: usize,
+}
+
+/// Import every transcript under `dir` into `ledger`.
+///
+/// Running it again adds nothing twice: a session the ledger recorded while
+/// it ran is skipped, and one imported before only gains snapshots newer
+/// than its last. A resumed session starts with a copy of the conversation
+/// it resumes, so each response and prompt is counted once across all
+/// transcripts, towards the oldest.
+pub fn import_transcripts(
+    dir: &Path,
+    pricing: &PricingTable,
+    ledger: &mut Ledger,
+) -> Result<ImportSummary, ProviderError> {
+    let mut transcripts = Vec::new();
+    for project in fs::read_dir(dir)? {
+        let project = project?.path();
+        let Some(name) = project.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !project.is_dir() {
+            continue;
+        }
+        let cwd = decode_project_dir(name);
+        for file in fs::read_dir(&project)? {
+            let path = file?.path();
+            if path.extension().is_some_and(|ext| ext == "jsonl") {
+                let read = TranscriptCursor::default().read_new(&path)?;
+                let started = read.entries.iter().chain(&read.prompts).filter_map(TranscriptEntry::time).min();
+                transcripts.push((started, path, cwd.clone(), read));
+            }
+        }
+    }
+    // Oldest conversation first, so resumed copies come after the original
+    transcripts.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+
+    let mut summary = ImportSummary::default();
+    let mut seen = HashSet::new();
+    for (_, path, cwd, read) in transcripts {
+        let Some(session_id) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        summary.transcripts += 1;
+
+        // Count the session's usage even when it is skipped, so resumed
+        // copies of its conversation are not counted again
+        let mut snapshots = session_history(session_id, &cwd, &read.entries, &read.prompts, pricing, &mut seen);
+        let mut previous = 0.0;
+        match ledger.session(session_id) {
+            Some(last) if !last.imported => {
+                summary.skipped += 1;
+                continue;
+            }
+            Some(last) => {
+                // A lower cost would read as a restarted session
+                snapshots.retain(|s| s.time > last.time && s.cost >= last.cost);
+                previous = last.cost;
+            }
+            None => {}
+        }
+
+        let mut written = 0;
+        for snapshot in snapshots {
+            let cost = snapshot.cost;
+            if ledger.record(snapshot)? {
+                written += 1;
+                summary.cost += cost - previous;
+                previous = cost;
+            }
+        }
+        if written > 0 {
+            summary.sessions += 1;
+            summary.snapshots += written;
+        }
+    }
+    Ok(summary)
+}
+
+/// Snapshots of a session at the end of each local day it was active
+fn session_history(
+    session_id: &str,
+    cwd: &Path,
+    entries: &[TranscriptEntry],
+    prompts: &[TranscriptEntry],
+    pricing: &PricingTable,
+    seen: &mut HashSet<String>,
+) -> Vec<LedgerEntry> {
+    let mut timeline: Vec<(i64, &TranscriptEntry)> = entries
+        .iter()
+        .chain(prompts)
+        .filter_map(|entry| Some((entry.time()?, entry)))
+        .collect();
+    timeline.sort_by_key(|&(time, _)| time);
+
+    let mut totals = UsageMetrics::default();
+    let mut recorded = UsageMetrics::default();
+    let mut model = String::new();
+    let mut snapshots = Vec::new();
+    for (i, &(time, entry)) in timeline.iter().enumerate() {
+        if entry.usage().is_some() {
+            accumulate(&mut totals, std::slice::from_ref(entry), pricing, seen);
+            if let Some(id) = entry.model().filter(|id| *id != "<synthetic>") {
+                model = display_name(id);
+            }
+        } else {
+            count_prompts(&mut totals, std::slice::from_ref(entry), seen);
+        }
+
+        let day_ends = timeline.get(i + 1).is_none_or(|&(next, _)| local_day(next) != local_day(time));
+        if day_ends && totals != recorded {
+            snapshots.push(LedgerEntry {
+                time,
+                session_id: session_id.to_string(),
+                cwd: cwd.to_string_lossy().into_owned(),
+                model: model.clone(),
+                cost: totals.estimated_cost,
+                input_tokens: totals.input_tokens,
+                output_tokens: totals.output_tokens,
+                cache_read: totals.cache_read,
+                cache_write: totals.cache_write,
+                turns: totals.turns,
+                imported: true,
+            });
+            recorded = totals.clone();
+        }
+    }
+    snapshots
+}
+
+/// `claude-opus-4-5-20251101` -> `Opus 4.5`, the name the status line
+/// reports; ids of any other form are kept as they are
+fn display_name(model: &str) -> String {
+    let id = strip_snapshot_date(model).unwrap_or(model);
+    let Some(id) = id.strip_prefix("claude-") else {
+        return model.to_string();
+    };
+    let (version, family): (Vec<&str>, Vec<&str>) =
+        id.split('-').partition(|part| part.bytes().all(|b| b.is_ascii_digit()));
+
+    match family.as_slice() {
+        [family] if !version.is_empty() => {
+            let mut chars = family.chars();
+            let first = chars.next().map(|c| c.to_ascii_uppercase()).unwrap_or_default();
+            format!("{}{} {}", first, chars.as_str(), version.join("."))
+        }
+        _ => model.to_string(),
+    }
+}
+
+/// The project path a transcript directory is named after.
+///
+/// The name cannot be decoded on its own, since `/`, `.`, `-` and `_` all
+/// became `-`, so it is matched against the directories that exist. A path
+/// that no longer exists is assumed to have had only `/` separators.
+pub fn decode_project_dir(name: &str) -> PathBuf {
+    let encoded = name.strip_prefix('-').unwrap_or(name);
+    find_encoded(Path::new("/"), encoded).unwrap_or_else(|| Path::new("/").join(encoded.replace('-', "/")))
+}
+
+/// A directory under `dir` whose path below `dir` encodes to `rest`,
+/// trying longer names first
+fn find_encoded(dir: &Path, rest: &str) -> Option<PathBuf> {
+    if rest.is_empty() {
+        return Some(dir.to_path_buf());
+    }
+
+    let mut candidates: Vec<(String, &str)> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|name| {
+            let after = rest.strip_prefix(encode_project_dir(&name).as_str())?;
+            let after = if after.is_empty() { after } else { after.strip_prefix('-')? };
+            Some((name, after))
+        })
+        .collect();
+    candidates.sort_by_key(|(name, _)| Reverse(name.len()));
+
+    candidates
+        .into_iter()
+        .find_map(|(name, after)| find_encoded(&dir.join(name), after))
+}
+
+/// Claude Code's directory name for a project path
+fn encode_project_dir(path: &str) -> String {
+    path.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '-' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcript::test_lines::response;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("llm_test_import_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn prompt(uuid: &str, timestamp: &str) -> String {
+        format!(
+            r#"{{"type":"user","uuid":"{}","timestamp":"{}","message":{{"role":"user","content":"go"}}}}"#,
+            uuid, timestamp
+        ) + "\n"
+    }
+
+    #[test]
+    fn test_display_name() {
+        assert_eq!(display_name("claude-opus-4-5-20251101"), "Opus 4.5");
+        assert_eq!(display_name("claude-sonnet-4-20250514"), "Sonnet 4");
+        assert_eq!(display_name("claude-3-5-haiku-20241022"), "Haiku 3.5");
+        assert_eq!(display_name("claude-3-5-sonnet-latest"), "claude-3-5-sonnet-latest");
+        assert_eq!(display_name("gpt-5"), "gpt-5");
+    }
+
+    #[test]
+    fn test_decode_project_dir_matches_existing_directories() {
+        let dir = temp_dir("decode");
+        let project = dir.join("my-app.v2/src_x");
+        fs::create_dir_all(&project).unwrap();
+        fs::create_dir_all(dir.join("my/app")).unwrap();
+
+        let name = encode_project_dir(project.to_str().unwrap());
+        assert_eq!(decode_project_dir(&name), project);
+        assert_eq!(decode_project_dir("-no-such-dir"), PathBuf::from("/no/such/dir"));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_import_is_idempotent() {
+        let dir = temp_dir("ledger");
+        let projects = dir.join("projects");
+        let project = dir.join("app");
+        let transcripts = projects.join(encode_project_dir(project.to_str().unwrap()));
+        fs::create_dir_all(&transcripts).unwrap();
+        fs::create_dir_all(&project).unwrap();
+
+        // Two days of one session, then a resumed copy that adds a response
+        let first_day = prompt("p1", "2025-10-10T12:00:00Z") + &response("a", "2025-10-10T12:01:00Z", 0, 1_000_000);
+        let second_day = prompt("p2", "2025-10-12T12:00:00Z") + &response("b", "2025-10-12T12:01:00Z", 0, 1_000_000);
+        fs::write(transcripts.join("first.jsonl"), first_day.clone() + &second_day).unwrap();
+        let resumed = first_day + &second_day + &response("c", "2025-10-13T12:00:00Z", 0, 2_000_000);
+        fs::write(transcripts.join("resumed.jsonl"), resumed).unwrap();
+
+        let ledger_path = dir.join("ledger.jsonl");
+        let pricing = PricingTable::builtin();
+        let mut ledger = Ledger::open(&ledger_path).unwrap();
+        let summary = import_transcripts(&projects, &pricing, &mut ledger).unwrap();
+        assert_eq!((summary.transcripts, summary.sessions, summary.snapshots), (2, 2, 3));
+        // Opus 4.5 output is $25 per million tokens
+        assert!((summary.cost - 100.0).abs() < 1e-9, "{:?}", summary);
+
+        let first = ledger.session("first").unwrap();
+        assert_eq!((first.turns, first.output_tokens, first.model.as_str()), (2, 2_000_000, "Opus 4.5"));
+        assert_eq!(first.cwd, project.to_str().unwrap());
+        assert!(first.imported);
+        assert_eq!(ledger.session("resumed").unwrap().turns, 0);
+
+        // Nothing is added twice, however often it runs
+        let mut reopened = Ledger::open(&ledger_path).unwrap();
+        assert_eq!(import_transcripts(&projects, &pricing, &mut reopened).unwrap().snapshots, 0);
+
+        // A session that continued gains only its new day
+        fs::OpenOptions::new()
+            .append(true)
+            .open(transcripts.join("first.jsonl"))
+            .and_then(|mut f| std::io::Write::write_all(&mut f, response("d", "2025-10-14T12:00:00Z", 0, 400_000).as_bytes()))
+            .unwrap();
+        let summary = import_transcripts(&projects, &pricing, &mut reopened).unwrap();
+        assert_eq!(summary.snapshots, 1);
+        assert!((summary.cost - 10.0).abs() < 1e-9, "{:?}", summary);
+
+        // Sessions recorded while running are left alone
+        let mut live = Ledger::new(&dir.join("live.jsonl"));
+        live.record(LedgerEntry { session_id: "first".into(), cost: 1.0, ..Default::default() }).unwrap();
+        let summary = import_transcripts(&projects, &pricing, &mut live).unwrap();
+        assert_eq!((summary.skipped, summary.sessions), (1, 1));
+        assert_eq!(live.session("first").unwrap().cost, 1.0);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
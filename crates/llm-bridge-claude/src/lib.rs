@@ -1,6 +1,12 @@
+pub mod block;
 pub mod hooks;
+pub mod import;
+pub mod pricing;
+pub mod statusline;
+pub mod tool_summary;
 pub mod transcript;
 pub mod usage;
 
+mod fingerprint;
 mod provider;
 pub use provider::ClaudeProvider;
@@ -0,0 +1,425 @@
+//! Per-model token pricing for Claude models.
+//!
+//! Prices are USD per million tokens. The built-in table covers the Claude
+//! models Claude Code ships with; a TOML pricing file can override entries,
+//! add new models, alias custom model ids and record dated price changes:
+//!
+//! ```toml
+//! [aliases]
+//! "my-proxy-model" = "claude-sonnet-4-5-20250929"
+//!
+//! [[models]]
+//! id = "claude-opus-4-5-20251101"
+//! aliases = ["opus"]
+//! since = "2026-01-01"    # optional, applies from this date (UTC)
+//! input = 5.0
+//! output = 25.0
+//! cache_read = 0.50
+//! cache_write = 6.25
+//! ```
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use thiserror::Error;
+
+use crate::fingerprint::Fnv64;
+use crate::transcript::TokenUsage;
+
+#[derive(Error, Debug)]
+pub enum PricingError {
+    #[error("Failed to read pricing file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse pricing file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Invalid pricing entry: {0}")]
+    Invalid(String),
+}
+
+/// Token prices for one model, in USD per million tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+    pub cache_read: f64,
+    pub cache_write: f64,
+}
+
+impl ModelPrice {
+    const fn new(input: f64, output: f64, cache_read: f64, cache_write: f64) -> Self {
+        Self { input, output, cache_read, cache_write }
+    }
+
+    /// Cost in USD of a single usage record at these prices
+    pub fn cost(&self, usage: &TokenUsage) -> f64 {
+        (usage.input_tokens as f64 * self.input
+            + usage.output_tokens as f64 * self.output
+            + usage.cache_read_input_tokens as f64 * self.cache_read
+            + usage.cache_creation_input_tokens as f64 * self.cache_write)
+            / 1_000_000.0
+    }
+}
+
+const OPUS_4_5: ModelPrice = ModelPrice::new(5.0, 25.0, 0.50, 6.25);
+const OPUS: ModelPrice = ModelPrice::new(15.0, 75.0, 1.50, 18.75);
+const SONNET: ModelPrice = ModelPrice::new(3.0, 15.0, 0.30, 3.75);
+const HAIKU_4_5: ModelPrice = ModelPrice::new(1.0, 5.0, 0.10, 1.25);
+const HAIKU_3_5: ModelPrice = ModelPrice::new(0.80, 4.0, 0.08, 1.0);
+const HAIKU_3: ModelPrice = ModelPrice::new(0.25, 1.25, 0.03, 0.30);
+
+/// Used for models that are in neither the built-in table nor the pricing file
+const FALLBACK: ModelPrice = SONNET;
+
+const BUILTIN_MODELS: &[(&str, &[&str], ModelPrice)] = &[
+    ("claude-opus-4-5-20251101", &["claude-opus-4-5"], OPUS_4_5),
+    ("claude-opus-4-1-20250805", &["claude-opus-4-1"], OPUS),
+    ("claude-opus-4-20250514", &["claude-opus-4-0", "claude-opus-4"], OPUS),
+    ("claude-sonnet-4-5-20250929", &["claude-sonnet-4-5"], SONNET),
+    ("claude-sonnet-4-20250514", &["claude-sonnet-4-0", "claude-sonnet-4"], SONNET),
+    ("claude-3-7-sonnet-20250219", &["claude-3-7-sonnet-latest"], SONNET),
+    ("claude-3-5-sonnet-20241022", &["claude-3-5-sonnet-latest"], SONNET),
+    ("claude-3-5-sonnet-20240620", &[], SONNET),
+    ("claude-haiku-4-5-20251001", &["claude-haiku-4-5"], HAIKU_4_5),
+    ("claude-3-5-haiku-20241022", &["claude-3-5-haiku-latest"], HAIKU_3_5),
+    ("claude-3-opus-20240229", &["claude-3-opus-latest"], OPUS),
+    ("claude-3-haiku-20240307", &[], HAIKU_3),
+];
+
+#[derive(Debug, Clone)]
+struct DatedPrice {
+    /// First day (YYYY-MM-DD, UTC) the price applies; `None` means always
+    since: Option<String>,
+    price: ModelPrice,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PricingFile {
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+    #[serde(default)]
+    models: Vec<PricingFileModel>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PricingFileModel {
+    id: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    since: Option<String>,
+    input: f64,
+    output: f64,
+    cache_read: f64,
+    cache_write: f64,
+}
+
+/// Lookup table from model id to price, with aliases and dated price changes
+#[derive(Debug, Clone)]
+pub struct PricingTable {
+    models: HashMap<String, Vec<DatedPrice>>,
+    aliases: HashMap<String, String>,
+}
+
+impl Default for PricingTable {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl PricingTable {
+    /// Table containing only the built-in Claude models
+    pub fn builtin() -> Self {
+        let mut table = Self {
+            models: HashMap::new(),
+            aliases: HashMap::new(),
+        };
+
+        for (id, aliases, price) in BUILTIN_MODELS {
+            table.models.insert(
+                id.to_string(),
+                vec![DatedPrice { since: None, price: *price }],
+            );
+            for alias in aliases.iter() {
+                table.aliases.insert(alias.to_string(), id.to_string());
+            }
+        }
+
+        table
+    }
+
+    /// Built-in table extended with the pricing file at `path`, if it exists
+    pub fn load(path: &Path) -> Result<Self, PricingError> {
+        let mut table = Self::builtin();
+        if path.exists() {
+            table.merge_toml(&fs::read_to_string(path)?)?;
+        }
+        Ok(table)
+    }
+
+    /// Merge a TOML pricing document into this table.
+    ///
+    /// An entry with the same id and `since` as an existing one replaces it;
+    /// any other entry is added as a new (possibly dated) price.
+    pub fn merge_toml(&mut self, toml_str: &str) -> Result<(), PricingError> {
+        let file: PricingFile = toml::from_str(toml_str)?;
+        // Merge into a copy, so that an invalid document leaves the table as it was
+        let mut merged = self.clone();
+
+        for model in file.models {
+            if model.id.is_empty() {
+                return Err(PricingError::Invalid("model id must not be empty".to_string()));
+            }
+            if let Some(since) = &model.since {
+                if !is_iso_date(since) {
+                    return Err(PricingError::Invalid(format!(
+                        "{}: `since` must be a YYYY-MM-DD date, got {:?}",
+                        model.id, since
+                    )));
+                }
+            }
+            let price = ModelPrice::new(model.input, model.output, model.cache_read, model.cache_write);
+            if [price.input, price.output, price.cache_read, price.cache_write]
+                .iter()
+                .any(|v| !v.is_finite() || *v < 0.0)
+            {
+                return Err(PricingError::Invalid(format!(
+                    "{}: prices must be non-negative numbers",
+                    model.id
+                )));
+            }
+
+            let prices = merged.models.entry(model.id.clone()).or_default();
+            prices.retain(|d| d.since != model.since);
+            prices.push(DatedPrice { since: model.since, price });
+            // Undated entries sort first, then by date
+            prices.sort_by(|a, b| a.since.cmp(&b.since));
+
+            for alias in model.aliases {
+                merged.aliases.insert(alias, model.id.clone());
+            }
+        }
+
+        for (alias, target) in file.aliases {
+            merged.aliases.insert(alias, target);
+        }
+
+        for (alias, target) in &merged.aliases {
+            if !merged.models.contains_key(target) {
+                return Err(PricingError::Invalid(format!(
+                    "alias {:?} points to unknown model {:?}",
+                    alias, target
+                )));
+            }
+        }
+
+        *self = merged;
+        Ok(())
+    }
+
+    /// Stable hash of the table contents, used to invalidate running totals
+    /// that were priced with a different table
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = Fnv64::default();
+
+        let mut ids: Vec<_> = self.models.keys().collect();
+        ids.sort();
+        for id in ids {
+            id.hash(&mut hasher);
+            for dated in &self.models[id] {
+                dated.since.hash(&mut hasher);
+                let p = dated.price;
+                for v in [p.input, p.output, p.cache_read, p.cache_write] {
+                    v.to_bits().hash(&mut hasher);
+                }
+            }
+        }
+
+        let mut aliases: Vec<_> = self.aliases.iter().collect();
+        aliases.sort();
+        aliases.hash(&mut hasher);
+
+        hasher.finish()
+    }
+
+    /// Resolve a model id or alias to the canonical id in this table.
+    /// Falls back to matching without a trailing `-YYYYMMDD` snapshot date.
+    pub fn resolve<'a>(&'a self, model: &str) -> Option<&'a str> {
+        let lookup = |name: &str| -> Option<&'a str> {
+            if let Some((id, _)) = self.models.get_key_value(name) {
+                return Some(id.as_str());
+            }
+            self.aliases.get(name).map(|s| s.as_str())
+        };
+
+        lookup(model).or_else(|| strip_snapshot_date(model).and_then(lookup))
+    }
+
+    /// Price for `model` on `date` (an ISO-8601 timestamp or date; only the
+    /// `YYYY-MM-DD` prefix is used). Unknown models use Sonnet pricing.
+    pub fn price_for(&self, model: Option<&str>, date: Option<&str>) -> ModelPrice {
+        let prices = match model.and_then(|m| self.resolve(m)).and_then(|id| self.models.get(id)) {
+            Some(prices) => prices,
+            None => return FALLBACK,
+        };
+
+        let day = date.and_then(|d| d.get(..10));
+        let applicable = prices.iter().rev().find(|p| match (&p.since, day) {
+            (None, _) => true,
+            (Some(since), Some(day)) => since.as_str() <= day,
+            // No date to compare against: use the newest price
+            (Some(_), None) => true,
+        });
+
+        applicable.or_else(|| prices.first()).map(|p| p.price).unwrap_or(FALLBACK)
+    }
+}
+
+fn is_iso_date(s: &str) -> bool {
+    let b = s.as_bytes();
+    b.len() == 10
+        && b[4] == b'-'
+        && b[7] == b'-'
+        && b.iter().enumerate().all(|(i, c)| i == 4 || i == 7 || c.is_ascii_digit())
+}
+
+/// `claude-sonnet-4-5-20250929` -> `claude-sonnet-4-5`
+pub(crate) fn strip_snapshot_date(model: &str) -> Option<&str> {
+    let (base, suffix) = model.rsplit_once('-')?;
+    (suffix.len() == 8 && suffix.bytes().all(|c| c.is_ascii_digit())).then_some(base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(input: u64, output: u64) -> TokenUsage {
+        TokenUsage {
+            input_tokens: input,
+            output_tokens: output,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_builtin_prices_by_model() {
+        let table = PricingTable::builtin();
+        let u = usage(1_000_000, 1_000_000);
+
+        assert_eq!(table.price_for(Some("claude-opus-4-1-20250805"), None).cost(&u), 90.0);
+        assert_eq!(table.price_for(Some("claude-sonnet-4-5-20250929"), None).cost(&u), 18.0);
+        assert_eq!(table.price_for(Some("claude-3-5-haiku-20241022"), None).cost(&u), 4.8);
+    }
+
+    #[test]
+    fn test_resolve_aliases_and_snapshot_dates() {
+        let table = PricingTable::builtin();
+
+        assert_eq!(table.resolve("claude-opus-4-5"), Some("claude-opus-4-5-20251101"));
+        // Unknown snapshot of a known alias
+        assert_eq!(table.resolve("claude-sonnet-4-5-20991231"), Some("claude-sonnet-4-5-20250929"));
+        assert_eq!(table.resolve("gpt-4"), None);
+    }
+
+    #[test]
+    fn test_unknown_model_uses_fallback() {
+        let table = PricingTable::builtin();
+        assert_eq!(table.price_for(Some("mystery-model"), None), SONNET);
+        assert_eq!(table.price_for(None, None), SONNET);
+    }
+
+    #[test]
+    fn test_user_file_overrides_and_extends() {
+        let mut table = PricingTable::builtin();
+        table
+            .merge_toml(
+                r#"
+                [aliases]
+                "proxy/sonnet" = "claude-sonnet-4-5-20250929"
+
+                [[models]]
+                id = "claude-sonnet-4-5-20250929"
+                input = 2.0
+                output = 10.0
+                cache_read = 0.2
+                cache_write = 2.5
+
+                [[models]]
+                id = "local-llama"
+                aliases = ["llama"]
+                input = 0.0
+                output = 0.0
+                cache_read = 0.0
+                cache_write = 0.0
+                "#,
+            )
+            .unwrap();
+
+        assert_eq!(table.price_for(Some("proxy/sonnet"), None).input, 2.0);
+        assert_eq!(table.price_for(Some("llama"), None).output, 0.0);
+        // Untouched built-ins remain
+        assert_eq!(table.price_for(Some("claude-opus-4-20250514"), None), OPUS);
+    }
+
+    #[test]
+    fn test_dated_price_changes() {
+        let mut table = PricingTable::builtin();
+        table
+            .merge_toml(
+                r#"
+                [[models]]
+                id = "claude-opus-4-5-20251101"
+                since = "2026-03-01"
+                input = 4.0
+                output = 20.0
+                cache_read = 0.4
+                cache_write = 5.0
+                "#,
+            )
+            .unwrap();
+
+        let model = Some("claude-opus-4-5-20251101");
+        assert_eq!(table.price_for(model, Some("2026-02-28T23:59:59.000Z")).input, 5.0);
+        assert_eq!(table.price_for(model, Some("2026-03-01T00:00:00.000Z")).input, 4.0);
+        assert_eq!(table.price_for(model, None).input, 4.0);
+    }
+
+    #[test]
+    fn test_invalid_pricing_files_are_rejected() {
+        let mut table = PricingTable::builtin();
+
+        let bad_alias = table.merge_toml("[aliases]\nfoo = \"no-such-model\"");
+        assert!(matches!(bad_alias, Err(PricingError::Invalid(_))));
+
+        let bad_date = table.merge_toml(
+            "[[models]]\nid = \"x\"\nsince = \"March\"\ninput = 1.0\noutput = 1.0\ncache_read = 0.0\ncache_write = 0.0",
+        );
+        assert!(matches!(bad_date, Err(PricingError::Invalid(_))));
+
+        let unknown_key = table.merge_toml("[[models]]\nid = \"x\"\nprice = 1.0");
+        assert!(matches!(unknown_key, Err(PricingError::Parse(_))));
+
+        // Prices before the bad alias are not merged either
+        let half = table.merge_toml(
+            "[[models]]\nid = \"x\"\ninput = 1.0\noutput = 1.0\ncache_read = 0.0\ncache_write = 0.0\n\n[aliases]\nfoo = \"no-such-model\"",
+        );
+        assert!(matches!(half, Err(PricingError::Invalid(_))));
+        assert!(table.resolve("x").is_none());
+        assert_eq!(table.fingerprint(), PricingTable::builtin().fingerprint());
+    }
+
+    #[test]
+    fn test_fingerprint_tracks_price_changes() {
+        let builtin = PricingTable::builtin();
+        assert_eq!(builtin.fingerprint(), PricingTable::builtin().fingerprint());
+
+        let mut changed = PricingTable::builtin();
+        changed
+            .merge_toml("[[models]]\nid = \"claude-3-haiku-20240307\"\ninput = 0.3\noutput = 1.25\ncache_read = 0.03\ncache_write = 0.3")
+            .unwrap();
+        assert_ne!(builtin.fingerprint(), changed.fingerprint());
+    }
+}
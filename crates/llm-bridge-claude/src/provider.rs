@@ -1,14 +1,45 @@
-use std::path::Path;
-use llm_bridge_core::provider::{LlmProvider, LlmEvent, UsageMetrics, ProviderError};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use llm_bridge_core::provider::{LlmProvider, LlmEvent, HookEvent, UsageMetrics, ProviderError};
+use crate::fingerprint::fnv1a;
 use crate::hooks::ClaudeHookPayload;
-use crate::transcript::parse_transcript_tail;
-use crate::usage::calculate_cost;
+use crate::pricing::PricingTable;
+use crate::transcript::last_api_error;
+use crate::usage::UsageTracker;
 
-pub struct ClaudeProvider;
+pub struct ClaudeProvider {
+    pricing: PricingTable,
+    /// Where transcript cursors are persisted between invocations
+    cursor_dir: Option<PathBuf>,
+    trackers: Mutex<HashMap<PathBuf, UsageTracker>>,
+}
 
 impl ClaudeProvider {
     pub fn new() -> Self {
-        Self
+        Self::with_pricing(PricingTable::builtin())
+    }
+
+    pub fn with_pricing(pricing: PricingTable) -> Self {
+        Self {
+            pricing,
+            cursor_dir: None,
+            trackers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Persist transcript cursors in `dir`, so repeated short-lived
+    /// invocations only parse what was appended since the last one
+    pub fn with_cursor_dir(mut self, dir: PathBuf) -> Self {
+        self.cursor_dir = Some(dir);
+        self
+    }
+
+    fn cursor_path(&self, log_path: &Path) -> Option<PathBuf> {
+        let dir = self.cursor_dir.as_ref()?;
+        let stem = log_path.file_stem()?.to_string_lossy();
+        let hash = fnv1a(log_path.as_os_str().as_encoded_bytes());
+        Some(dir.join(format!("{}-{:016x}.json", stem, hash)))
     }
 }
 
@@ -23,32 +54,179 @@ impl LlmProvider for ClaudeProvider {
         "claude"
     }
 
-    fn parse_event(&self, event_type: &str, payload: Option<&str>) -> Result<LlmEvent, ProviderError> {
+    fn parse_event(&self, event_type: &str, payload: Option<&str>) -> Result<HookEvent, ProviderError> {
         let hook_payload = payload
             .map(ClaudeHookPayload::from_json)
             .transpose()
             .map_err(|e| ProviderError::ParseEvent(e.to_string()))?
             .unwrap_or_default();
 
-        match event_type {
-            "submit" => Ok(LlmEvent::Submit {
+        let event = match event_type {
+            "submit" => LlmEvent::Submit {
                 prompt: hook_payload.prompt,
-            }),
-            "tool-start" => Ok(LlmEvent::ToolStart {
+            },
+            "tool-start" => LlmEvent::ToolStart {
                 tool: hook_payload.tool_name.unwrap_or_else(|| "unknown".to_string()),
-                input: hook_payload.tool_input.map(|v| v.to_string()),
-            }),
-            "tool-end" => Ok(LlmEvent::ToolEnd {
+                input: hook_payload.tool_input,
+            },
+            "tool-end" => LlmEvent::ToolEnd {
+                error: hook_payload.tool_error(),
                 tool: hook_payload.tool_name.unwrap_or_else(|| "unknown".to_string()),
-                error: hook_payload.error,
-            }),
-            "stop" => Ok(LlmEvent::Stop),
-            other => Err(ProviderError::ParseEvent(format!("Unknown event type: {}", other))),
-        }
+            },
+            "stop" => LlmEvent::Stop {
+                error: hook_payload
+                    .transcript_path
+                    .as_deref()
+                    .and_then(|path| last_api_error(Path::new(path)).ok().flatten()),
+            },
+            "notification" => LlmEvent::Notification {
+                message: hook_payload.message,
+            },
+            "subagent-stop" => LlmEvent::SubagentStop,
+            "pre-compact" => LlmEvent::PreCompact,
+            "session-start" => LlmEvent::SessionStart {
+                source: hook_payload.source,
+            },
+            "session-end" => LlmEvent::SessionEnd {
+                reason: hook_payload.reason,
+            },
+            other => return Err(ProviderError::ParseEvent(format!("Unknown event type: {}", other))),
+        };
+
+        Ok(HookEvent {
+            event,
+            session_id: hook_payload.session_id,
+            cwd: hook_payload.cwd,
+            transcript_path: hook_payload.transcript_path.map(PathBuf::from),
+        })
     }
 
+    /// Cumulative usage for the whole transcript. Only lines appended since
+    /// the previous call (in this process, or persisted in the cursor
+    /// directory) are parsed.
     fn parse_usage(&self, log_path: &Path) -> Result<UsageMetrics, ProviderError> {
-        let entries = parse_transcript_tail(log_path, 100)?;
-        Ok(calculate_cost(&entries))
+        let mut trackers = self.trackers.lock().unwrap_or_else(|e| e.into_inner());
+        let cursor_path = self.cursor_path(log_path);
+
+        let tracker = trackers.entry(log_path.to_path_buf()).or_insert_with(|| {
+            cursor_path
+                .as_ref()
+                .and_then(|p| UsageTracker::load(p, log_path))
+                .unwrap_or_else(|| UsageTracker::new(log_path))
+        });
+
+        let totals = tracker.update(&self.pricing)?.clone();
+
+        if let Some(path) = cursor_path {
+            let _ = tracker.save(&path); // Best effort, a lost cursor only costs a rescan
+        }
+
+        Ok(totals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_event_from_hook_payload() {
+        let payload = r#"{
+            "session_id": "abc-123",
+            "transcript_path": "/home/me/.claude/projects/p/abc-123.jsonl",
+            "cwd": "/home/me/project",
+            "hook_event_name": "PreToolUse",
+            "tool_name": "Bash",
+            "tool_input": {"command": "cargo test"}
+        }"#;
+
+        let hook = ClaudeProvider::new().parse_event("tool-start", Some(payload)).unwrap();
+
+        assert_eq!(hook.session_id.as_deref(), Some("abc-123"));
+        assert_eq!(hook.cwd.as_deref(), Some("/home/me/project"));
+        assert_eq!(
+            hook.transcript_path,
+            Some(PathBuf::from("/home/me/.claude/projects/p/abc-123.jsonl"))
+        );
+        assert_eq!(hook.tool(), Some("Bash"));
+        match hook.event {
+            LlmEvent::ToolStart { input, .. } => assert_eq!(input.unwrap()["command"], "cargo test"),
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_notification_and_session_events() {
+        let provider = ClaudeProvider::new();
+
+        let hook = provider
+            .parse_event(
+                "notification",
+                Some(r#"{"hook_event_name":"Notification","message":"Claude needs your permission to use Bash"}"#),
+            )
+            .unwrap();
+        assert!(matches!(hook.event, LlmEvent::Notification { message: Some(_) }));
+
+        let hook = provider
+            .parse_event("session-end", Some(r#"{"session_id":"abc","reason":"logout"}"#))
+            .unwrap();
+        assert_eq!(hook.session_id.as_deref(), Some("abc"));
+        assert!(matches!(hook.event, LlmEvent::SessionEnd { reason: Some(r) } if r == "logout"));
+    }
+
+    #[test]
+    fn test_parse_failed_tool_end() {
+        let provider = ClaudeProvider::new();
+        let tool_end = |payload: &str| match provider.parse_event("tool-end", Some(payload)).unwrap().event {
+            LlmEvent::ToolEnd { error, .. } => error,
+            other => panic!("unexpected event {:?}", other),
+        };
+
+        assert_eq!(
+            tool_end(r#"{"tool_name":"Bash","tool_response":{"stdout":"","stderr":"boom","exit_code":101}}"#).as_deref(),
+            Some("exit code 101")
+        );
+        assert_eq!(
+            tool_end(r#"{"tool_name":"Edit","tool_response":{"is_error":true,"stderr":"\nString not found\nmore"}}"#)
+                .as_deref(),
+            Some("String not found")
+        );
+        assert_eq!(
+            tool_end(r#"{"tool_name":"Read","error":"File does not exist."}"#).as_deref(),
+            Some("File does not exist.")
+        );
+        assert_eq!(tool_end(r#"{"tool_name":"Bash","tool_response":{"stdout":"ok","exit_code":0}}"#), None);
+        assert_eq!(tool_end(r#"{"tool_name":"Read","tool_response":"file contents"}"#), None);
+    }
+
+    #[test]
+    fn test_parse_stop_after_api_error() {
+        let path = std::env::temp_dir().join(format!("llm_test_api_error_{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            concat!(
+                r#"{"type":"user","message":{"role":"user","content":"hi"}}"#,
+                "\n",
+                r#"{"type":"assistant","isApiErrorMessage":true,"message":{"content":[{"type":"text","text":"API Error: 529 Overloaded"}]}}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+        let payload = format!(r#"{{"transcript_path":"{}"}}"#, path.display());
+
+        let hook = ClaudeProvider::new().parse_event("stop", Some(&payload)).unwrap();
+        assert!(matches!(hook.event, LlmEvent::Stop { error: Some(e) } if e == "API Error: 529 Overloaded"));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_parse_event_without_payload() {
+        let hook = ClaudeProvider::new().parse_event("stop", None).unwrap();
+        assert!(matches!(hook.event, LlmEvent::Stop { error: None }));
+        assert_eq!(hook.session_id, None);
+
+        assert!(ClaudeProvider::new().parse_event("stop", Some("not json")).is_err());
+        assert!(ClaudeProvider::new().parse_event("launch", None).is_err());
     }
 }
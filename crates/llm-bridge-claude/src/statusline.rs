@@ -0,0 +1,220 @@
+use llm_bridge_core::{ContextConfig, WaybarState};
+use serde::Deserialize;
+
+/// Context window of current Claude models
+pub const DEFAULT_CONTEXT_WINDOW: u64 = 200_000;
+/// Context window of the 1M-token variants (`[1m]` model ids)
+pub const EXTENDED_CONTEXT_WINDOW: u64 = 1_000_000;
+
+/// JSON input from Claude Code's statusLine hook
+#[derive(Debug, Deserialize, Default)]
+pub struct StatuslinePayload {
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(default)]
+    pub transcript_path: Option<String>,
+    #[serde(default)]
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub model: Option<ModelInfo>,
+    #[serde(default)]
+    pub cost: Option<CostInfo>,
+    #[serde(default)]
+    pub context_window: Option<ContextWindow>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ModelInfo {
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CostInfo {
+    #[serde(default)]
+    pub total_cost_usd: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ContextWindow {
+    /// Reported by newer Claude Code versions; preferred over the model table
+    #[serde(default)]
+    pub context_window_size: Option<u64>,
+    #[serde(default)]
+    pub current_usage: Option<CurrentUsage>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CurrentUsage {
+    #[serde(default)]
+    pub input_tokens: Option<u64>,
+    #[serde(default)]
+    pub output_tokens: Option<u64>,
+    #[serde(default)]
+    pub cache_creation_input_tokens: Option<u64>,
+    #[serde(default)]
+    pub cache_read_input_tokens: Option<u64>,
+}
+
+impl StatuslinePayload {
+    /// Parse a payload, treating malformed input as empty
+    pub fn from_json(json: &str) -> Self {
+        serde_json::from_str(json).unwrap_or_default()
+    }
+
+    /// Display name, falling back to the model id
+    pub fn model_name(&self) -> Option<&str> {
+        let model = self.model.as_ref()?;
+        model.display_name.as_deref().or(model.id.as_deref())
+    }
+
+    pub fn total_cost(&self) -> Option<f64> {
+        self.cost.as_ref().and_then(|c| c.total_cost_usd)
+    }
+
+    pub fn current_usage(&self) -> Option<&CurrentUsage> {
+        self.context_window.as_ref()?.current_usage.as_ref()
+    }
+
+    /// Tokens occupying the context window: the last request's input,
+    /// including cached prefix reads and writes
+    pub fn context_used(&self) -> Option<u64> {
+        let usage = self.current_usage()?;
+        Some(
+            usage.input_tokens.unwrap_or(0)
+                + usage.cache_creation_input_tokens.unwrap_or(0)
+                + usage.cache_read_input_tokens.unwrap_or(0),
+        )
+    }
+
+    /// Copy the fields present in the payload into `state`
+    pub fn apply_to(&self, state: &mut WaybarState, thresholds: &ContextConfig) {
+        if let Some(name) = self.model_name() {
+            state.model = name.to_string();
+        }
+        if let Some(cost) = self.total_cost() {
+            state.cost = cost;
+        }
+        if let Some(sid) = &self.session_id {
+            state.session_id = sid.clone();
+        }
+        if let Some(cwd) = &self.cwd {
+            state.cwd = cwd.clone();
+        }
+        if let Some(usage) = self.current_usage() {
+            state.input_tokens = usage.input_tokens.unwrap_or(0);
+            state.output_tokens = usage.output_tokens.unwrap_or(0);
+            state.cache_read = usage.cache_read_input_tokens.unwrap_or(0);
+            state.cache_write = usage.cache_creation_input_tokens.unwrap_or(0);
+        }
+        if let Some(used) = self.context_used() {
+            state.set_context(used, self.context_window_size(), thresholds);
+        }
+    }
+
+    /// Size of the model's context window
+    pub fn context_window_size(&self) -> u64 {
+        if let Some(size) = self
+            .context_window
+            .as_ref()
+            .and_then(|c| c.context_window_size)
+            .filter(|&size| size > 0)
+        {
+            return size;
+        }
+        let model = self.model.as_ref();
+        context_window_size(
+            model.and_then(|m| m.id.as_deref()),
+            model.and_then(|m| m.display_name.as_deref()),
+        )
+    }
+}
+
+/// Context window for a model, from its id or display name.
+///
+/// Every model has the default window except the 1M-context variants,
+/// marked with a `[1m]` id suffix, or "1M context" in the display name
+/// (e.g. "Sonnet 4.5 (1M context)").
+pub fn context_window_size(model_id: Option<&str>, display_name: Option<&str>) -> u64 {
+    let id = model_id.unwrap_or("").to_ascii_lowercase();
+    let extended = id.ends_with("[1m]")
+        || display_name.is_some_and(|name| name.to_ascii_lowercase().contains("1m context"));
+    if extended {
+        EXTENDED_CONTEXT_WINDOW
+    } else {
+        DEFAULT_CONTEXT_WINDOW
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_used_and_window_from_payload() {
+        let payload = StatuslinePayload::from_json(
+            r#"{
+                "model": {"id": "claude-opus-4-5-20251101", "display_name": "Opus 4.5"},
+                "context_window": {
+                    "context_window_size": 200000,
+                    "current_usage": {
+                        "input_tokens": 10,
+                        "output_tokens": 500,
+                        "cache_creation_input_tokens": 2000,
+                        "cache_read_input_tokens": 48000
+                    }
+                }
+            }"#,
+        );
+
+        assert_eq!(payload.model_name(), Some("Opus 4.5"));
+        // Output tokens are not part of the prompt that filled the window
+        assert_eq!(payload.context_used(), Some(50010));
+        assert_eq!(payload.context_window_size(), 200_000);
+    }
+
+    #[test]
+    fn test_context_window_size_for_1m_variants() {
+        assert_eq!(
+            context_window_size(Some("claude-sonnet-4-5-20250929[1m]"), None),
+            EXTENDED_CONTEXT_WINDOW
+        );
+        assert_eq!(
+            context_window_size(Some("claude-sonnet-4-5"), Some("Sonnet 4.5 (1M context)")),
+            EXTENDED_CONTEXT_WINDOW
+        );
+        assert_eq!(
+            context_window_size(Some("claude-sonnet-4-5-20250929"), Some("Sonnet 4.5")),
+            200_000
+        );
+        assert_eq!(context_window_size(None, None), DEFAULT_CONTEXT_WINDOW);
+    }
+
+    #[test]
+    fn test_apply_sets_percentage_and_keeps_missing_fields() {
+        let mut state = WaybarState {
+            model: "Opus 4.5".to_string(),
+            cost: 1.0,
+            ..Default::default()
+        };
+        let payload = StatuslinePayload::from_json(
+            r#"{"model":{"id":"claude-sonnet-4-5-20250929[1m]"},"context_window":{"current_usage":{"input_tokens":800000}}}"#,
+        );
+
+        payload.apply_to(&mut state, &ContextConfig::default());
+
+        assert_eq!(state.model, "claude-sonnet-4-5-20250929[1m]");
+        assert_eq!(state.cost, 1.0);
+        assert_eq!(state.percentage, 80);
+        assert_eq!(state.classes, vec!["context-warn"]);
+    }
+
+    #[test]
+    fn test_malformed_payload_is_empty() {
+        let payload = StatuslinePayload::from_json("not json");
+        assert_eq!(payload.model_name(), None);
+        assert_eq!(payload.context_used(), None);
+    }
+}
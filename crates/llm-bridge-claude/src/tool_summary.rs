@@ -0,0 +1,169 @@
+//! Short descriptions of what a tool call is working on, from its `tool_input`.
+//!
+//! Summaries are shown on the bar, so they only ever contain a safe fragment
+//! of the input: a file name rather than a path, the program and subcommand
+//! of a shell command rather than its arguments, a URL's host.
+
+use serde_json::Value;
+
+/// Longest summary, in characters
+const MAX_CHARS: usize = 32;
+
+/// Summarise `input` for `tool`, or `None` for tools without a useful detail
+///
+/// ```text
+/// Read/Edit/Write  {"file_path": "/src/main.rs"}        -> main.rs
+/// Bash             {"command": "cargo test --all"}      -> cargo test
+/// Grep/Glob        {"pattern": "fn main"}               -> fn main
+/// Task             {"description": "Find callers"}      -> Find callers
+/// WebFetch         {"url": "https://docs.rs/tokio/..."} -> docs.rs
+/// ```
+pub fn summarize_tool_input(tool: &str, input: &Value) -> Option<String> {
+    let field = |name: &str| input.get(name).and_then(Value::as_str).map(str::trim);
+
+    let summary = match tool {
+        "Read" | "Edit" | "MultiEdit" | "Write" => basename(field("file_path")?),
+        "NotebookEdit" => basename(field("notebook_path")?),
+        "Bash" => command_name(field("command")?)?,
+        "Grep" | "Glob" => field("pattern")?.to_string(),
+        "Task" => field("description")?.to_string(),
+        "WebFetch" => url_host(field("url")?)?,
+        "WebSearch" => field("query")?.to_string(),
+        _ => return None,
+    };
+
+    let summary = single_line(&summary);
+    (!summary.is_empty()).then(|| truncate(&summary, MAX_CHARS))
+}
+
+/// `/home/me/src/main.rs` -> `main.rs`
+fn basename(path: &str) -> String {
+    path.trim_end_matches('/').rsplit('/').next().unwrap_or_default().to_string()
+}
+
+/// The program of a shell command and its subcommand, if it has one:
+/// `FOO=1 cargo test --all | tail` -> `cargo test`. Arguments may hold
+/// paths or secrets, so anything that is not a plain word is dropped.
+fn command_name(command: &str) -> Option<String> {
+    let mut words = command
+        .split_whitespace()
+        .take_while(|w| !matches!(*w, "|" | "||" | "&&" | ";" | "&"))
+        .skip_while(|w| is_assignment(w));
+
+    let program = basename(words.next()?);
+    let program = program.trim_end_matches(';');
+    if program.is_empty() {
+        return None;
+    }
+
+    match words.next().filter(|w| is_plain_word(w)) {
+        Some(subcommand) => Some(format!("{} {}", program, subcommand)),
+        None => Some(program.to_string()),
+    }
+}
+
+/// `NAME=value`, as used to set the environment for one command
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+/// A subcommand like `test`, `run` or `compose`, not a flag, path or value
+fn is_plain_word(word: &str) -> bool {
+    word.starts_with(|c: char| c.is_ascii_alphabetic())
+        && word.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':'))
+}
+
+/// `https://user:pw@docs.rs:443/tokio` -> `docs.rs`
+fn url_host(url: &str) -> Option<String> {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = if host.starts_with('[') {
+        host.split_inclusive(']').next()? // IPv6 literal
+    } else {
+        host.split(':').next()?
+    };
+    (!host.is_empty()).then(|| host.to_string())
+}
+
+/// Collapse whitespace, including newlines, to single spaces
+fn single_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_chars - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn summary(tool: &str, input: Value) -> Option<String> {
+        summarize_tool_input(tool, &input)
+    }
+
+    #[test]
+    fn test_file_tools_show_basename() {
+        assert_eq!(summary("Read", json!({"file_path": "/home/me/src/main.rs"})).as_deref(), Some("main.rs"));
+        assert_eq!(summary("Edit", json!({"file_path": "lib.rs", "old_string": "x"})).as_deref(), Some("lib.rs"));
+        assert_eq!(summary("Write", json!({"file_path": "/tmp/out/"})).as_deref(), Some("out"));
+        assert_eq!(
+            summary("NotebookEdit", json!({"notebook_path": "/n/analysis.ipynb"})).as_deref(),
+            Some("analysis.ipynb")
+        );
+        assert_eq!(summary("Read", json!({})), None);
+    }
+
+    #[test]
+    fn test_bash_shows_program_and_subcommand_only() {
+        let bash = |command: &str| summary("Bash", json!({ "command": command }));
+
+        assert_eq!(bash("cargo test --workspace").as_deref(), Some("cargo test"));
+        assert_eq!(bash("git commit -m 'secret plans'").as_deref(), Some("git commit"));
+        assert_eq!(bash("ls -la /home/me").as_deref(), Some("ls"));
+        assert_eq!(bash("API_KEY=sk-123 ./deploy.sh prod").as_deref(), Some("deploy.sh prod"));
+        assert_eq!(bash("/usr/bin/python3 /tmp/x.py").as_deref(), Some("python3"));
+        assert_eq!(bash("curl https://example.com/?token=abc").as_deref(), Some("curl"));
+        assert_eq!(bash("make && make install").as_deref(), Some("make"));
+        assert_eq!(bash("   "), None);
+    }
+
+    #[test]
+    fn test_search_task_and_web_tools() {
+        assert_eq!(summary("Grep", json!({"pattern": "fn main", "path": "src"})).as_deref(), Some("fn main"));
+        assert_eq!(summary("Glob", json!({"pattern": "**/*.rs"})).as_deref(), Some("**/*.rs"));
+        assert_eq!(
+            summary("Task", json!({"description": "Find callers", "prompt": "long..."})).as_deref(),
+            Some("Find callers")
+        );
+        assert_eq!(
+            summary("WebFetch", json!({"url": "https://user:pw@docs.rs:443/tokio?x=1"})).as_deref(),
+            Some("docs.rs")
+        );
+        assert_eq!(summary("WebFetch", json!({"url": "http://[::1]:8080/"})).as_deref(), Some("[::1]"));
+        assert_eq!(summary("TodoWrite", json!({"todos": []})), None);
+        assert_eq!(summary("mcp__github__search", json!({"query": "x"})), None);
+    }
+
+    #[test]
+    fn test_summaries_are_one_short_line() {
+        let pattern = "a\nvery   long pattern that goes on and on and on";
+        let grep = summary("Grep", json!({ "pattern": pattern })).unwrap();
+
+        assert_eq!(grep.chars().count(), MAX_CHARS);
+        assert!(grep.starts_with("a very long pattern"));
+        assert!(grep.ends_with('…'));
+        assert_eq!(summary("Task", json!({"description": "日本語".repeat(20)})).unwrap().chars().count(), MAX_CHARS);
+    }
+}
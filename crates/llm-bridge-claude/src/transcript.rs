@@ -1,21 +1,130 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
+use llm_bridge_core::ledger::days_from_civil;
 use llm_bridge_core::provider::ProviderError;
 
-#[derive(Debug, Deserialize)]
+use crate::fingerprint::fnv1a;
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct TranscriptEntry {
     #[serde(rename = "type")]
     pub entry_type: Option<String>,
     #[serde(default)]
+    pub timestamp: Option<String>,
+    #[serde(default)]
+    pub uuid: Option<String>,
+    #[serde(default, rename = "requestId")]
+    pub request_id: Option<String>,
+    #[serde(default)]
     pub message: Option<TranscriptMessage>,
+    /// Lines Claude Code adds itself, such as command caveats
+    #[serde(default, rename = "isMeta")]
+    pub is_meta: bool,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct TranscriptMessage {
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
     #[serde(default)]
     pub usage: Option<TokenUsage>,
+    #[serde(default, rename = "content")]
+    pub content_kind: Option<ContentKind>,
+}
+
+/// What a message's `content` holds, without keeping the content itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentKind {
+    /// Text typed by the user (or written by the model)
+    Text,
+    /// Results of tool calls, sent back as a user message
+    ToolResult,
+    Other,
+}
+
+impl<'de> Deserialize<'de> for ContentKind {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KindVisitor;
+
+        #[derive(Deserialize)]
+        struct Block {
+            #[serde(rename = "type")]
+            block_type: Option<String>,
+        }
+
+        impl<'de> serde::de::Visitor<'de> for KindVisitor {
+            type Value = ContentKind;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("message content")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, _: &str) -> Result<ContentKind, E> {
+                Ok(ContentKind::Text)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<ContentKind, A::Error> {
+                let mut kind = ContentKind::Other;
+                while let Some(block) = seq.next_element::<Block>()? {
+                    match block.block_type.as_deref() {
+                        Some("tool_result") => kind = ContentKind::ToolResult,
+                        Some("text") if kind == ContentKind::Other => kind = ContentKind::Text,
+                        _ => {}
+                    }
+                }
+                Ok(kind)
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<ContentKind, A::Error> {
+                while map.next_entry::<serde::de::IgnoredAny, serde::de::IgnoredAny>()?.is_some() {}
+                Ok(ContentKind::Other)
+            }
+        }
+
+        deserializer.deserialize_any(KindVisitor)
+    }
+}
+
+impl TranscriptEntry {
+    /// Identifies the API response this entry belongs to.
+    ///
+    /// Claude Code writes one line per content block of an assistant message,
+    /// each repeating the message's usage, so usage must only be counted once
+    /// per key. Entries without a message id fall back to their line `uuid`.
+    pub fn dedup_key(&self) -> Option<String> {
+        let message_id = self.message.as_ref().and_then(|m| m.id.as_deref());
+        match (message_id, self.request_id.as_deref()) {
+            (Some(id), Some(request)) => Some(format!("{}:{}", id, request)),
+            (Some(id), None) => Some(id.to_string()),
+            (None, _) => self.uuid.clone(),
+        }
+    }
+
+    pub fn usage(&self) -> Option<&TokenUsage> {
+        self.message.as_ref().and_then(|m| m.usage.as_ref())
+    }
+
+    pub fn model(&self) -> Option<&str> {
+        self.message.as_ref().and_then(|m| m.model.as_deref())
+    }
+
+    /// `timestamp` as a Unix time in seconds
+    pub fn time(&self) -> Option<i64> {
+        self.timestamp.as_deref().and_then(parse_timestamp)
+    }
+
+    /// Whether this line is a prompt the user submitted, as opposed to tool
+    /// results or notes Claude Code adds to the conversation
+    pub fn is_prompt(&self) -> bool {
+        self.entry_type.as_deref() == Some("user")
+            && !self.is_meta
+            && self.message.as_ref().and_then(|m| m.content_kind) == Some(ContentKind::Text)
+    }
 }
 
 #[derive(Debug, Deserialize, Default, Clone)]
@@ -30,30 +139,310 @@ pub struct TokenUsage {
     pub cache_creation_input_tokens: u64,
 }
 
-pub fn parse_transcript_tail(path: &Path, max_lines: usize) -> Result<Vec<TokenUsage>, ProviderError> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
+/// Bytes at the start of a transcript used to recognise it after a reopen
+const HEAD_LEN: u64 = 256;
 
-    let mut usages = Vec::new();
+/// Read position in a transcript file.
+///
+/// Remembers the file's inode and the byte offset after the last complete
+/// line, so each call only parses lines appended since the previous one.
+/// A changed inode (rotation), a file shorter than the offset (truncation)
+/// or different leading bytes (rewrite) restart reading from the beginning.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TranscriptCursor {
+    pub inode: u64,
+    pub offset: u64,
+    #[serde(default)]
+    head_len: u64,
+    #[serde(default)]
+    head_hash: u64,
+}
 
-    // Read all lines and take last max_lines
-    let lines: Vec<_> = reader.lines().collect();
-    let start = lines.len().saturating_sub(max_lines);
+/// Result of reading a transcript from a cursor
+#[derive(Debug, Default)]
+pub struct TranscriptRead {
+    /// New entries that carry usage
+    pub entries: Vec<TranscriptEntry>,
+    /// New user prompts that have a `uuid` to count turns by
+    pub prompts: Vec<TranscriptEntry>,
+    /// True if the file was read from the start because it was replaced,
+    /// truncated or never read before; running totals must be discarded
+    pub reset: bool,
+}
 
-    for line_result in lines.into_iter().skip(start) {
-        let line = line_result?;
-        if line.trim().is_empty() {
-            continue;
+impl TranscriptCursor {
+    /// Parse complete lines appended since the last call
+    pub fn read_new(&mut self, path: &Path) -> Result<TranscriptRead, ProviderError> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let len = metadata.len();
+
+        let mut reset = self.offset == 0
+            || metadata.ino() != self.inode
+            || len < self.offset
+            || read_head(&mut file, self.head_len)? != self.head_hash;
+
+        if reset {
+            *self = Self {
+                inode: metadata.ino(),
+                ..Default::default()
+            };
         }
 
-        if let Ok(entry) = serde_json::from_str::<TranscriptEntry>(&line) {
-            if let Some(message) = entry.message {
-                if let Some(usage) = message.usage {
-                    usages.push(usage);
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(self.offset))?;
+
+        let mut entries = Vec::new();
+        let mut prompts = Vec::new();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let n = reader.read_until(b'\n', &mut line)?;
+            // EOF, or a line that is still being written
+            if n == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            self.offset += n as u64;
+
+            if let Ok(entry) = serde_json::from_slice::<TranscriptEntry>(&line) {
+                if entry.usage().is_some() {
+                    entries.push(entry);
+                } else if entry.is_prompt() && entry.uuid.is_some() {
+                    prompts.push(entry);
                 }
             }
         }
+
+        if self.head_len < HEAD_LEN.min(self.offset) {
+            let mut file = reader.into_inner();
+            self.head_len = HEAD_LEN.min(self.offset);
+            self.head_hash = read_head(&mut file, self.head_len)?;
+        }
+
+        // Nothing has been consumed yet, so there is nothing to discard
+        reset &= self.offset > 0 || !entries.is_empty();
+
+        Ok(TranscriptRead { entries, prompts, reset })
     }
+}
+
+fn read_head(file: &mut File, len: u64) -> std::io::Result<u64> {
+    let mut head = Vec::with_capacity(len as usize);
+    file.seek(SeekFrom::Start(0))?;
+    file.by_ref().take(len).read_to_end(&mut head)?;
+    Ok(fnv1a(&head))
+}
+
+/// How much of the end of a transcript [`last_api_error`] looks at
+const TAIL_LEN: u64 = 64 * 1024;
 
-    Ok(usages)
+/// The API error the conversation ended on, if its latest message is one.
+///
+/// Claude Code records failed API calls (overload, rate limit, ...) as an
+/// assistant message flagged `isApiErrorMessage`. Only the end of the file
+/// is read, and an error followed by any other message is not reported.
+pub fn last_api_error(path: &Path) -> Result<Option<String>, ProviderError> {
+    let mut file = File::open(path)?;
+    let start = file.metadata()?.len().saturating_sub(TAIL_LEN);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+
+    let mut lines = tail.split(|&b| b == b'\n').rev();
+    if start > 0 {
+        // The first line was cut by the seek; it is last in reverse order
+        lines.next_back();
+    }
+
+    for line in lines {
+        let Ok(entry) = serde_json::from_slice::<serde_json::Value>(line) else {
+            continue;
+        };
+        match entry["type"].as_str() {
+            Some("assistant") if entry["isApiErrorMessage"] == true => {
+                return Ok(Some(message_text(&entry["message"]["content"])));
+            }
+            Some("assistant") | Some("user") => return Ok(None),
+            _ => continue, // summaries, system notes
+        }
+    }
+    Ok(None)
+}
+
+/// First line of a message's text, whether `content` is a string or blocks
+fn message_text(content: &serde_json::Value) -> String {
+    let text = match content {
+        serde_json::Value::String(s) => s.as_str(),
+        blocks => blocks
+            .as_array()
+            .and_then(|b| b.iter().find_map(|block| block["text"].as_str()))
+            .unwrap_or_default(),
+    };
+    match text.lines().map(str::trim).find(|l| !l.is_empty()) {
+        Some(line) => line.to_string(),
+        None => "API error".to_string(),
+    }
+}
+
+/// Unix time of an RFC 3339 timestamp, as Claude Code writes them:
+/// `2025-10-16T09:30:00.123Z`. Fractions of a second are dropped.
+fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let (date, time) = timestamp.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<u32>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (clock, offset) = match time.strip_suffix('Z') {
+        Some(clock) => (clock, 0),
+        None => {
+            let at = time.rfind(['+', '-'])?;
+            let (hours, minutes) = time[at + 1..].split_once(':')?;
+            let offset = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+            (&time[..at], if time[at..].starts_with('-') { -offset } else { offset })
+        }
+    };
+    let clock = clock.split('.').next()?;
+    let mut clock = clock.splitn(3, ':').map(str::parse::<i64>);
+    let (hour, minute, second) = (clock.next()?.ok()?, clock.next()?.ok()?, clock.next()?.ok()?);
+
+    let days = days_from_civil(year as i64, month, day);
+    Some(days * 86_400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+/// Parse a whole transcript, keeping entries that carry usage
+pub fn parse_transcript(path: &Path) -> Result<Vec<TranscriptEntry>, ProviderError> {
+    Ok(TranscriptCursor::default().read_new(path)?.entries)
+}
+
+/// Transcript lines for the tests of this crate
+#[cfg(test)]
+pub(crate) mod test_lines {
+    /// An assistant response line, as Claude Code appends it
+    pub(crate) fn response(id: &str, timestamp: &str, input_tokens: u64, output_tokens: u64) -> String {
+        format!(
+            r#"{{"type":"assistant","timestamp":"{}","requestId":"req_{}","message":{{"id":"msg_{}","model":"claude-opus-4-5-20251101","usage":{{"input_tokens":{},"output_tokens":{}}}}}}}"#,
+            timestamp, id, id, input_tokens, output_tokens
+        ) + "\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_lines::response;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    fn assistant_line(output_tokens: u64) -> String {
+        response("a", "2025-10-16T09:30:00Z", 1, output_tokens)
+    }
+
+    fn append(path: &Path, data: &str) {
+        let mut file = OpenOptions::new().create(true).append(true).open(path).unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+    }
+
+    fn temp_transcript(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("llm_test_cursor_{}_{}.jsonl", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_cursor_reads_only_appended_lines() {
+        let path = temp_transcript("append");
+        append(&path, &assistant_line(10));
+        append(&path, r#"{"type":"user","message":{"role":"user","content":"hi"}}"#);
+        append(&path, "\n");
+
+        let mut cursor = TranscriptCursor::default();
+        let first = cursor.read_new(&path).unwrap();
+        assert!(first.reset);
+        assert_eq!(first.entries.len(), 1);
+        assert!(first.prompts.is_empty()); // no uuid to count it by
+
+        append(&path, &assistant_line(20));
+        let second = cursor.read_new(&path).unwrap();
+        assert!(!second.reset);
+        assert_eq!(second.entries.len(), 1);
+        assert_eq!(second.entries[0].usage().unwrap().output_tokens, 20);
+
+        let third = cursor.read_new(&path).unwrap();
+        assert!(third.entries.is_empty());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("2025-10-16T09:30:00.123Z"), Some(1_760_607_000));
+        assert_eq!(parse_timestamp("2025-10-16T11:30:00+02:00"), Some(1_760_607_000));
+        assert_eq!(parse_timestamp("2025-10-16T04:30:00-05:00"), Some(1_760_607_000));
+        assert_eq!(parse_timestamp("2025-13-16T09:30:00Z"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn test_cursor_waits_for_complete_line() {
+        let path = temp_transcript("partial");
+        let line = assistant_line(10);
+        let (start, rest) = line.split_at(20);
+        append(&path, start);
+
+        let mut cursor = TranscriptCursor::default();
+        assert!(cursor.read_new(&path).unwrap().entries.is_empty());
+        assert_eq!(cursor.offset, 0);
+
+        append(&path, rest);
+        assert_eq!(cursor.read_new(&path).unwrap().entries.len(), 1);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_last_api_error_only_when_conversation_ends_on_it() {
+        let path = temp_transcript("api-error");
+        let error = r#"{"type":"assistant","isApiErrorMessage":true,"message":{"content":[{"type":"text","text":"API Error: 429 rate limited\nretry later"}]}}"#;
+        append(&path, &assistant_line(10));
+        append(&path, &(error.to_string() + "\n"));
+        append(&path, "{\"type\":\"system\",\"content\":\"note\"}\n");
+
+        assert_eq!(last_api_error(&path).unwrap().as_deref(), Some("API Error: 429 rate limited"));
+
+        append(&path, r#"{"type":"user","message":{"role":"user","content":"try again"}}"#);
+        append(&path, "\n");
+        assert_eq!(last_api_error(&path).unwrap(), None);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_cursor_restarts_after_truncation_and_rotation() {
+        let path = temp_transcript("rotate");
+        append(&path, &assistant_line(10));
+        append(&path, &assistant_line(20));
+
+        let mut cursor = TranscriptCursor::default();
+        assert_eq!(cursor.read_new(&path).unwrap().entries.len(), 2);
+
+        // Truncate in place
+        std::fs::write(&path, assistant_line(30)).unwrap();
+        let read = cursor.read_new(&path).unwrap();
+        assert!(read.reset);
+        assert_eq!(read.entries.len(), 1);
+        assert_eq!(read.entries[0].usage().unwrap().output_tokens, 30);
+
+        // Replace with a new file that has grown past the old offset
+        let replacement = path.with_extension("new");
+        std::fs::write(&replacement, assistant_line(40) + &assistant_line(50) + &assistant_line(60)).unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+        let read = cursor.read_new(&path).unwrap();
+        assert!(read.reset);
+        assert_eq!(read.entries.len(), 3);
+
+        std::fs::remove_file(&path).ok();
+    }
 }
@@ -1,27 +1,306 @@
-use llm_bridge_core::provider::UsageMetrics;
-use crate::transcript::TokenUsage;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use llm_bridge_core::provider::{ProviderError, UsageMetrics};
+use crate::pricing::PricingTable;
+use crate::transcript::{TranscriptCursor, TranscriptEntry};
 
-// Claude Sonnet 3.5 pricing (per million tokens)
-const INPUT_PRICE: f64 = 3.0;
-const OUTPUT_PRICE: f64 = 15.0;
-const CACHE_READ_PRICE: f64 = 0.30;
-const CACHE_WRITE_PRICE: f64 = 3.75;
-
-pub fn calculate_cost(usages: &[TokenUsage]) -> UsageMetrics {
+/// Sum token usage across transcript entries, pricing each entry by the
+/// model recorded on its message at the time it was written.
+/// Each API response is counted once, however many lines repeat it.
+pub fn calculate_cost(entries: &[TranscriptEntry], pricing: &PricingTable) -> UsageMetrics {
     let mut total = UsageMetrics::default();
+    accumulate(&mut total, entries, pricing, &mut HashSet::new());
+    total
+}
+
+/// Dedup keys of the responses and prompts already counted
+pub(crate) trait Seen {
+    /// Add `key`, returning whether it is new
+    fn insert(&mut self, key: String) -> bool;
+}
+
+impl Seen for HashSet<String> {
+    fn insert(&mut self, key: String) -> bool {
+        HashSet::insert(self, key)
+    }
+}
+
+/// How many keys `RecentKeys` holds
+const RECENT_KEYS: usize = 64;
+
+/// The latest keys of one transcript. A response's lines are written
+/// together and a prompt only once, so an older key does not come up again
+/// in the same file; keeping only a few bounds the saved tracker.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+struct RecentKeys(VecDeque<String>);
+
+impl Seen for RecentKeys {
+    fn insert(&mut self, key: String) -> bool {
+        if self.0.contains(&key) {
+            return false;
+        }
+        while self.0.len() >= RECENT_KEYS {
+            self.0.pop_front();
+        }
+        self.0.push_back(key);
+        true
+    }
+}
+
+/// Add entries whose dedup key is not yet in `seen` to `total`
+pub(crate) fn accumulate(
+    total: &mut UsageMetrics,
+    entries: &[TranscriptEntry],
+    pricing: &PricingTable,
+    seen: &mut impl Seen,
+) {
+    for entry in entries {
+        let Some(usage) = entry.usage() else {
+            continue;
+        };
+
+        if let Some(key) = entry.dedup_key() {
+            if !seen.insert(key) {
+                continue;
+            }
+        }
 
-    for usage in usages {
         total.input_tokens += usage.input_tokens;
         total.output_tokens += usage.output_tokens;
         total.cache_read += usage.cache_read_input_tokens;
         total.cache_write += usage.cache_creation_input_tokens;
+        total.estimated_cost += pricing
+            .price_for(entry.model(), entry.timestamp.as_deref())
+            .cost(usage);
+    }
+}
+
+/// Count prompts whose `uuid` is not yet in `seen` as turns; resumed
+/// sessions repeat earlier prompts
+pub(crate) fn count_prompts(total: &mut UsageMetrics, prompts: &[TranscriptEntry], seen: &mut impl Seen) {
+    for uuid in prompts.iter().filter_map(|p| p.uuid.as_deref()) {
+        if seen.insert(format!("prompt:{}", uuid)) {
+            total.turns += 1;
+        }
     }
+}
 
-    total.estimated_cost =
-        (total.input_tokens as f64 * INPUT_PRICE / 1_000_000.0) +
-        (total.output_tokens as f64 * OUTPUT_PRICE / 1_000_000.0) +
-        (total.cache_read as f64 * CACHE_READ_PRICE / 1_000_000.0) +
-        (total.cache_write as f64 * CACHE_WRITE_PRICE / 1_000_000.0);
+/// Running usage totals for one transcript, advanced incrementally.
+///
+/// Serializable so the cursor and totals survive between short-lived CLI
+/// invocations; each update only parses lines appended since the last one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UsageTracker {
+    pub path: PathBuf,
+    cursor: TranscriptCursor,
+    totals: UsageMetrics,
+    /// Dedup keys of the latest responses and prompts counted in `totals`
+    #[serde(default)]
+    seen: RecentKeys,
+    /// `PricingTable::fingerprint` the totals were priced with
+    #[serde(default)]
+    pricing: u64,
+}
 
-    total
+impl UsageTracker {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Load a tracker saved by `save`, if it belongs to `path`
+    pub fn load(cache_path: &Path, path: &Path) -> Option<Self> {
+        let content = fs::read_to_string(cache_path).ok()?;
+        let tracker: Self = serde_json::from_str(&content).ok()?;
+        (tracker.path == path).then_some(tracker)
+    }
+
+    pub fn save(&self, cache_path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = cache_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = cache_path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        fs::rename(&tmp_path, cache_path)
+    }
+
+    /// Parse newly appended entries and return the cumulative totals
+    pub fn update(&mut self, pricing: &PricingTable) -> Result<&UsageMetrics, ProviderError> {
+        let fingerprint = pricing.fingerprint();
+        if self.pricing != fingerprint {
+            // Totals were priced with another table: recount from the start
+            self.cursor = TranscriptCursor::default();
+            self.pricing = fingerprint;
+        }
+
+        let read = self.cursor.read_new(&self.path)?;
+        if read.reset {
+            self.totals = UsageMetrics::default();
+            self.seen = RecentKeys::default();
+        }
+        accumulate(&mut self.totals, &read.entries, pricing, &mut self.seen);
+        count_prompts(&mut self.totals, &read.prompts, &mut self.seen);
+
+        Ok(&self.totals)
+    }
+
+    pub fn totals(&self) -> &UsageMetrics {
+        &self.totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcript::parse_transcript;
+    use crate::transcript::test_lines::response;
+
+    fn entry(json: &str) -> TranscriptEntry {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_calculate_cost_prices_each_model() {
+        let entries = vec![
+            entry(r#"{"type":"assistant","timestamp":"2025-12-01T10:00:00.000Z","message":{"model":"claude-opus-4-1-20250805","usage":{"input_tokens":1000000,"output_tokens":0}}}"#),
+            entry(r#"{"type":"assistant","timestamp":"2025-12-01T10:01:00.000Z","message":{"model":"claude-3-5-haiku-20241022","usage":{"input_tokens":1000000,"output_tokens":0}}}"#),
+        ];
+
+        let metrics = calculate_cost(&entries, &PricingTable::builtin());
+
+        assert_eq!(metrics.input_tokens, 2_000_000);
+        // $15 for Opus + $0.80 for Haiku
+        assert!((metrics.estimated_cost - 15.80).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_calculate_cost_includes_cache_tokens() {
+        let entries = vec![entry(
+            r#"{"type":"assistant","message":{"model":"claude-sonnet-4-5-20250929","usage":{"input_tokens":0,"output_tokens":0,"cache_read_input_tokens":1000000,"cache_creation_input_tokens":1000000}}}"#,
+        )];
+
+        let metrics = calculate_cost(&entries, &PricingTable::builtin());
+
+        assert_eq!(metrics.cache_read, 1_000_000);
+        assert_eq!(metrics.cache_write, 1_000_000);
+        assert!((metrics.estimated_cost - 4.05).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tracker_keeps_running_totals_across_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("llm_test_tracker_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let transcript = dir.join("session.jsonl");
+        let cache = dir.join("cursor.json");
+        let line = r#"{"type":"assistant","message":{"model":"claude-sonnet-4-5-20250929","usage":{"input_tokens":100,"output_tokens":10}}}"#;
+        let pricing = PricingTable::builtin();
+
+        std::fs::write(&transcript, format!("{}\n", line)).unwrap();
+        let mut tracker = UsageTracker::new(&transcript);
+        assert_eq!(tracker.update(&pricing).unwrap().input_tokens, 100);
+        tracker.save(&cache).unwrap();
+
+        std::fs::write(&transcript, format!("{}\n{}\n", line, line)).unwrap();
+        let mut restored = UsageTracker::load(&cache, &transcript).unwrap();
+        let totals = restored.update(&pricing).unwrap();
+        assert_eq!(totals.input_tokens, 200);
+        assert_eq!(totals.output_tokens, 20);
+
+        // A cursor saved for another transcript is ignored
+        assert!(UsageTracker::load(&cache, &dir.join("other.jsonl")).is_none());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    #[test]
+    fn test_content_blocks_of_one_response_count_once() {
+        let entries = parse_transcript(&fixture("multi-block-session.jsonl")).unwrap();
+        // Six assistant lines, three API responses
+        assert_eq!(entries.len(), 6);
+
+        let metrics = calculate_cost(&entries, &PricingTable::builtin());
+
+        assert_eq!(metrics.input_tokens, 4 + 6 + 3);
+        assert_eq!(metrics.output_tokens, 310 + 120 + 85);
+        assert_eq!(metrics.cache_read, 15237 + 17507 + 19307);
+        assert_eq!(metrics.cache_write, 2270 + 1800 + 450);
+
+        let expected_cost = (13.0 * 3.0 + 515.0 * 15.0 + 52051.0 * 0.30 + 4520.0 * 3.75) / 1_000_000.0;
+        assert!((metrics.estimated_cost - expected_cost).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_dedup_falls_back_to_uuid_and_spans_transcripts() {
+        let mut entries = parse_transcript(&fixture("multi-block-session.jsonl")).unwrap();
+        let resumed = parse_transcript(&fixture("resumed-session.jsonl")).unwrap();
+
+        // Within the resumed transcript: the copied response counts once, and
+        // id-less lines are keyed by their own uuid
+        let metrics = calculate_cost(&resumed, &PricingTable::builtin());
+        assert_eq!(metrics.input_tokens, 4 + 10);
+        assert_eq!(metrics.output_tokens, 310 + 20);
+
+        // Across transcripts the copied response is not counted again
+        entries.extend(resumed);
+        let metrics = calculate_cost(&entries, &PricingTable::builtin());
+        assert_eq!(metrics.input_tokens, 13 + 10);
+        assert_eq!(metrics.output_tokens, 515 + 20);
+    }
+
+    #[test]
+    fn test_tracker_dedups_responses_split_across_updates() {
+        let dir = std::env::temp_dir().join(format!("llm_test_tracker_split_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let transcript = dir.join("session.jsonl");
+        let content = std::fs::read_to_string(fixture("multi-block-session.jsonl")).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        let pricing = PricingTable::builtin();
+
+        // Stop in the middle of the first response's content blocks
+        std::fs::write(&transcript, lines[..4].join("\n") + "\n").unwrap();
+        let mut tracker = UsageTracker::new(&transcript);
+        assert_eq!(tracker.update(&pricing).unwrap().output_tokens, 310);
+
+        std::fs::write(&transcript, content.clone()).unwrap();
+        assert_eq!(tracker.update(&pricing).unwrap().output_tokens, 515);
+        // One typed prompt; the other user lines are tool results
+        assert_eq!(tracker.totals().turns, 1);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_tracker_keeps_only_recent_keys() {
+        let dir = std::env::temp_dir().join(format!("llm_test_tracker_recent_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let transcript = dir.join("session.jsonl");
+        let line = |n: usize| response(&n.to_string(), "2025-10-16T09:30:00Z", 1, 10);
+        let pricing = PricingTable::builtin();
+
+        std::fs::write(&transcript, (0..100).map(line).collect::<String>()).unwrap();
+        let mut tracker = UsageTracker::new(&transcript);
+        assert_eq!(tracker.update(&pricing).unwrap().output_tokens, 1000);
+        assert_eq!(tracker.seen.0.len(), RECENT_KEYS);
+
+        // Saved and loaded, it still knows the latest response when another
+        // of its content blocks is written
+        let cache = dir.join("tracker.json");
+        tracker.save(&cache).unwrap();
+        let mut tracker = UsageTracker::load(&cache, &transcript).unwrap();
+        let mut content = std::fs::read_to_string(&transcript).unwrap();
+        content.push_str(&line(99));
+        std::fs::write(&transcript, content).unwrap();
+        assert_eq!(tracker.update(&pricing).unwrap().output_tokens, 1000);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
@@ -0,0 +1,350 @@
+//! Combined bar view over several sessions
+
+use std::collections::HashMap;
+
+use crate::state::{activity_icon, WaybarState};
+
+/// CSS classes by how much they need the user's attention; the combined
+/// view takes the highest of its sessions
+const CLASS_PRIORITY: &[&str] = &["idle", "thinking", "compacting", "tool-active", "error", "attention"];
+
+fn class_priority(class: &str) -> usize {
+    CLASS_PRIORITY.iter().position(|c| *c == class).unwrap_or(0)
+}
+
+/// Modifier classes by family, lowest level first. The combined view keeps
+/// only the highest of each family its sessions have, so that CSS order does
+/// not decide between `budget-warn` and `budget-over`.
+const CLASS_LEVELS: &[&[&str]] = &[&["context-warn", "context-critical"], &["budget-warn", "budget-over"]];
+
+fn keep_highest_levels(classes: &mut Vec<String>) {
+    for family in CLASS_LEVELS {
+        let Some(highest) = family.iter().rev().find(|level| classes.iter().any(|c| c == *level)) else {
+            continue;
+        };
+        classes.retain(|c| c == highest || !family.contains(&c.as_str()));
+    }
+}
+
+/// Combine session states into one bar state, to be rendered with the
+/// configured templates.
+///
+/// A single session is shown as-is. With several, cost and tokens are summed,
+/// the context percentage is the fullest one and `session_list` has a line
+/// per session, those waiting for the user first. `sessions` and
+/// `icon_counts` (`2 󰔟 1 󰈔`) are set either way.
+pub fn combine(sessions: &[WaybarState], icons: &HashMap<String, String>) -> WaybarState {
+    match sessions {
+        [] => WaybarState::default(),
+        [only] => WaybarState {
+            sessions: 1,
+            icon_counts: icon_counts(&[only], icons).0,
+            ..only.clone()
+        },
+        _ => combine_many(sessions, icons),
+    }
+}
+
+/// Active sessions per activity icon in order of first appearance, and
+/// how many are active
+fn icon_counts(sessions: &[&WaybarState], icons: &HashMap<String, String>) -> (String, usize) {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for session in sessions.iter().filter(|s| s.activity != "Idle") {
+        let icon = activity_icon(&session.activity, icons);
+        match counts.iter_mut().find(|(i, _)| *i == icon) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((icon, 1)),
+        }
+    }
+    let parts: Vec<String> = counts.iter().map(|(icon, n)| format!("{} {}", n, icon)).collect();
+    (parts.join(" "), counts.iter().map(|(_, n)| n).sum())
+}
+
+fn combine_many(sessions: &[WaybarState], icons: &HashMap<String, String>) -> WaybarState {
+    let mut sorted: Vec<&WaybarState> = sessions.iter().collect();
+    sorted.sort_by_key(|s| (!s.is_awaiting_input(), std::cmp::Reverse(s.last_activity_time)));
+
+    let mut combined = WaybarState {
+        model: sorted[0].model.clone(),
+        ..Default::default()
+    };
+
+    for session in &sorted {
+        combined.cost += session.cost;
+        combined.input_tokens += session.input_tokens;
+        combined.output_tokens += session.output_tokens;
+        combined.cache_read += session.cache_read;
+        combined.cache_write += session.cache_write;
+        combined.error_count += session.error_count;
+        // Ledger totals span all sessions already
+        combined.today_cost = combined.today_cost.max(session.today_cost);
+        combined.week_cost = combined.week_cost.max(session.week_cost);
+        combined.month_cost = combined.month_cost.max(session.month_cost);
+        combined.cost_per_hour += session.cost_per_hour;
+        combined.tokens_per_min += session.tokens_per_min;
+        // Projected from the rate across all sessions, like the ledger totals
+        combined.projected_today_cost = combined.projected_today_cost.max(session.projected_today_cost);
+        if combined.last_error.is_empty() {
+            combined.last_error = session.last_error.clone();
+        }
+        if !session.budget.is_empty() && (combined.budget.is_empty() || session.budget_pct > combined.budget_pct) {
+            combined.budget = session.budget.clone();
+            combined.budget_pct = session.budget_pct;
+            combined.budget_remaining = session.budget_remaining;
+        }
+        // Usage blocks span all sessions; the latest render is the freshest
+        if session.last_activity_time >= combined.last_activity_time {
+            combined.block_tokens = session.block_tokens;
+            combined.block_limit = session.block_limit;
+            combined.block_pct = session.block_pct;
+            combined.block_reset = session.block_reset.clone();
+            combined.block_limit_in = session.block_limit_in.clone();
+        }
+        combined.last_activity_time = combined.last_activity_time.max(session.last_activity_time);
+        combined.percentage = combined.percentage.max(session.percentage);
+        if session.percentage == combined.percentage {
+            combined.context_tokens = session.context_tokens;
+            combined.context_window = session.context_window;
+        }
+
+        for class in &session.classes {
+            if !combined.classes.contains(class) {
+                combined.classes.push(class.clone());
+            }
+        }
+        if class_priority(&session.class) > class_priority(&combined.class) {
+            combined.class = session.class.clone();
+        }
+    }
+    keep_highest_levels(&mut combined.classes);
+
+    let (counts, active) = icon_counts(&sorted, icons);
+    combined.sessions = sessions.len() as u32;
+    combined.icon_counts = counts;
+    combined.activity = if active > 0 {
+        format!("{} active", active)
+    } else {
+        "Idle".to_string()
+    };
+    combined.alt = if sorted[0].is_awaiting_input() {
+        "attention"
+    } else if active > 0 {
+        "active"
+    } else {
+        "idle"
+    }
+    .to_string();
+
+    let home = dirs::home_dir().unwrap_or_default();
+    let home = home.to_str().unwrap_or("");
+    let mut lines = Vec::new();
+    for session in &sorted {
+        // Sessions known only from hook events have no cwd or model yet
+        let label = if session.cwd.is_empty() {
+            session.session_id.clone()
+        } else if home.is_empty() {
+            session.cwd.clone()
+        } else {
+            session.cwd.replace(home, "~")
+        };
+        let model = if session.model.is_empty() {
+            String::new()
+        } else {
+            format!("{} - ", session.model)
+        };
+        let detail = if session.detail.is_empty() {
+            String::new()
+        } else {
+            format!(" {}", session.detail)
+        };
+        let errors = match session.error_count {
+            0 => String::new(),
+            1 => ", 1 error".to_string(),
+            n => format!(", {} errors", n),
+        };
+        lines.push(format!(
+            "{}: {}{}{} (${:.2}{})",
+            label, model, session.activity, detail, session.cost, errors
+        ));
+    }
+    combined.session_list = lines.join("\n");
+
+    combined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(id: &str, activity: &str, class: &str, cost: f64, time: i64) -> WaybarState {
+        WaybarState {
+            session_id: id.to_string(),
+            activity: activity.to_string(),
+            class: class.to_string(),
+            cost,
+            last_activity_time: time,
+            cwd: format!("/work/{}", id),
+            model: "Opus 4.5".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_single_session_is_shown_as_is() {
+        let only = WaybarState {
+            text: "custom text".to_string(),
+            ..session("a", "Read", "tool-active", 1.0, 10)
+        };
+        let combined = combine(std::slice::from_ref(&only), &HashMap::new());
+        assert_eq!(combined.text, "custom text");
+        assert_eq!(combined.session_id, "a");
+        assert_eq!((combined.sessions, combined.icon_counts.as_str()), (1, "1 \u{f0214}"));
+        assert!(combined.session_list.is_empty());
+    }
+
+    #[test]
+    fn test_sessions_are_counted_by_icon() {
+        let sessions = vec![
+            session("a", "Thinking", "thinking", 1.0, 10),
+            session("b", "Thinking", "thinking", 0.5, 20),
+            session("c", "Read", "tool-active", 0.25, 30),
+            session("d", "Idle", "idle", 0.0, 5),
+        ];
+
+        let combined = combine(&sessions, &HashMap::new());
+
+        assert_eq!(combined.icon_counts, "1 \u{f0214} 2 \u{f0517}");
+        assert_eq!(combined.compute_text("{icon_counts} | ${cost:.2}"), "1 \u{f0214} 2 \u{f0517} | $1.75");
+        assert_eq!(combined.class, "tool-active");
+        assert_eq!(combined.alt, "active");
+        assert_eq!(combined.activity, "3 active");
+        assert_eq!(combined.last_activity_time, 30);
+        assert_eq!(combined.sessions, 4);
+        assert!(combined.compute_tooltip().starts_with("4 sessions | $1.75 total\n\n/work/c:"));
+    }
+
+    #[test]
+    fn test_sessions_awaiting_input_come_first() {
+        let sessions = vec![
+            session("a", "Read", "tool-active", 0.0, 30),
+            session("b", "Waiting for permission", "attention", 0.0, 10),
+            session("c", "Thinking", "error", 0.0, 20),
+        ];
+
+        let combined = combine(&sessions, &HashMap::new());
+
+        assert_eq!(combined.class, "attention");
+        assert_eq!(combined.alt, "attention");
+        assert!(combined.icon_counts.starts_with("1 \u{f009a} "), "{}", combined.icon_counts);
+        assert!(combined.session_list.starts_with("/work/b: Opus 4.5 - Waiting for permission"));
+    }
+
+    #[test]
+    fn test_errors_are_summed_and_last_one_listed() {
+        let mut a = session("a", "Error: Bash: exit code 1", "error", 0.5, 20);
+        a.error_count = 2;
+        a.last_error = "Bash: exit code 1".to_string();
+        let mut b = session("b", "Thinking", "thinking", 0.0, 10);
+        b.error_count = 1;
+        b.last_error = "API Error: 529".to_string();
+
+        let combined = combine(&[a, b], &HashMap::new());
+
+        assert_eq!(combined.class, "error");
+        assert_eq!(combined.error_count, 3);
+        assert!(combined.session_list.contains("/work/a: Opus 4.5 - Error: Bash: exit code 1 ($0.50, 2 errors)"));
+        assert!(combined.compute_tooltip().ends_with("\nErrors: 3 (last: Bash: exit code 1)"));
+    }
+
+    #[test]
+    fn test_tightest_budget_is_shown() {
+        let mut a = session("a", "Thinking", "thinking", 4.5, 10);
+        (a.budget, a.budget_pct, a.budget_remaining) = ("session".to_string(), 90, 0.5);
+        a.classes = vec!["budget-warn".to_string()];
+        let mut b = session("b", "Idle", "idle", 0.5, 20);
+        (b.budget, b.budget_pct, b.budget_remaining) = ("daily".to_string(), 50, 5.0);
+
+        let combined = combine(&[b, a], &HashMap::new());
+
+        assert_eq!((combined.budget.as_str(), combined.budget_pct), ("session", 90));
+        assert_eq!(combined.classes, vec!["budget-warn"]);
+        assert!(combined.compute_tooltip().ends_with("\nBudget: 90% of session used, $0.50 left"));
+    }
+
+    #[test]
+    fn test_burn_rates_are_summed() {
+        let mut a = session("a", "Thinking", "thinking", 2.0, 10);
+        (a.cost_per_hour, a.tokens_per_min, a.projected_today_cost) = (3.0, 1000, 20.0);
+        let mut b = session("b", "Idle", "idle", 1.0, 20);
+        (b.cost_per_hour, b.tokens_per_min, b.projected_today_cost) = (1.5, 500, 21.0);
+
+        let combined = combine(&[a, b.clone()], &HashMap::new());
+
+        assert_eq!((combined.cost_per_hour, combined.tokens_per_min), (4.5, 1500));
+        assert!(combined.compute_tooltip().ends_with("\nRate: $4.50/h, 1500 tokens/min, $21.00 by midnight"));
+
+        b.cost_per_hour = 0.0;
+        let idle = combine(&[session("a", "Idle", "idle", 2.0, 10), b], &HashMap::new());
+        assert!(!idle.compute_tooltip().contains("Rate:"));
+    }
+
+    #[test]
+    fn test_latest_usage_block_is_shown() {
+        let mut a = session("a", "Thinking", "thinking", 2.0, 20);
+        (a.block_tokens, a.block_limit, a.block_pct) = (30_000, 100_000, 30);
+        (a.block_reset, a.block_limit_in) = ("2h 10m".to_string(), "1h".to_string());
+        let mut b = session("b", "Idle", "idle", 1.0, 10);
+        (b.block_tokens, b.block_reset) = (25_000, "2h 15m".to_string());
+
+        let combined = combine(&[b, a], &HashMap::new());
+
+        assert_eq!((combined.block_tokens, combined.block_pct), (30_000, 30));
+        assert!(combined.compute_tooltip().ends_with("\nBlock: 30k tokens (30%), resets in 2h 10m, limit in 1h"));
+    }
+
+    #[test]
+    fn test_all_idle_sessions() {
+        let sessions = vec![session("a", "Idle", "idle", 1.0, 10), session("b", "Idle", "idle", 2.0, 20)];
+        let combined = combine(&sessions, &HashMap::new());
+        assert_eq!(combined.icon_counts, "");
+        let format = "{?icon_counts}{icon_counts}{/icon_counts}{!icon_counts}{icon} {activity}{/icon_counts} | ${cost:.2}";
+        assert_eq!(combined.compute_text(format), "\u{f04b2} Idle | $3.00");
+        assert_eq!(combined.class, "idle");
+    }
+
+    #[test]
+    fn test_modifier_classes_and_context_are_merged() {
+        let mut a = session("a", "Thinking", "thinking", 0.0, 10);
+        a.percentage = 95;
+        a.classes = vec!["context-critical".to_string()];
+        let mut b = session("b", "Idle", "idle", 0.0, 20);
+        b.percentage = 40;
+        b.cwd = "/srv/R&D".to_string();
+
+        let combined = combine(&[a, b], &HashMap::new());
+
+        assert_eq!(combined.percentage, 95);
+        assert_eq!(combined.classes, vec!["context-critical"]);
+        assert!(combined.compute_tooltip().contains("/srv/R&amp;D: Opus 4.5 - Idle"));
+
+        let bare = WaybarState { session_id: "c".to_string(), ..Default::default() };
+        let combined = combine(&[bare.clone(), bare], &HashMap::new());
+        assert!(combined.session_list.ends_with("\nc: Idle ($0.00)"));
+    }
+
+    #[test]
+    fn test_only_the_highest_level_of_each_class_family_is_kept() {
+        let classes = |names: &[&str]| names.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let ok = session("a", "Idle", "idle", 0.0, 30);
+        let mut warn = session("b", "Thinking", "thinking", 0.0, 20);
+        warn.classes = classes(&["context-warn", "budget-warn"]);
+        let mut over = session("c", "Idle", "idle", 0.0, 10);
+        over.classes = classes(&["context-critical", "budget-over"]);
+
+        let combined = combine(&[ok.clone(), warn.clone(), over], &HashMap::new());
+        assert_eq!(combined.classes, vec!["context-critical", "budget-over"]);
+
+        let combined = combine(&[ok, warn], &HashMap::new());
+        assert_eq!(combined.classes, vec!["context-warn", "budget-warn"]);
+    }
+}
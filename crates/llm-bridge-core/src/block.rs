@@ -0,0 +1,137 @@
+//! Subscription usage blocks.
+//!
+//! Subscription plans limit usage per rolling 5-hour block rather than in
+//! dollars. A block starts at the hour of the first message after the
+//! previous block ended and lasts five hours; messages in it count towards
+//! the same limit, whichever session sent them. Five hours without any
+//! message also ends a block.
+
+pub const BLOCK_SECS: i64 = 5 * 3600;
+
+/// Shortest span the token rate is averaged over
+const MIN_SPAN_SECS: i64 = 60;
+
+/// Tokens used in one block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Block {
+    /// Unix time the block started, on the hour
+    pub start: i64,
+    /// Unix times of its first and latest message
+    pub first: i64,
+    pub last: i64,
+    /// Input and output tokens
+    pub tokens: u64,
+}
+
+impl Block {
+    /// Unix time the block resets
+    pub fn end(&self) -> i64 {
+        self.start + BLOCK_SECS
+    }
+
+    /// Whether a message at `now` would still count towards this block
+    pub fn is_active(&self, now: i64) -> bool {
+        now < self.end() && now - self.last < BLOCK_SECS
+    }
+
+    /// Seconds from `now` until `limit` is reached at the block's rate so
+    /// far, if that happens before it resets
+    pub fn limit_eta(&self, limit: u64, now: i64) -> Option<i64> {
+        if limit == 0 {
+            return None;
+        }
+        if self.tokens >= limit {
+            return Some(0);
+        }
+        let per_sec = self.tokens as f64 / (now - self.first).max(MIN_SPAN_SECS) as f64;
+        if per_sec <= 0.0 {
+            return None;
+        }
+        let eta = ((limit - self.tokens) as f64 / per_sec).ceil() as i64;
+        (now + eta < self.end()).then_some(eta)
+    }
+}
+
+/// Group messages, given as (Unix time, tokens) in time order, into blocks
+pub fn group(messages: &[(i64, u64)]) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    for &(time, tokens) in messages {
+        match blocks.last_mut() {
+            Some(block) if time < block.end() && time - block.last < BLOCK_SECS => {
+                block.last = time;
+                block.tokens += tokens;
+            }
+            _ => blocks.push(Block {
+                start: time - time.rem_euclid(3600),
+                first: time,
+                last: time,
+                tokens,
+            }),
+        }
+    }
+    blocks
+}
+
+/// The block a message at `now` would count towards, if one has started
+pub fn current(blocks: &[Block], now: i64) -> Option<Block> {
+    blocks.last().copied().filter(|block| block.is_active(now))
+}
+
+/// The most tokens any finished block used, as a stand-in for the plan's
+/// limit when none is configured
+pub fn largest(blocks: &[Block], now: i64) -> u64 {
+    blocks
+        .iter()
+        .filter(|block| !block.is_active(now))
+        .map(|block| block.tokens)
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: i64 = 3600;
+
+    #[test]
+    fn test_group_into_blocks() {
+        let day = 1_760_572_800; // midnight UTC
+        let messages = [
+            (day + 9 * HOUR + 1200, 100), // starts a block at 09:00
+            (day + 13 * HOUR + 3000, 200),
+            (day + 14 * HOUR, 50), // 14:00 is the reset
+            (day + 14 * HOUR + 600, 25),
+            // Five idle hours end the block early
+            (day + 23 * HOUR + 1800, 10),
+        ];
+
+        let blocks = group(&messages);
+        assert_eq!(
+            blocks.iter().map(|b| (b.start, b.tokens)).collect::<Vec<_>>(),
+            vec![(day + 9 * HOUR, 300), (day + 14 * HOUR, 75), (day + 23 * HOUR, 10)]
+        );
+        assert_eq!(blocks[0].end(), day + 14 * HOUR);
+
+        assert_eq!(current(&blocks, day + 24 * HOUR), Some(blocks[2]));
+        assert_eq!(current(&blocks, day + 28 * HOUR), None);
+        assert_eq!(largest(&blocks, day + 24 * HOUR), 300);
+        assert!(group(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_limit_eta() {
+        let block = Block {
+            start: 0,
+            first: 600,
+            last: 1800,
+            tokens: 10_000,
+        };
+        // 10k tokens in 20 minutes: 30k more takes an hour
+        assert_eq!(block.limit_eta(40_000, 1800), Some(3600));
+        // Not before the reset at 5h
+        assert_eq!(block.limit_eta(200_000, 1800), None);
+        assert_eq!(block.limit_eta(10_000, 1800), Some(0));
+        assert_eq!(block.limit_eta(0, 1800), None);
+    }
+}
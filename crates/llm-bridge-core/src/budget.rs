@@ -0,0 +1,295 @@
+//! Spending budgets.
+//!
+//! The daily and weekly budgets are checked against the ledger's spend across
+//! all sessions, the session budget against each session's own cost. The bar
+//! shows whichever budget is closest to running out, and an alert goes out
+//! once each time spend crosses into the warning or over-budget level.
+//!
+//! With `enforce` set, a spent budget also blocks new prompts and tool calls
+//! until the period is over or `budget allow` lifts the block for a while.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::config::BudgetConfig;
+use crate::ledger::Spend;
+
+/// Spend each budget is checked against, in USD
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Spent {
+    pub session: f64,
+    pub today: f64,
+    pub week: f64,
+}
+
+impl Spent {
+    /// A session's cost alongside the ledger's period totals
+    pub fn new(session: f64, spend: &Spend) -> Self {
+        Self {
+            session,
+            today: spend.today,
+            week: spend.week,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BudgetLevel {
+    Ok,
+    Warn,
+    Over,
+}
+
+impl BudgetLevel {
+    /// Modifier class added to the bar at this level
+    pub fn class(self) -> Option<&'static str> {
+        match self {
+            BudgetLevel::Ok => None,
+            BudgetLevel::Warn => Some("budget-warn"),
+            BudgetLevel::Over => Some("budget-over"),
+        }
+    }
+}
+
+/// One configured budget and what has been spent against it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    /// `daily`, `weekly` or `session`
+    pub name: &'static str,
+    pub limit: f64,
+    pub spent: f64,
+}
+
+impl Budget {
+    /// Share of the limit spent, in percent; over 100 once it is exceeded
+    pub fn pct(&self) -> f64 {
+        self.spent / self.limit * 100.0
+    }
+
+    pub fn remaining(&self) -> f64 {
+        (self.limit - self.spent).max(0.0)
+    }
+
+    pub fn level(&self, warn_pct: u8) -> BudgetLevel {
+        if self.spent >= self.limit {
+            BudgetLevel::Over
+        } else if self.pct() >= warn_pct as f64 {
+            BudgetLevel::Warn
+        } else {
+            BudgetLevel::Ok
+        }
+    }
+
+    /// Alert text for reaching `level`
+    pub fn message(&self, level: BudgetLevel) -> String {
+        let name = match self.name {
+            "daily" => "Daily",
+            "weekly" => "Weekly",
+            _ => "Session",
+        };
+        match level {
+            BudgetLevel::Over => format!("{} budget spent: ${:.2} of ${:.2}", name, self.spent, self.limit),
+            _ => format!(
+                "{} budget {:.0}% used: ${:.2} of ${:.2}",
+                name,
+                self.pct(),
+                self.spent,
+                self.limit
+            ),
+        }
+    }
+}
+
+/// The budgets that are set, with what has been spent against each
+pub fn budgets(config: &BudgetConfig, spent: &Spent) -> Vec<Budget> {
+    [
+        ("daily", config.daily, spent.today),
+        ("weekly", config.weekly, spent.week),
+        ("session", config.session, spent.session),
+    ]
+    .into_iter()
+    .filter(|&(_, limit, _)| limit > 0.0)
+    .map(|(name, limit, spent)| Budget { name, limit, spent })
+    .collect()
+}
+
+/// The budget closest to running out
+pub fn tightest(config: &BudgetConfig, spent: &Spent) -> Option<Budget> {
+    budgets(config, spent).into_iter().max_by(|a, b| a.pct().total_cmp(&b.pct()))
+}
+
+/// The budget furthest over its limit, if any is used up
+pub fn exceeded(config: &BudgetConfig, spent: &Spent) -> Option<Budget> {
+    tightest(config, spent).filter(|budget| budget.level(config.warn_pct) == BudgetLevel::Over)
+}
+
+/// Alert messages for the budgets whose level went up between `before`
+/// and `after`
+pub fn alerts(config: &BudgetConfig, before: &Spent, after: &Spent) -> Vec<String> {
+    budgets(config, before)
+        .iter()
+        .zip(budgets(config, after))
+        .filter_map(|(before, after)| {
+            let level = after.level(config.warn_pct);
+            (level > before.level(config.warn_pct)).then(|| after.message(level))
+        })
+        .collect()
+}
+
+/// Run `command` with `message` as its last argument, without waiting for
+/// it to finish. An empty command does nothing.
+pub fn notify(command: &[String], message: &str) -> io::Result<()> {
+    let Some((program, args)) = command.split_first() else {
+        return Ok(());
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .arg(message)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+    // Reap it in the background so the daemon leaves no zombies
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
+
+/// A time-limited exception to enforcement, written by `budget allow`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Allowance {
+    /// Unix time the allowance ends
+    pub until: i64,
+}
+
+impl Allowance {
+    /// The allowance saved at `path`, if there is one
+    pub fn read(path: &Path) -> Option<Self> {
+        serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)
+    }
+
+    /// Whether enforcement is lifted at Unix time `now`
+    pub fn is_active(&self, now: i64) -> bool {
+        now < self.until
+    }
+}
+
+/// Seconds in a duration such as `90s`, `30m`, `1h`, `1h30m` or `2d`
+pub fn parse_duration(text: &str) -> Option<u64> {
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in text.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86_400,
+            _ => return None,
+        };
+        total = total.checked_add(number.parse::<u64>().ok()?.checked_mul(unit)?)?;
+        number.clear();
+    }
+    (number.is_empty() && total > 0).then_some(total)
+}
+
+/// `3h 5m`, `12m`, `40s`
+pub fn format_duration(secs: u64) -> String {
+    match (secs / 3600, secs % 3600 / 60) {
+        (0, 0) => format!("{}s", secs),
+        (0, minutes) => format!("{}m", minutes),
+        (hours, 0) => format!("{}h", hours),
+        (hours, minutes) => format!("{}h {}m", hours, minutes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> BudgetConfig {
+        BudgetConfig {
+            daily: 10.0,
+            weekly: 40.0,
+            session: 4.0,
+            warn_pct: 80,
+            enforce: true,
+            notify_command: Vec::new(),
+        }
+    }
+
+    fn spent(session: f64, today: f64, week: f64) -> Spent {
+        Spent { session, today, week }
+    }
+
+    #[test]
+    fn test_tightest_budget_and_levels() {
+        let config = config();
+
+        let budget = tightest(&config, &spent(1.0, 8.5, 20.0)).unwrap();
+        assert_eq!(budget.name, "daily");
+        assert_eq!(budget.pct(), 85.0);
+        assert_eq!(budget.remaining(), 1.5);
+        assert_eq!(budget.level(config.warn_pct), BudgetLevel::Warn);
+
+        let budget = tightest(&config, &spent(5.0, 8.5, 20.0)).unwrap();
+        assert_eq!((budget.name, budget.remaining()), ("session", 0.0));
+        assert_eq!(budget.level(config.warn_pct), BudgetLevel::Over);
+
+        assert_eq!(tightest(&config, &spent(0.0, 0.0, 0.0)).unwrap().level(80), BudgetLevel::Ok);
+        assert_eq!(tightest(&BudgetConfig::default(), &spent(100.0, 100.0, 100.0)), None);
+    }
+
+    #[test]
+    fn test_exceeded_only_when_a_budget_is_spent() {
+        let config = config();
+        assert_eq!(exceeded(&config, &spent(3.9, 9.9, 39.0)), None);
+        let budget = exceeded(&config, &spent(3.9, 12.0, 39.0)).unwrap();
+        assert_eq!(budget.message(BudgetLevel::Over), "Daily budget spent: $12.00 of $10.00");
+    }
+
+    #[test]
+    fn test_parse_and_format_duration() {
+        assert_eq!(parse_duration("90s"), Some(90));
+        assert_eq!(parse_duration("30m"), Some(1800));
+        assert_eq!(parse_duration("1h30m"), Some(5400));
+        assert_eq!(parse_duration("2d"), Some(172_800));
+        for invalid in ["", "1", "h", "1x", "0m", "1.5h", "-1h"] {
+            assert_eq!(parse_duration(invalid), None, "{}", invalid);
+        }
+
+        assert_eq!(format_duration(40), "40s");
+        assert_eq!(format_duration(720), "12m");
+        assert_eq!(format_duration(7200), "2h");
+        assert_eq!(format_duration(11_100), "3h 5m");
+    }
+
+    #[test]
+    fn test_alerts_fire_once_per_crossing() {
+        let config = config();
+        let alerts = |before, after| alerts(&config, &before, &after);
+
+        assert!(alerts(spent(1.0, 1.0, 1.0), spent(2.0, 2.0, 2.0)).is_empty());
+        assert_eq!(
+            alerts(spent(1.0, 7.0, 7.0), spent(2.0, 8.0, 8.0)),
+            vec!["Daily budget 80% used: $8.00 of $10.00"]
+        );
+        // Already warned: only the next level alerts
+        assert!(alerts(spent(2.0, 8.0, 8.0), spent(2.0, 9.0, 9.0)).is_empty());
+        assert_eq!(
+            alerts(spent(3.0, 9.0, 9.0), spent(4.5, 10.5, 10.5)),
+            vec!["Daily budget spent: $10.50 of $10.00", "Session budget spent: $4.50 of $4.00"]
+        );
+    }
+}
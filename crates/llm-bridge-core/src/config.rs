@@ -1,14 +1,144 @@
+//! Bridge settings.
+//!
+//! Values are resolved with the precedence CLI > environment > config file >
+//! defaults. The config file is `$XDG_CONFIG_HOME/llm-waybar/config.toml`
+//! (or `LLM_BRIDGE_CONFIG`); unknown keys are rejected so typos are caught.
+//! CLI overrides are applied by the binary after [`Config::load`].
+
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::state::{DEFAULT_TOOLTIP, PLACEHOLDERS};
+use crate::template::{Template, TemplateError};
+
+/// Highest usable `SIGRTMIN+N` offset on Linux (SIGRTMAX - SIGRTMIN)
+const MAX_SIGNAL: u8 = 30;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {}: {source}", .path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("Invalid config file {}: {source}", .path.display())]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[error("Invalid value for {var}: {value:?}")]
+    Env { var: &'static str, value: String },
+    #[error("Invalid format: {0}")]
+    Format(#[from] TemplateError),
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub state_path: PathBuf,
     pub signal: u8,
+    /// Process names or `*`/`?` patterns of the bars to signal
+    pub waybar_processes: Vec<String>,
     pub transcript_dir: PathBuf,
     pub format: String,
+    /// Tooltip template, rendered as Pango markup
+    pub tooltip_format: String,
     pub sessions_dir: PathBuf,
     pub socket_path: PathBuf,
+    pub pricing_path: PathBuf,
+    pub cursor_dir: PathBuf,
+    /// Durable cost history, appended to on every usage change
+    pub ledger_path: PathBuf,
+    pub timeouts: TimeoutConfig,
+    pub daemon: DaemonConfig,
+    pub context: ContextConfig,
+    pub budget: BudgetConfig,
+    pub rate: RateConfig,
+    pub block: BlockConfig,
+    pub metrics: MetricsConfig,
+    /// Icon overrides keyed by activity (e.g. `Thinking`, `Bash`, `Idle`)
+    pub icons: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Reset activity to Idle after this many seconds without events
+    pub activity_secs: u64,
+    /// Drop sessions from the aggregate view after this many idle seconds
+    pub stale_session_secs: u64,
+    /// Drop a session waiting for the user after this many seconds, in case
+    /// it was closed without ending
+    pub awaiting_input_secs: u64,
+    /// Keep a failed tool call or API error on the bar for at least this long
+    pub error_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Quiet period before signalling Waybar after an event
+    pub debounce_ms: u64,
+    /// Longest a signal is held back by a burst of events
+    pub max_debounce_ms: u64,
+    /// Minimum interval between state file writes
+    pub disk_flush_ms: u64,
+}
+
+/// Context-window fill thresholds for the `context-warn`/`context-critical` classes
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContextConfig {
+    pub warn_pct: u8,
+    pub critical_pct: u8,
+}
+
+/// Spending limits in USD for the `budget-warn`/`budget-over` classes and
+/// alerts; a limit of 0 is no limit
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BudgetConfig {
+    /// Spend across all sessions in a local day
+    pub daily: f64,
+    /// Spend across all sessions since Monday
+    pub weekly: f64,
+    /// Cost of a single session
+    pub session: f64,
+    pub warn_pct: u8,
+    /// Block new prompts and tool calls while a budget is used up
+    pub enforce: bool,
+    /// Program and arguments to run, with the message appended, when a
+    /// budget reaches `warn_pct` or runs out; empty for no alerts
+    pub notify_command: Vec<String>,
+}
+
+/// Averaging for the `{cost_per_hour}` and `{tokens_per_min}` burn rates
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateConfig {
+    /// Rolling window the rates are averaged over
+    pub window_secs: u64,
+}
+
+/// Subscription usage blocks, see [`crate::block`]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockConfig {
+    /// Input and output tokens a block allows; 0 uses the largest block
+    /// of the past week instead
+    pub token_limit: u64,
+}
+
+/// OpenMetrics export of the daemon's state; both outputs are off unless set
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Loopback address to serve `/metrics` on, e.g. `127.0.0.1:9464`
+    pub listen: Option<SocketAddr>,
+    /// File for node_exporter's textfile collector, rewritten with the state file
+    pub textfile_path: Option<PathBuf>,
 }
 
 impl Default for Config {
@@ -16,39 +146,255 @@ impl Default for Config {
         Self {
             state_path: default_state_path(),
             signal: 8,
+            waybar_processes: vec!["waybar".to_string(), ".waybar-wrapped".to_string()],
             transcript_dir: default_transcript_dir(),
             format: "{activity} | ${cost:.2}".to_string(),
+            tooltip_format: DEFAULT_TOOLTIP.to_string(),
             sessions_dir: default_sessions_dir(),
             socket_path: default_socket_path(),
+            pricing_path: default_pricing_path(),
+            cursor_dir: default_cursor_dir(),
+            ledger_path: default_ledger_path(),
+            timeouts: TimeoutConfig::default(),
+            daemon: DaemonConfig::default(),
+            context: ContextConfig::default(),
+            budget: BudgetConfig::default(),
+            rate: RateConfig::default(),
+            block: BlockConfig::default(),
+            metrics: MetricsConfig::default(),
+            icons: HashMap::new(),
         }
     }
 }
 
-impl Config {
-    pub fn from_env() -> Self {
+impl Default for TimeoutConfig {
+    fn default() -> Self {
         Self {
-            state_path: env::var("LLM_BRIDGE_STATE_PATH")
-                .map(PathBuf::from)
-                .unwrap_or_else(|_| default_state_path()),
-            signal: env::var("LLM_BRIDGE_SIGNAL")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(8),
-            transcript_dir: env::var("LLM_BRIDGE_TRANSCRIPT_DIR")
-                .map(PathBuf::from)
-                .unwrap_or_else(|_| default_transcript_dir()),
-            format: env::var("LLM_BRIDGE_FORMAT")
-                .unwrap_or_else(|_| "{activity} | ${cost:.2}".to_string()),
-            sessions_dir: env::var("LLM_BRIDGE_SESSIONS_DIR")
-                .map(PathBuf::from)
-                .unwrap_or_else(|_| default_sessions_dir()),
-            socket_path: env::var("LLM_BRIDGE_SOCKET_PATH")
-                .map(PathBuf::from)
-                .unwrap_or_else(|_| default_socket_path()),
+            activity_secs: 60,
+            stale_session_secs: 300,
+            awaiting_input_secs: 3600,
+            error_secs: 30,
+        }
+    }
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            debounce_ms: 16,
+            max_debounce_ms: 50,
+            disk_flush_ms: 100,
+        }
+    }
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        Self {
+            warn_pct: 70,
+            critical_pct: 90,
+        }
+    }
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        Self {
+            daily: 0.0,
+            weekly: 0.0,
+            session: 0.0,
+            warn_pct: 80,
+            enforce: false,
+            notify_command: vec![
+                "notify-send".to_string(),
+                "--app-name=llm-waybar".to_string(),
+                "LLM budget".to_string(),
+            ],
+        }
+    }
+}
+
+impl Default for RateConfig {
+    fn default() -> Self {
+        Self { window_secs: 600 }
+    }
+}
+
+impl Config {
+    /// Load defaults, then the config file, then environment overrides.
+    ///
+    /// `path` (or `LLM_BRIDGE_CONFIG`) names the config file explicitly, in
+    /// which case it must exist; the default location is optional.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let explicit = path
+            .map(Path::to_path_buf)
+            .or_else(|| env::var_os("LLM_BRIDGE_CONFIG").map(PathBuf::from));
+        let path = explicit.clone().unwrap_or_else(default_config_path);
+
+        let mut config = match fs::read_to_string(&path) {
+            Ok(content) => {
+                Self::from_toml(&content).map_err(|source| ConfigError::Parse { path, source })?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound && explicit.is_none() => Self::default(),
+            Err(source) => return Err(ConfigError::Read { path, source }),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse a config file; keys that are not set keep their defaults
+    pub fn from_toml(content: &str) -> Result<Self, toml::de::Error> {
+        let mut config: Self = toml::from_str(content)?;
+        for path in [
+            &mut config.state_path,
+            &mut config.transcript_dir,
+            &mut config.sessions_dir,
+            &mut config.socket_path,
+            &mut config.pricing_path,
+            &mut config.cursor_dir,
+            &mut config.ledger_path,
+        ] {
+            *path = expand_home(path);
+        }
+        if let Some(path) = &mut config.metrics.textfile_path {
+            *path = expand_home(path);
+        }
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        for (var, field) in [
+            ("LLM_BRIDGE_STATE_PATH", &mut self.state_path),
+            ("LLM_BRIDGE_TRANSCRIPT_DIR", &mut self.transcript_dir),
+            ("LLM_BRIDGE_SESSIONS_DIR", &mut self.sessions_dir),
+            ("LLM_BRIDGE_SOCKET_PATH", &mut self.socket_path),
+            ("LLM_BRIDGE_PRICING_PATH", &mut self.pricing_path),
+            ("LLM_BRIDGE_CURSOR_DIR", &mut self.cursor_dir),
+            ("LLM_BRIDGE_LEDGER_PATH", &mut self.ledger_path),
+        ] {
+            if let Some(value) = env::var_os(var) {
+                *field = PathBuf::from(value);
+            }
+        }
+
+        if let Ok(value) = env::var("LLM_BRIDGE_SIGNAL") {
+            self.signal = value.trim().parse().map_err(|_| ConfigError::Env {
+                var: "LLM_BRIDGE_SIGNAL",
+                value,
+            })?;
+        }
+
+        if let Ok(value) = env::var("LLM_BRIDGE_FORMAT") {
+            self.format = value;
+        }
+
+        if let Ok(value) = env::var("LLM_BRIDGE_TOOLTIP_FORMAT") {
+            self.tooltip_format = value;
         }
+
+        Ok(())
+    }
+
+    /// Parsed `format`, checked against the placeholders the bar provides
+    pub fn text_template(&self) -> Result<Template, TemplateError> {
+        Template::parse(&self.format, PLACEHOLDERS)
+    }
+
+    /// Parsed `tooltip_format`
+    pub fn tooltip_template(&self) -> Result<Template, TemplateError> {
+        Template::parse(&self.tooltip_format, PLACEHOLDERS)
+    }
+
+    /// Check values that parse but make no sense
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.signal > MAX_SIGNAL {
+            return Err(ConfigError::Invalid(format!(
+                "signal must be between 0 and {} (SIGRTMIN+N), got {}",
+                MAX_SIGNAL, self.signal
+            )));
+        }
+        if self.waybar_processes.iter().any(String::is_empty) {
+            return Err(ConfigError::Invalid("waybar_processes must not contain empty names".to_string()));
+        }
+        if self.format.is_empty() {
+            return Err(ConfigError::Invalid("format must not be empty".to_string()));
+        }
+        self.text_template()?;
+        self.tooltip_template()?;
+        if self.timeouts.activity_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "timeouts.activity_secs must be greater than 0".to_string(),
+            ));
+        }
+        if self.timeouts.stale_session_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "timeouts.stale_session_secs must be greater than 0".to_string(),
+            ));
+        }
+        if self.timeouts.awaiting_input_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "timeouts.awaiting_input_secs must be greater than 0".to_string(),
+            ));
+        }
+        if self.daemon.debounce_ms > self.daemon.max_debounce_ms {
+            return Err(ConfigError::Invalid(format!(
+                "daemon.debounce_ms ({}) must not exceed daemon.max_debounce_ms ({})",
+                self.daemon.debounce_ms, self.daemon.max_debounce_ms
+            )));
+        }
+        if self.context.warn_pct > self.context.critical_pct || self.context.critical_pct > 100 {
+            return Err(ConfigError::Invalid(format!(
+                "context thresholds must satisfy warn_pct <= critical_pct <= 100, got {} and {}",
+                self.context.warn_pct, self.context.critical_pct
+            )));
+        }
+        for (name, limit) in [
+            ("daily", self.budget.daily),
+            ("weekly", self.budget.weekly),
+            ("session", self.budget.session),
+        ] {
+            if !(limit >= 0.0 && limit.is_finite()) {
+                return Err(ConfigError::Invalid(format!("budget.{} must be 0 or more, got {}", name, limit)));
+            }
+        }
+        if !(1..=100).contains(&self.budget.warn_pct) {
+            return Err(ConfigError::Invalid(format!(
+                "budget.warn_pct must be between 1 and 100, got {}",
+                self.budget.warn_pct
+            )));
+        }
+        if self.rate.window_secs == 0 {
+            return Err(ConfigError::Invalid("rate.window_secs must be greater than 0".to_string()));
+        }
+        if let Some(addr) = self.metrics.listen.filter(|addr| !addr.ip().is_loopback()) {
+            return Err(ConfigError::Invalid(format!(
+                "metrics.listen must be a loopback address, got {}",
+                addr
+            )));
+        }
+        if let Some(activity) = self.icons.iter().find(|(_, icon)| icon.is_empty()).map(|(a, _)| a) {
+            return Err(ConfigError::Invalid(format!("icons.{} must not be empty", activity)));
+        }
+        Ok(())
+    }
+}
+
+/// `~/foo` -> `$HOME/foo`
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
     }
 }
 
+fn default_config_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("llm-waybar/config.toml")
+}
+
 fn default_state_path() -> PathBuf {
     if let Ok(runtime_dir) = env::var("XDG_RUNTIME_DIR") {
         PathBuf::from(runtime_dir).join("llm_state.json")
@@ -78,3 +424,142 @@ fn default_socket_path() -> PathBuf {
         PathBuf::from("/tmp/llm-bridge.sock")
     }
 }
+
+fn default_pricing_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("llm-waybar/pricing.toml")
+}
+
+fn default_cursor_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+        .join("llm-waybar/transcripts")
+}
+
+fn default_ledger_path() -> PathBuf {
+    dirs::state_dir()
+        .or_else(|| dirs::home_dir().map(|home| home.join(".local/state")))
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+        .join("llm-waybar/ledger.jsonl")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_values_override_defaults() {
+        let config = Config::from_toml(
+            r#"
+            signal = 10
+            waybar_processes = ["waybar", "*waybar-wrapped"]
+            format = "{icon} {activity}"
+            sessions_dir = "/tmp/sessions"
+
+            [timeouts]
+            activity_secs = 120
+
+            [daemon]
+            debounce_ms = 5
+
+            [icons]
+            Bash = ">"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.signal, 10);
+        assert_eq!(config.waybar_processes, vec!["waybar", "*waybar-wrapped"]);
+        assert_eq!(config.format, "{icon} {activity}");
+        assert_eq!(config.sessions_dir, PathBuf::from("/tmp/sessions"));
+        assert_eq!(config.timeouts.activity_secs, 120);
+        assert_eq!(config.daemon.debounce_ms, 5);
+        assert_eq!(config.icons.get("Bash").map(String::as_str), Some(">"));
+
+        // Unset keys keep their defaults, including inside sections
+        assert_eq!(config.timeouts.stale_session_secs, 300);
+        assert_eq!(config.timeouts.awaiting_input_secs, 3600);
+        assert_eq!(config.daemon.max_debounce_ms, 50);
+        assert_eq!(config.daemon.disk_flush_ms, 100);
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        let err = Config::from_toml("sginal = 8").unwrap_err();
+        assert!(err.to_string().contains("unknown field `sginal`"), "{}", err);
+
+        let err = Config::from_toml("[daemon]\ndebounce = 5").unwrap_err();
+        assert!(err.to_string().contains("unknown field `debounce`"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_rejects_inconsistent_values() {
+        let mut config = Config::default();
+        assert!(config.validate().is_ok());
+
+        config.daemon.debounce_ms = 100;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let config = Config {
+            signal: 40,
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let config = Config {
+            format: "{activity} | {csot}".to_string(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Format(TemplateError::UnknownPlaceholder { .. }))
+        ));
+
+        let mut config = Config::default();
+        config.budget.daily = -5.0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        config.budget.daily = 5.0;
+        config.budget.warn_pct = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut config = Config::default();
+        config.rate.window_secs = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let config = Config::from_toml("[metrics]\nlisten = \"0.0.0.0:9464\"").unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = Config::from_toml("[metrics]\nlisten = \"127.0.0.1:9464\"").unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_readme_example_is_valid() {
+        let readme = include_str!("../../../README.md");
+        let section = &readme[readme.find("### Config File").unwrap()..];
+        let start = section.find("```toml\n").unwrap() + "```toml\n".len();
+        let example = &section[start..start + section[start..].find("```").unwrap()];
+
+        let config = Config::from_toml(example).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.icons.get("Bash").map(String::as_str), Some("\u{f120}"));
+    }
+
+    #[test]
+    fn test_home_is_expanded_in_paths() {
+        let Some(home) = dirs::home_dir() else {
+            return;
+        };
+        let config = Config::from_toml("pricing_path = \"~/prices.toml\"").unwrap();
+        assert_eq!(config.pricing_path, home.join("prices.toml"));
+    }
+
+    #[test]
+    fn test_explicit_missing_config_file_is_an_error() {
+        let missing = std::env::temp_dir().join("llm_test_no_such_config.toml");
+        assert!(matches!(
+            Config::load(Some(&missing)),
+            Err(ConfigError::Read { .. })
+        ));
+    }
+}
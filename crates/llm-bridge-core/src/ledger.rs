@@ -0,0 +1,503 @@
+//! Durable spend history.
+//!
+//! Session state lives under `$XDG_RUNTIME_DIR` and is gone after a logout,
+//! so every change in a session's cumulative cost and usage is also appended
+//! to a JSON Lines file under `$XDG_STATE_HOME`:
+//!
+//! ```text
+//! {"time":1760605200,"session_id":"abc","cwd":"/src/x","model":"Opus 4.5","cost":1.25,"input_tokens":1200,...}
+//! ```
+//!
+//! Entries are snapshots, not increments. What a session spent in a period
+//! is the growth of its cost between snapshots, counted on the local day of
+//! the later one; a cost that drops means the counter restarted.
+//!
+//! Hooks check budgets on every prompt and tool call, so they do not read
+//! the whole file: `record` keeps an index next to it with the spend per day,
+//! recent sessions and how many bytes of the ledger that covers.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nix::libc;
+
+const SECS_PER_DAY: i64 = 86_400;
+
+/// How long after its latest snapshot a session stays in the index
+const INDEX_SESSION_SECS: i64 = 7 * SECS_PER_DAY;
+
+/// One snapshot of a session's cumulative usage
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// Unix timestamp of the snapshot
+    pub time: i64,
+    pub session_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub cwd: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub model: String,
+    /// Session cost so far, in USD
+    #[serde(default)]
+    pub cost: f64,
+    #[serde(default)]
+    pub input_tokens: u64,
+    #[serde(default)]
+    pub output_tokens: u64,
+    #[serde(default)]
+    pub cache_read: u64,
+    #[serde(default)]
+    pub cache_write: u64,
+    /// Prompts submitted so far
+    #[serde(default)]
+    pub turns: u64,
+    /// Written by `import` from a transcript rather than by a running
+    /// session; cost is then estimated from the pricing table
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub imported: bool,
+}
+
+impl LedgerEntry {
+    /// Whether the usage differs from `other`'s, ignoring time and labels
+    fn usage_differs(&self, other: &LedgerEntry) -> bool {
+        self.cost != other.cost
+            || self.input_tokens != other.input_tokens
+            || self.output_tokens != other.output_tokens
+            || self.cache_read != other.cache_read
+            || self.cache_write != other.cache_write
+            || self.turns != other.turns
+    }
+}
+
+/// Spend in the current calendar periods, in local time
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Spend {
+    pub today: f64,
+    /// Since Monday
+    pub week: f64,
+    pub month: f64,
+}
+
+/// The ledger file with an index of spend per local day
+#[derive(Debug, Default)]
+pub struct Ledger {
+    path: PathBuf,
+    /// Latest snapshot per session
+    sessions: HashMap<String, LedgerEntry>,
+    /// Cost added per local day, keyed by days since 1970-01-01
+    days: BTreeMap<i64, f64>,
+    /// Bytes of the file indexed so far
+    offset: u64,
+    /// When read from the index, sessions whose latest snapshot is older
+    /// than this Unix time are missing from `sessions`
+    since: i64,
+}
+
+/// What `record` saves next to the ledger for `Ledger::open_recent`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    offset: u64,
+    since: i64,
+    days: BTreeMap<i64, f64>,
+    sessions: Vec<LedgerEntry>,
+}
+
+impl Ledger {
+    /// An empty ledger that will write to `path`
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Read the ledger at `path`; a missing file is an empty ledger.
+    /// Lines that do not parse, such as one cut short by a crash, are skipped.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut ledger = Self::new(path);
+        ledger.catch_up()?;
+        Ok(ledger)
+    }
+
+    /// Like `open`, but start from the index `record` keeps next to the
+    /// ledger and read only what was appended since. Sessions last recorded
+    /// over a week ago are not looked up by `session`.
+    pub fn open_recent(path: &Path) -> io::Result<Self> {
+        let mut ledger = Self::new(path);
+        let index = fs::read(index_path(path)).ok().and_then(|data| serde_json::from_slice::<Index>(&data).ok());
+        if let Some(index) = index {
+            ledger.offset = index.offset;
+            ledger.since = index.since;
+            ledger.days = index.days;
+            ledger.sessions = index.sessions.into_iter().map(|e| (e.session_id.clone(), e)).collect();
+        }
+        ledger.catch_up()?;
+        Ok(ledger)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Latest snapshot of a session
+    pub fn session(&self, session_id: &str) -> Option<&LedgerEntry> {
+        self.sessions.get(session_id)
+    }
+
+    /// Append `entry` if its usage changed since the session's last snapshot.
+    /// Returns whether it was written.
+    pub fn record(&mut self, entry: LedgerEntry) -> io::Result<bool> {
+        if entry.session_id.is_empty() {
+            return Ok(false);
+        }
+        // Snapshots other processes appended count towards the same days
+        self.catch_up()?;
+        self.find_sessions(&[&entry.session_id])?;
+        if let Some(last) = self.sessions.get(&entry.session_id) {
+            if !entry.usage_differs(last) {
+                return Ok(false);
+            }
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        // End a line cut short by a crash rather than continue it
+        if fs::metadata(&self.path).is_ok_and(|m| m.len() > self.offset) {
+            line.insert(0, '\n');
+        }
+        // One write per line, so concurrent writers never interleave within it
+        OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(line.as_bytes())?;
+
+        self.catch_up()?;
+        self.write_index()?;
+        Ok(true)
+    }
+
+    /// Index the complete lines appended since `offset`
+    fn catch_up(&mut self) -> io::Result<()> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.reset();
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        // A file shorter than what was indexed was replaced; start over
+        if file.metadata()?.len() < self.offset {
+            self.reset();
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let (entries, read) = read_lines(BufReader::new(file))?;
+        // A resumed session's growth counts from its previous snapshot, even
+        // one the index left out
+        let ids: Vec<&str> = entries.iter().map(|e| e.session_id.as_str()).collect();
+        self.find_sessions(&ids)?;
+        self.offset += read;
+        for entry in entries {
+            self.index(entry);
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(&self.path);
+    }
+
+    /// Look up sessions the index left out in the part of the file read
+    fn find_sessions(&mut self, session_ids: &[&str]) -> io::Result<()> {
+        let missing: HashSet<&str> =
+            session_ids.iter().copied().filter(|id| !self.sessions.contains_key(*id)).collect();
+        if self.since == 0 || missing.is_empty() {
+            return Ok(());
+        }
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let (entries, _) = read_lines(BufReader::new(file).take(self.offset))?;
+        // Later snapshots replace earlier ones
+        for entry in entries.into_iter().filter(|e| missing.contains(e.session_id.as_str())) {
+            self.sessions.insert(entry.session_id.clone(), entry);
+        }
+        Ok(())
+    }
+
+    /// Save the days and the sessions of the last week, replacing the index
+    /// in one rename so that readers never see half of it
+    fn write_index(&self) -> io::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+        let since = self.since.max(now - INDEX_SESSION_SECS);
+        let index = Index {
+            offset: self.offset,
+            since,
+            days: self.days.clone(),
+            sessions: self.sessions.values().filter(|e| e.time >= since).cloned().collect(),
+        };
+        let path = index_path(&self.path);
+        let tmp = path.with_extension(format!("json.{}.tmp", std::process::id()));
+        fs::write(&tmp, serde_json::to_vec(&index)?)?;
+        fs::rename(&tmp, &path)
+    }
+
+    fn index(&mut self, entry: LedgerEntry) {
+        let previous = self.sessions.get(&entry.session_id).map_or(0.0, |last| last.cost);
+        let added = if entry.cost >= previous { entry.cost - previous } else { entry.cost };
+        if added > 0.0 {
+            *self.days.entry(local_day(entry.time)).or_default() += added;
+        }
+        self.sessions.insert(entry.session_id.clone(), entry);
+    }
+
+    /// Spend today, this week and this month as of Unix time `now`
+    pub fn spend(&self, now: i64) -> Spend {
+        let today = local_day(now);
+        let week_start = today - (today + 3).rem_euclid(7); // 1970-01-01 was a Thursday
+        let month_start = today - civil_from_days(today).2 as i64 + 1;
+
+        let mut spend = Spend::default();
+        for (&day, &cost) in self.days.range(week_start.min(month_start)..=today) {
+            if day == today {
+                spend.today += cost;
+            }
+            if day >= week_start {
+                spend.week += cost;
+            }
+            if day >= month_start {
+                spend.month += cost;
+            }
+        }
+        spend
+    }
+}
+
+/// The index kept next to the ledger at `path`
+pub fn index_path(path: &Path) -> PathBuf {
+    path.with_extension("index.json")
+}
+
+/// Snapshots on the complete lines of `reader`, with the bytes those span.
+/// A last line without its newline may still be being written.
+fn read_lines(mut reader: impl BufRead) -> io::Result<(Vec<LedgerEntry>, u64)> {
+    let mut entries = Vec::new();
+    let mut read = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line)?;
+        if n == 0 || line.last() != Some(&b'\n') {
+            return Ok((entries, read));
+        }
+        read += n as u64;
+        if let Ok(entry) = serde_json::from_slice(&line) {
+            entries.push(entry);
+        }
+    }
+}
+
+/// Every snapshot in the ledger at `path`, in file order. A missing file
+/// has none; lines that do not parse, such as one cut short by a crash, are
+/// skipped.
+pub fn read_entries(path: &Path) -> io::Result<Vec<LedgerEntry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Local calendar day of a Unix timestamp, as days since 1970-01-01
+pub fn local_day(time: i64) -> i64 {
+    (time + utc_offset(time)).div_euclid(SECS_PER_DAY)
+}
+
+/// Unix time of the local midnight that ends the day of `time`
+pub fn day_end(time: i64) -> i64 {
+    (local_day(time) + 1) * SECS_PER_DAY - utc_offset(time)
+}
+
+/// Offset of local time from UTC at `time`, in seconds
+fn utc_offset(time: i64) -> i64 {
+    let t = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the call; localtime_r is thread-safe
+    if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff
+}
+
+/// Day number of a calendar date, after Howard Hinnant's `days_from_civil`
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// (year, month 1-12, day 1-31) of a day number, after Howard Hinnant's
+/// `civil_from_days`
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_ledger(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("llm-bridge-ledger-{}-{}.jsonl", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(index_path(&path));
+        path
+    }
+
+    fn snapshot(session_id: &str, time: i64, cost: f64) -> LedgerEntry {
+        LedgerEntry {
+            time,
+            session_id: session_id.to_string(),
+            cost,
+            ..Default::default()
+        }
+    }
+
+    /// Noon local time on the given day number
+    fn noon(day: i64) -> i64 {
+        let guess = day * SECS_PER_DAY + SECS_PER_DAY / 2;
+        guess - utc_offset(guess)
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(20_377), (2025, 10, 16));
+        for days in [-800_000, -1, 0, 59, 11_016, 20_377, 2_932_896] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn test_spend_counts_cost_growth_per_period() {
+        let path = temp_ledger("spend");
+        let mut ledger = Ledger::open(&path).unwrap();
+        // Thursday 2025-10-16; Monday was the 13th, the month began on the 1st
+        let today = 20_377;
+
+        assert!(ledger.record(snapshot("a", noon(today - 20), 1.0)).unwrap()); // September
+        assert!(ledger.record(snapshot("a", noon(today - 10), 3.0)).unwrap()); // Oct 6
+        assert!(ledger.record(snapshot("a", noon(today - 3), 3.5)).unwrap()); // Monday
+        assert!(ledger.record(snapshot("b", noon(today - 1), 2.0)).unwrap());
+        assert!(ledger.record(snapshot("a", noon(today), 4.0)).unwrap());
+        // A restarted counter counts from zero
+        assert!(ledger.record(snapshot("b", noon(today), 0.25)).unwrap());
+        // Unchanged usage is not written again
+        assert!(!ledger.record(snapshot("a", noon(today) + 60, 4.0)).unwrap());
+
+        let expected = Spend { today: 0.75, week: 3.25, month: 5.25 };
+        let spend = ledger.spend(noon(today));
+        assert!((spend.today - expected.today).abs() < 1e-9, "{:?}", spend);
+        assert!((spend.week - expected.week).abs() < 1e-9, "{:?}", spend);
+        assert!((spend.month - expected.month).abs() < 1e-9, "{:?}", spend);
+
+        // The same totals come back from the file
+        let reopened = Ledger::open(&path).unwrap();
+        assert_eq!(reopened.sessions.len(), 2);
+        assert!((reopened.spend(noon(today)).month - expected.month).abs() < 1e-9);
+
+        fs::remove_file(&path).ok();
+        fs::remove_file(index_path(&path)).ok();
+    }
+
+    #[test]
+    fn test_open_recent_reads_from_the_index() {
+        let path = temp_ledger("recent");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let today = local_day(now);
+        let mut ledger = Ledger::open(&path).unwrap();
+        ledger.record(snapshot("old", noon(today - 10), 1.0)).unwrap();
+        ledger.record(snapshot("older", noon(today - 10), 3.0)).unwrap();
+        ledger.record(snapshot("a", noon(today - 1), 2.0)).unwrap();
+
+        // Another writer appends a snapshot and crashes halfway through the next
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"time\":0,\"session_id\":\"b\",\"cost\":0.5}\n{\"time\":0,\"sess").unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+
+        let recent = Ledger::open_recent(&path).unwrap();
+        assert_eq!(recent.offset, len - 15);
+        assert_eq!(recent.session("a").unwrap().cost, 2.0);
+        assert_eq!(recent.session("b").unwrap().cost, 0.5);
+        assert!(recent.session("old").is_none());
+        let full = Ledger::open(&path).unwrap();
+        assert_eq!(recent.spend(now), full.spend(now));
+        assert!(full.session("old").is_some());
+
+        // A session missing from the index is found in the file before it
+        // is recorded, so its unchanged usage is not written again
+        let mut recent = recent;
+        assert!(!recent.record(snapshot("old", now, 1.0)).unwrap());
+        assert!(recent.record(snapshot("old", now, 1.5)).unwrap());
+        assert!((recent.spend(now).today - (full.spend(now).today + 0.5)).abs() < 1e-9);
+
+        // Another writer's snapshot of a session the index left out adds
+        // only its growth
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "{}", serde_json::to_string(&snapshot("older", now, 3.5)).unwrap()).unwrap();
+        let recent = Ledger::open_recent(&path).unwrap();
+        assert_eq!(recent.spend(now), Ledger::open(&path).unwrap().spend(now));
+        assert_eq!(recent.session("older").unwrap().cost, 3.5);
+
+        // An index for a longer file than there is is ignored
+        fs::write(&path, "{\"time\":0,\"session_id\":\"c\",\"cost\":3.0}\n").unwrap();
+        let replaced = Ledger::open_recent(&path).unwrap();
+        assert_eq!(replaced.sessions.keys().collect::<Vec<_>>(), ["c"]);
+
+        fs::remove_file(&path).ok();
+        fs::remove_file(index_path(&path)).ok();
+    }
+
+    #[test]
+    fn test_open_skips_damaged_lines() {
+        let path = temp_ledger("damaged");
+        fs::write(
+            &path,
+            "{\"time\":86400,\"session_id\":\"a\",\"cost\":1.5}\n{\"time\":8640\n{\"time\":90000,\"session_id\":\"a\",\"cost\":2.0}\n",
+        )
+        .unwrap();
+
+        let ledger = Ledger::open(&path).unwrap();
+        assert_eq!(ledger.sessions["a"].cost, 2.0);
+        assert!(Ledger::open(&temp_ledger("missing")).unwrap().sessions.is_empty());
+
+        fs::remove_file(&path).ok();
+    }
+}
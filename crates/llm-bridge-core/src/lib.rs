@@ -1,10 +1,19 @@
+pub mod aggregate;
+pub mod block;
+pub mod budget;
 pub mod config;
+pub mod ledger;
+pub mod metrics;
 pub mod state;
 pub mod signal;
 pub mod provider;
+pub mod rate;
+pub mod report;
 pub mod socket;
+pub mod template;
 
-pub use config::Config;
+pub use config::{BlockConfig, BudgetConfig, Config, ConfigError, ContextConfig, MetricsConfig, RateConfig};
 pub use state::{WaybarState, AgentPhase};
-pub use provider::{LlmProvider, LlmEvent, UsageMetrics};
-pub use socket::{DaemonMessage, send_to_daemon};
+pub use provider::{LlmProvider, LlmEvent, HookEvent, UsageMetrics};
+pub use socket::{DaemonMessage, EventMessage, ProtocolError, send_to_daemon};
+pub use template::{Template, TemplateError};
@@ -0,0 +1,319 @@
+//! Daemon metrics in the Prometheus and OpenMetrics text formats.
+//!
+//! Session gauges are read from the daemon's state when rendered; only what
+//! the state does not keep is counted here: tool calls and their durations,
+//! errors and how long the bar takes to hear about an event.
+//!
+//! ```text
+//! llm_bridge_session_tokens{session="abc",type="output"} 5120
+//! llm_bridge_tool_invocations_total{tool="Bash"} 12
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::ledger::Ledger;
+use crate::provider::LlmEvent;
+use crate::state::WaybarState;
+
+/// Text exposition format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// As served over HTTP, ending in `# EOF`
+    OpenMetrics,
+    /// As read by node_exporter's textfile collector, which names counter
+    /// families with their `_total` suffix
+    Prometheus,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            Format::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+        }
+    }
+}
+
+/// Count and total of observed values, rendered as a summary
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Summary {
+    count: u64,
+    sum: f64,
+}
+
+impl Summary {
+    fn observe(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Counters the daemon keeps alongside its session state
+#[derive(Debug, Default)]
+pub struct Metrics {
+    tool_calls: BTreeMap<String, u64>,
+    tool_durations: BTreeMap<String, Summary>,
+    /// Unix time in ms each running tool started, by session and tool
+    tool_starts: HashMap<(String, String), i64>,
+    errors: u64,
+    signal_latency: Summary,
+}
+
+impl Metrics {
+    /// Count an event of `session_id` that happened at Unix time `time_ms`
+    pub fn record_event(&mut self, session_id: &str, event: &LlmEvent, time_ms: i64) {
+        match event {
+            LlmEvent::ToolStart { tool, .. } => {
+                *self.tool_calls.entry(tool.clone()).or_default() += 1;
+                self.tool_starts.insert((session_id.to_string(), tool.clone()), time_ms);
+            }
+            LlmEvent::ToolEnd { tool, error } => {
+                if let Some(start) = self.tool_starts.remove(&(session_id.to_string(), tool.clone())) {
+                    let secs = (time_ms - start).max(0) as f64 / 1000.0;
+                    self.tool_durations.entry(tool.clone()).or_default().observe(secs);
+                }
+                if error.is_some() {
+                    self.errors += 1;
+                }
+            }
+            LlmEvent::Stop { error: Some(_) } => self.errors += 1,
+            _ => {}
+        }
+    }
+
+    /// Drop the tool calls still running in a session that has ended
+    pub fn end_session(&mut self, session_id: &str) {
+        self.tool_starts.retain(|(sid, _), _| sid != session_id);
+    }
+
+    /// Time from the first event of a burst until the bar was told
+    pub fn record_signal(&mut self, latency: Duration) {
+        self.signal_latency.observe(latency.as_secs_f64());
+    }
+
+    /// Metrics for `sessions`, with cumulative usage from their latest
+    /// snapshot in `ledger`
+    pub fn render(&self, sessions: &[&WaybarState], ledger: &Ledger, format: Format) -> String {
+        let mut out = Exposition::new(format);
+
+        out.family("llm_bridge_sessions", "gauge", "Sessions the daemon is tracking.");
+        out.sample("llm_bridge_sessions", &[], sessions.len());
+        out.family("llm_bridge_sessions_active", "gauge", "Tracked sessions that are not idle.");
+        let active = sessions.iter().filter(|s| s.activity != "Idle").count();
+        out.sample("llm_bridge_sessions_active", &[], active);
+
+        let mut sessions: Vec<&WaybarState> = sessions.to_vec();
+        sessions.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+        let named = || sessions.iter().filter(|s| !s.session_id.is_empty());
+
+        out.family("llm_bridge_session_tokens", "gauge", "Tokens a session has used, by type.");
+        let mut totals = [0u64; 4];
+        for session in named() {
+            let Some(entry) = ledger.session(&session.session_id) else {
+                continue;
+            };
+            let tokens = [entry.input_tokens, entry.output_tokens, entry.cache_read, entry.cache_write];
+            for ((kind, count), total) in TOKEN_TYPES.iter().zip(tokens).zip(&mut totals) {
+                out.sample("llm_bridge_session_tokens", &[("session", &session.session_id), ("type", kind)], count);
+                *total += count;
+            }
+        }
+        out.family("llm_bridge_tokens", "gauge", "Tokens used by all tracked sessions, by type.");
+        for (kind, total) in TOKEN_TYPES.iter().zip(totals) {
+            out.sample("llm_bridge_tokens", &[("type", kind)], total);
+        }
+
+        out.family("llm_bridge_session_cost_usd", "gauge", "Cost of a session so far, in USD.");
+        for session in named() {
+            out.sample("llm_bridge_session_cost_usd", &[("session", &session.session_id)], session.cost);
+        }
+        out.family("llm_bridge_cost_usd", "gauge", "Cost of all tracked sessions, in USD.");
+        out.sample("llm_bridge_cost_usd", &[], sessions.iter().map(|s| s.cost).sum::<f64>());
+
+        out.family("llm_bridge_session_errors", "gauge", "Failed tool calls and API errors in a session.");
+        for session in named() {
+            out.sample("llm_bridge_session_errors", &[("session", &session.session_id)], session.error_count);
+        }
+        out.counter("llm_bridge_errors", "Failed tool calls and API errors since the daemon started.");
+        out.sample("llm_bridge_errors_total", &[], self.errors);
+
+        out.counter("llm_bridge_tool_invocations", "Tool calls started, by tool.");
+        for (tool, count) in &self.tool_calls {
+            out.sample("llm_bridge_tool_invocations_total", &[("tool", tool)], count);
+        }
+        out.family("llm_bridge_tool_duration_seconds", "summary", "Time from a tool call's start to its end.");
+        for (tool, summary) in &self.tool_durations {
+            out.summary("llm_bridge_tool_duration_seconds", &[("tool", tool)], summary);
+        }
+        out.family(
+            "llm_bridge_signal_latency_seconds",
+            "summary",
+            "Time from an event until the bar was signalled.",
+        );
+        out.summary("llm_bridge_signal_latency_seconds", &[], &self.signal_latency);
+
+        out.finish()
+    }
+}
+
+const TOKEN_TYPES: [&str; 4] = ["input", "output", "cache_read", "cache_write"];
+
+/// Text being rendered in one format
+struct Exposition {
+    format: Format,
+    text: String,
+}
+
+impl Exposition {
+    fn new(format: Format) -> Self {
+        Self {
+            format,
+            text: String::new(),
+        }
+    }
+
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+    }
+
+    /// A counter family, whose samples are named `<name>_total`
+    fn counter(&mut self, name: &str, help: &str) {
+        match self.format {
+            Format::OpenMetrics => self.family(name, "counter", help),
+            Format::Prometheus => self.family(&format!("{}_total", name), "counter", help),
+        }
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        let _ = write!(self.text, "{}", name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+                .collect();
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.text, " {}", value);
+    }
+
+    fn summary(&mut self, name: &str, labels: &[(&str, &str)], summary: &Summary) {
+        self.sample(&format!("{}_sum", name), labels, summary.sum);
+        self.sample(&format!("{}_count", name), labels, summary.count);
+    }
+
+    fn finish(mut self) -> String {
+        if self.format == Format::OpenMetrics {
+            self.text.push_str("# EOF\n");
+        }
+        self.text
+    }
+}
+
+/// Escape a label value: `\`, `"` and newlines
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::LedgerEntry;
+
+    fn tool_start(tool: &str) -> LlmEvent {
+        LlmEvent::ToolStart {
+            tool: tool.to_string(),
+            input: None,
+        }
+    }
+
+    fn tool_end(tool: &str, error: Option<&str>) -> LlmEvent {
+        LlmEvent::ToolEnd {
+            tool: tool.to_string(),
+            error: error.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_counts_tools_errors_and_latency() {
+        let mut metrics = Metrics::default();
+        metrics.record_event("a", &tool_start("Bash"), 1_000);
+        metrics.record_event("b", &tool_start("Bash"), 1_500);
+        metrics.record_event("a", &tool_end("Bash", Some("exit code 1")), 3_500);
+        metrics.record_event("b", &tool_end("Bash", None), 2_000);
+        metrics.record_event("a", &tool_start("Read"), 4_000);
+        metrics.record_event("a", &LlmEvent::Stop { error: Some("Overloaded".to_string()) }, 5_000);
+        metrics.end_session("a");
+        metrics.record_signal(Duration::from_millis(20));
+
+        let text = metrics.render(&[], &Ledger::default(), Format::OpenMetrics);
+        for line in [
+            "llm_bridge_sessions 0",
+            "# TYPE llm_bridge_tool_invocations counter",
+            "llm_bridge_tool_invocations_total{tool=\"Bash\"} 2",
+            "llm_bridge_tool_invocations_total{tool=\"Read\"} 1",
+            "llm_bridge_tool_duration_seconds_sum{tool=\"Bash\"} 3",
+            "llm_bridge_tool_duration_seconds_count{tool=\"Bash\"} 2",
+            "llm_bridge_errors_total 2",
+            "llm_bridge_signal_latency_seconds_sum 0.02",
+            "llm_bridge_signal_latency_seconds_count 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+        }
+        assert!(text.ends_with("\n# EOF\n"));
+        assert!(metrics.tool_starts.is_empty());
+
+        let text = metrics.render(&[], &Ledger::default(), Format::Prometheus);
+        assert!(text.contains("# TYPE llm_bridge_tool_invocations_total counter\n"));
+        assert!(!text.contains("# EOF"));
+    }
+
+    #[test]
+    fn test_session_gauges_come_from_state_and_ledger() {
+        let path = std::env::temp_dir().join(format!("llm-bridge-metrics-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut ledger = Ledger::new(&path);
+        ledger
+            .record(LedgerEntry {
+                time: 1,
+                session_id: "a\"b".to_string(),
+                cost: 1.25,
+                input_tokens: 100,
+                output_tokens: 200,
+                cache_read: 300,
+                ..Default::default()
+            })
+            .unwrap();
+        let a = WaybarState {
+            session_id: "a\"b".to_string(),
+            activity: "Bash".to_string(),
+            cost: 1.25,
+            error_count: 1,
+            ..Default::default()
+        };
+        let b = WaybarState {
+            session_id: "c".to_string(),
+            cost: 0.5,
+            ..Default::default()
+        };
+
+        let text = Metrics::default().render(&[&b, &a], &ledger, Format::OpenMetrics);
+        for line in [
+            "llm_bridge_sessions 2",
+            "llm_bridge_sessions_active 1",
+            "llm_bridge_session_tokens{session=\"a\\\"b\",type=\"cache_read\"} 300",
+            "llm_bridge_tokens{type=\"output\"} 200",
+            "llm_bridge_session_cost_usd{session=\"c\"} 0.5",
+            "llm_bridge_cost_usd 1.75",
+            "llm_bridge_session_errors{session=\"a\\\"b\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+        }
+        // Sessions the ledger has no usage for have no token samples
+        assert!(!text.contains("session=\"c\",type"));
+
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(crate::ledger::index_path(&path)).ok();
+    }
+}
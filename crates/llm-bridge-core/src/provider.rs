@@ -1,6 +1,9 @@
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
+use crate::state::AgentPhase;
+
 #[derive(Error, Debug)]
 pub enum ProviderError {
     #[error("Failed to parse event: {0}")]
@@ -14,22 +17,160 @@ pub enum ProviderError {
 #[derive(Debug, Clone)]
 pub enum LlmEvent {
     Submit { prompt: Option<String> },
-    ToolStart { tool: String, input: Option<String> },
+    ToolStart { tool: String, input: Option<serde_json::Value> },
     ToolEnd { tool: String, error: Option<String> },
-    Stop,
+    /// `error` is set when the turn ended on an API error
+    Stop { error: Option<String> },
+    /// The agent needs the user, e.g. for a permission prompt
+    Notification { message: Option<String> },
+    SubagentStop,
+    PreCompact,
+    /// `source` is how the session started (`startup`, `resume`, `clear`, ...)
+    SessionStart { source: Option<String> },
+    SessionEnd { reason: Option<String> },
+}
+
+impl LlmEvent {
+    /// Bridge event type, as accepted by `event --type`
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            LlmEvent::Submit { .. } => "submit",
+            LlmEvent::ToolStart { .. } => "tool-start",
+            LlmEvent::ToolEnd { .. } => "tool-end",
+            LlmEvent::Stop { .. } => "stop",
+            LlmEvent::Notification { .. } => "notification",
+            LlmEvent::SubagentStop => "subagent-stop",
+            LlmEvent::PreCompact => "pre-compact",
+            LlmEvent::SessionStart { .. } => "session-start",
+            LlmEvent::SessionEnd { .. } => "session-end",
+        }
+    }
+
+    /// Whether this event means the user answered an agent that was waiting
+    /// for them while `running_tool` ran; other events leave `AwaitingInput`
+    /// in place. A permission prompt is answered once its tool ends, and no
+    /// prompt is still open when the turn stops.
+    pub fn ends_wait(&self, running_tool: &str) -> bool {
+        match self {
+            LlmEvent::ToolEnd { tool, .. } => tool == running_tool,
+            LlmEvent::Submit { .. }
+            | LlmEvent::ToolStart { .. }
+            | LlmEvent::Stop { .. }
+            | LlmEvent::Notification { .. }
+            | LlmEvent::SessionStart { .. }
+            | LlmEvent::SessionEnd { .. } => true,
+            LlmEvent::SubagentStop | LlmEvent::PreCompact => false,
+        }
+    }
+
+    /// Whether this event means the user started over after an error, so
+    /// the error no longer needs to stay on the bar
+    pub fn ends_error(&self) -> bool {
+        matches!(
+            self,
+            LlmEvent::Submit { .. } | LlmEvent::SessionStart { .. } | LlmEvent::SessionEnd { .. }
+        )
+    }
+
+    /// The phase the agent is in after this event. `None` for `SessionEnd`,
+    /// which removes the session instead.
+    pub fn phase(&self) -> Option<AgentPhase> {
+        Some(match self {
+            LlmEvent::ToolEnd { tool, error: Some(error) } => AgentPhase::Error {
+                message: format!("{}: {}", tool, error),
+            },
+            LlmEvent::Stop { error: Some(error) } => AgentPhase::Error { message: error.clone() },
+            LlmEvent::Submit { .. } | LlmEvent::ToolEnd { .. } | LlmEvent::SubagentStop => {
+                AgentPhase::Thinking
+            }
+            LlmEvent::ToolStart { tool, .. } => AgentPhase::ToolUse { tool: tool.clone() },
+            LlmEvent::Stop { .. } | LlmEvent::SessionStart { .. } => AgentPhase::Idle,
+            LlmEvent::Notification { message } => {
+                let permission = message.as_deref().is_some_and(|m| m.contains("permission"));
+                AgentPhase::AwaitingInput {
+                    reason: if permission { "Waiting for permission" } else { "Waiting for input" }
+                        .to_string(),
+                }
+            }
+            LlmEvent::PreCompact => AgentPhase::Compacting,
+            LlmEvent::SessionEnd { .. } => return None,
+        })
+    }
+}
+
+/// An event together with the session context the agent's hook reported
+#[derive(Debug, Clone)]
+pub struct HookEvent {
+    pub event: LlmEvent,
+    pub session_id: Option<String>,
+    pub cwd: Option<String>,
+    pub transcript_path: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, Default)]
+impl HookEvent {
+    /// The tool the event is about, for tool events
+    pub fn tool(&self) -> Option<&str> {
+        match &self.event {
+            LlmEvent::ToolStart { tool, .. } | LlmEvent::ToolEnd { tool, .. } => Some(tool),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct UsageMetrics {
     pub input_tokens: u64,
     pub output_tokens: u64,
     pub cache_read: u64,
     pub cache_write: u64,
     pub estimated_cost: f64,
+    /// Prompts the user submitted
+    #[serde(default)]
+    pub turns: u64,
+}
+
+impl UsageMetrics {
+    pub fn add(&mut self, other: &UsageMetrics) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_read += other.cache_read;
+        self.cache_write += other.cache_write;
+        self.estimated_cost += other.estimated_cost;
+        self.turns += other.turns;
+    }
 }
 
 pub trait LlmProvider: Send + Sync {
     fn name(&self) -> &'static str;
-    fn parse_event(&self, event_type: &str, payload: Option<&str>) -> Result<LlmEvent, ProviderError>;
+    fn parse_event(&self, event_type: &str, payload: Option<&str>) -> Result<HookEvent, ProviderError>;
     fn parse_usage(&self, log_path: &Path) -> Result<UsageMetrics, ProviderError>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_phases() {
+        let notification = |message: &str| LlmEvent::Notification { message: Some(message.to_string()) };
+
+        assert_eq!(
+            notification("Claude needs your permission to use Bash").phase(),
+            Some(AgentPhase::AwaitingInput { reason: "Waiting for permission".to_string() })
+        );
+        assert_eq!(
+            notification("Claude is waiting for your input").phase(),
+            Some(AgentPhase::AwaitingInput { reason: "Waiting for input".to_string() })
+        );
+        assert_eq!(LlmEvent::PreCompact.phase(), Some(AgentPhase::Compacting));
+        assert_eq!(LlmEvent::SubagentStop.phase(), Some(AgentPhase::Thinking));
+        assert_eq!(LlmEvent::SessionStart { source: None }.phase(), Some(AgentPhase::Idle));
+        assert_eq!(LlmEvent::SessionEnd { reason: None }.phase(), None);
+
+        let failed = LlmEvent::ToolEnd { tool: "Bash".to_string(), error: Some("exit code 101".to_string()) };
+        assert_eq!(failed.phase(), Some(AgentPhase::Error { message: "Bash: exit code 101".to_string() }));
+        let api_error = LlmEvent::Stop { error: Some("API Error: 529 Overloaded".to_string()) };
+        assert_eq!(api_error.phase(), Some(AgentPhase::Error { message: "API Error: 529 Overloaded".to_string() }));
+        assert_eq!(LlmEvent::Stop { error: None }.phase(), Some(AgentPhase::Idle));
+    }
+}
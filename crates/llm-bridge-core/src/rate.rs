@@ -0,0 +1,156 @@
+//! Spend and token burn rates over a rolling window.
+//!
+//! `cost` is a running total, which cannot tell a cheap session that has run
+//! all day from an expensive burst. The daemon samples each session's
+//! cumulative cost and tokens on every statusline update; a rate is the
+//! growth since the newest sample at least one window old, over the time
+//! since then. A session that goes quiet so decays towards zero instead of
+//! keeping the rate of its last burst.
+
+use std::collections::VecDeque;
+
+use crate::ledger::day_end;
+
+/// Shortest span a rate is averaged over, so two updates a second apart do
+/// not extrapolate into an absurd hourly figure
+const MIN_SPAN_SECS: i64 = 60;
+
+/// Spend and token rates of one session or of all of them
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Rates {
+    /// USD per hour
+    pub cost_per_hour: f64,
+    /// Input and output tokens per minute
+    pub tokens_per_min: f64,
+}
+
+impl Rates {
+    pub fn add(&mut self, other: &Rates) {
+        self.cost_per_hour += other.cost_per_hour;
+        self.tokens_per_min += other.tokens_per_min;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sample {
+    time: i64,
+    cost: f64,
+    tokens: u64,
+}
+
+/// Cumulative samples of one session covering the last window
+#[derive(Debug, Clone)]
+pub struct BurnRate {
+    window_secs: i64,
+    samples: VecDeque<Sample>,
+}
+
+impl BurnRate {
+    pub fn new(window_secs: u64) -> Self {
+        Self {
+            window_secs: window_secs as i64,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Add the session's cumulative cost and tokens at Unix time `time`.
+    /// A total that drops means the counters restarted, so the window does too.
+    pub fn record(&mut self, time: i64, cost: f64, tokens: u64) {
+        if self.samples.back().is_some_and(|last| cost < last.cost || tokens < last.tokens) {
+            self.samples.clear();
+        }
+        self.samples.push_back(Sample { time, cost, tokens });
+
+        // Keep the newest sample from before the window as the baseline
+        while self.samples.len() > 1 && self.samples[1].time <= time - self.window_secs {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Rates over the window ending at Unix time `now`
+    pub fn rates(&self, now: i64) -> Rates {
+        let (Some(first), Some(last)) = (self.samples.front(), self.samples.back()) else {
+            return Rates::default();
+        };
+        let baseline = self
+            .samples
+            .iter()
+            .rev()
+            .find(|s| s.time <= now - self.window_secs)
+            .unwrap_or(first);
+
+        let span = (now - baseline.time).max(MIN_SPAN_SECS) as f64;
+        Rates {
+            cost_per_hour: (last.cost - baseline.cost) / span * 3600.0,
+            tokens_per_min: (last.tokens - baseline.tokens) as f64 / span * 60.0,
+        }
+    }
+}
+
+/// Spend by the end of the local day if `today` grows at `cost_per_hour`
+/// from Unix time `now`
+pub fn projected_today(today: f64, cost_per_hour: f64, now: i64) -> f64 {
+    today + cost_per_hour * (day_end(now) - now) as f64 / 3600.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rates_cover_the_window() {
+        let mut rate = BurnRate::new(600);
+        assert_eq!(rate.rates(0), Rates::default());
+
+        rate.record(1000, 1.0, 10_000);
+        assert_eq!(rate.rates(1000), Rates::default());
+
+        // $0.50 and 6000 tokens in 5 minutes
+        rate.record(1300, 1.5, 16_000);
+        let rates = rate.rates(1300);
+        assert!((rates.cost_per_hour - 6.0).abs() < 1e-9, "{:?}", rates);
+        assert!((rates.tokens_per_min - 1200.0).abs() < 1e-9, "{:?}", rates);
+
+        // Ten minutes later the first sample is the baseline, averaged to now
+        rate.record(1600, 2.0, 20_000);
+        let rates = rate.rates(1600);
+        assert!((rates.cost_per_hour - 6.0).abs() < 1e-9, "{:?}", rates);
+
+        // Once the window has moved on, older growth no longer counts
+        rate.record(2200, 2.0, 20_000);
+        assert_eq!(rate.samples.front().unwrap().time, 1600);
+        assert_eq!(rate.rates(2200), Rates::default());
+    }
+
+    #[test]
+    fn test_quiet_session_decays() {
+        let mut rate = BurnRate::new(600);
+        rate.record(0, 0.0, 0);
+        rate.record(60, 1.0, 1000);
+        assert!((rate.rates(60).cost_per_hour - 60.0).abs() < 1e-9);
+        assert!((rate.rates(600).cost_per_hour - 6.0).abs() < 1e-9);
+        assert_eq!(rate.rates(1200).cost_per_hour, 0.0);
+    }
+
+    #[test]
+    fn test_short_bursts_and_restarts() {
+        let mut rate = BurnRate::new(600);
+        rate.record(0, 0.0, 0);
+        // Averaged over at least a minute
+        rate.record(1, 0.1, 100);
+        assert!((rate.rates(1).cost_per_hour - 6.0).abs() < 1e-9);
+
+        // A restarted session starts a new window
+        rate.record(30, 0.05, 50);
+        assert_eq!(rate.rates(30), Rates::default());
+    }
+
+    #[test]
+    fn test_projected_today() {
+        let now = 1_760_605_200;
+        let hours_left = (day_end(now) - now) as f64 / 3600.0;
+        assert!(hours_left > 0.0 && hours_left <= 24.0);
+        assert!((projected_today(5.0, 2.0, now) - (5.0 + 2.0 * hours_left)).abs() < 1e-9);
+        assert_eq!(projected_today(5.0, 0.0, now), 5.0);
+    }
+}
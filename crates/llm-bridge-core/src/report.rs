@@ -0,0 +1,359 @@
+//! Spend and usage breakdowns over the ledger.
+//!
+//! The ledger holds cumulative snapshots per session, so a report first
+//! turns each snapshot into what was added since the session's previous one,
+//! then sums those increments by day, project, model or session.
+
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use unicode_width::UnicodeWidthStr;
+
+use crate::ledger::{civil_from_days, days_from_civil, local_day, LedgerEntry};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupBy {
+    Day,
+    Project,
+    Model,
+    Session,
+}
+
+impl GroupBy {
+    pub fn name(self) -> &'static str {
+        match self {
+            GroupBy::Day => "day",
+            GroupBy::Project => "project",
+            GroupBy::Model => "model",
+            GroupBy::Session => "session",
+        }
+    }
+}
+
+/// Totals for one group
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ReportRow {
+    pub key: String,
+    pub sessions: usize,
+    pub turns: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_read: u64,
+    pub cache_write: u64,
+    pub cost: f64,
+}
+
+impl ReportRow {
+    fn add(&mut self, usage: &Usage) {
+        self.turns += usage.turns;
+        self.input_tokens += usage.input_tokens;
+        self.output_tokens += usage.output_tokens;
+        self.cache_read += usage.cache_read;
+        self.cache_write += usage.cache_write;
+        self.cost += usage.cost;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub group_by: &'static str,
+    pub rows: Vec<ReportRow>,
+    pub total: ReportRow,
+}
+
+/// What a snapshot added to its session
+#[derive(Debug, Default)]
+struct Usage {
+    turns: u64,
+    input_tokens: u64,
+    output_tokens: u64,
+    cache_read: u64,
+    cache_write: u64,
+    cost: f64,
+}
+
+/// Growth of a cumulative counter. A value below the previous one means the
+/// counter restarted; zero means it was not reported, so the previous value
+/// is kept for the next snapshot.
+fn growth<T: Copy + Default + PartialOrd + std::ops::Sub<Output = T>>(last: &mut T, value: T) -> T {
+    if value == T::default() {
+        return T::default();
+    }
+    let added = if value >= *last { value - *last } else { value };
+    *last = value;
+    added
+}
+
+/// Local day number of a `YYYY-MM-DD` date
+pub fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok().filter(|m| (1..=12).contains(m))?;
+    let day = parts.next()?.parse().ok().filter(|d| (1..=31).contains(d))?;
+    Some(days_from_civil(year, month, day))
+}
+
+/// `YYYY-MM-DD` of a local day number
+pub fn format_date(day: i64) -> String {
+    let (year, month, day) = civil_from_days(day);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Sum what the sessions in `entries` spent on local days `since..=until`
+pub fn build(entries: &[LedgerEntry], group_by: GroupBy, since: Option<i64>, until: Option<i64>) -> Report {
+    let mut sorted: Vec<&LedgerEntry> = entries.iter().collect();
+    sorted.sort_by_key(|e| e.time);
+
+    let mut last: HashMap<&str, LedgerEntry> = HashMap::new();
+    let mut groups: HashMap<String, (ReportRow, HashSet<&str>)> = HashMap::new();
+    for entry in sorted {
+        let previous = last.entry(&entry.session_id).or_default();
+        let usage = Usage {
+            turns: growth(&mut previous.turns, entry.turns),
+            input_tokens: growth(&mut previous.input_tokens, entry.input_tokens),
+            output_tokens: growth(&mut previous.output_tokens, entry.output_tokens),
+            cache_read: growth(&mut previous.cache_read, entry.cache_read),
+            cache_write: growth(&mut previous.cache_write, entry.cache_write),
+            cost: growth(&mut previous.cost, entry.cost),
+        };
+        // Snapshots without a cwd or model belong to the session's last known one
+        if !entry.cwd.is_empty() {
+            previous.cwd = entry.cwd.clone();
+        }
+        if !entry.model.is_empty() {
+            previous.model = entry.model.clone();
+        }
+
+        let day = local_day(entry.time);
+        if since.is_some_and(|s| day < s) || until.is_some_and(|u| day > u) {
+            continue;
+        }
+
+        let key = match group_by {
+            GroupBy::Day => format_date(day),
+            GroupBy::Project => previous.cwd.clone(),
+            GroupBy::Model => previous.model.clone(),
+            GroupBy::Session => entry.session_id.clone(),
+        };
+        let key = if key.is_empty() { "(unknown)".to_string() } else { key };
+        let (row, sessions) = groups.entry(key).or_default();
+        row.add(&usage);
+        sessions.insert(&entry.session_id);
+    }
+
+    let mut total = ReportRow {
+        key: "Total".to_string(),
+        ..Default::default()
+    };
+    let mut all_sessions = HashSet::new();
+    let mut rows: Vec<ReportRow> = groups
+        .into_iter()
+        .map(|(key, (mut row, sessions))| {
+            row.key = key;
+            row.sessions = sessions.len();
+            total.turns += row.turns;
+            total.input_tokens += row.input_tokens;
+            total.output_tokens += row.output_tokens;
+            total.cache_read += row.cache_read;
+            total.cache_write += row.cache_write;
+            total.cost += row.cost;
+            all_sessions.extend(sessions);
+            row
+        })
+        .collect();
+    total.sessions = all_sessions.len();
+
+    // Days in order; everything else most expensive first
+    if group_by == GroupBy::Day {
+        rows.sort_by(|a, b| a.key.cmp(&b.key));
+    } else {
+        rows.sort_by(|a, b| b.cost.total_cmp(&a.cost).then_with(|| a.key.cmp(&b.key)));
+    }
+
+    Report {
+        group_by: group_by.name(),
+        rows,
+        total,
+    }
+}
+
+const COLUMNS: [&str; 8] = ["", "sessions", "turns", "input", "output", "cache_read", "cache_write", "cost"];
+
+fn fields(row: &ReportRow) -> [String; 8] {
+    [
+        row.key.clone(),
+        row.sessions.to_string(),
+        row.turns.to_string(),
+        row.input_tokens.to_string(),
+        row.output_tokens.to_string(),
+        row.cache_read.to_string(),
+        row.cache_write.to_string(),
+        format!("{:.2}", row.cost),
+    ]
+}
+
+impl Report {
+    /// Aligned columns with a total line, for reading in a terminal
+    pub fn to_table(&self) -> String {
+        let mut header = COLUMNS.map(str::to_string);
+        header[0] = self.group_by.to_string();
+        let body: Vec<[String; 8]> = self.rows.iter().map(fields).collect();
+        let total = fields(&self.total);
+
+        let mut widths = header.clone().map(|h| h.width());
+        for line in body.iter().chain([&total]) {
+            for (width, field) in widths.iter_mut().zip(line) {
+                *width = (*width).max(field.width());
+            }
+        }
+
+        let format_line = |line: &[String; 8]| {
+            let cells: Vec<String> = line
+                .iter()
+                .zip(widths)
+                .enumerate()
+                .map(|(i, (field, width))| {
+                    let pad = " ".repeat(width - field.width());
+                    // Keys align left, numbers right
+                    if i == 0 { format!("{}{}", field, pad) } else { format!("{}{}", pad, field) }
+                })
+                .collect();
+            cells.join("  ").trim_end().to_string()
+        };
+
+        let mut lines = vec![format_line(&header)];
+        lines.extend(body.iter().map(format_line));
+        lines.push("-".repeat(widths.iter().sum::<usize>() + 2 * (widths.len() - 1)));
+        lines.push(format_line(&total));
+        lines.join("\n")
+    }
+
+    /// One line per group under a header row; the total is left to the reader
+    pub fn to_csv(&self) -> String {
+        let mut header = COLUMNS.map(str::to_string);
+        header[0] = self.group_by.to_string();
+        let mut lines = vec![header.join(",")];
+        for row in &self.rows {
+            let mut line = fields(row);
+            line[0] = csv_field(&row.key);
+            line[7] = row.cost.to_string();
+            lines.push(line.join(","));
+        }
+        lines.join("\n")
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+/// Quote a CSV field when it holds a separator, quote or newline
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A time on local day `date`, `hour` hours into it by UTC
+    fn at(date: &str, hour: i64) -> i64 {
+        let day = parse_date(date).unwrap();
+        // Shift by whole days so the snapshot lands on `date` in any zone
+        let guess = day * 86_400 + hour * 3600;
+        guess - (local_day(guess) - day) * 86_400
+    }
+
+    fn snapshot(session: &str, time: i64, cwd: &str, model: &str, cost: f64, tokens: u64, turns: u64) -> LedgerEntry {
+        LedgerEntry {
+            time,
+            session_id: session.to_string(),
+            cwd: cwd.to_string(),
+            model: model.to_string(),
+            cost,
+            input_tokens: tokens,
+            output_tokens: tokens / 10,
+            turns,
+            ..Default::default()
+        }
+    }
+
+    fn sample() -> Vec<LedgerEntry> {
+        vec![
+            snapshot("a", at("2025-10-01", 12), "/src/app", "Opus", 1.0, 1000, 1),
+            snapshot("a", at("2025-10-01", 13), "/src/app", "Opus", 3.0, 5000, 3),
+            snapshot("b", at("2025-10-02", 12), "/src/lib", "Sonnet", 0.5, 2000, 2),
+            snapshot("a", at("2025-10-02", 12), "", "", 4.0, 0, 0), // usage unknown this time
+            snapshot("a", at("2025-10-03", 12), "/src/app", "Opus", 4.5, 6000, 4),
+        ]
+    }
+
+    #[test]
+    fn test_group_by_day_counts_growth_between_snapshots() {
+        let report = build(&sample(), GroupBy::Day, None, None);
+
+        let days: Vec<(&str, f64, u64, u64)> = report
+            .rows
+            .iter()
+            .map(|r| (r.key.as_str(), r.cost, r.input_tokens, r.turns))
+            .collect();
+        assert_eq!(
+            days,
+            vec![
+                ("2025-10-01", 3.0, 5000, 3),
+                ("2025-10-02", 1.5, 2000, 2),
+                ("2025-10-03", 0.5, 1000, 1),
+            ]
+        );
+        assert_eq!(report.total.cost, 5.0);
+        assert_eq!(report.total.sessions, 2);
+        assert_eq!(report.total.output_tokens, 800);
+    }
+
+    #[test]
+    fn test_group_by_project_and_model_with_date_range() {
+        let since = parse_date("2025-10-02");
+        let until = parse_date("2025-10-02");
+
+        let report = build(&sample(), GroupBy::Project, since, until);
+        let projects: Vec<(&str, f64)> = report.rows.iter().map(|r| (r.key.as_str(), r.cost)).collect();
+        assert_eq!(projects, vec![("/src/app", 1.0), ("/src/lib", 0.5)]);
+
+        let report = build(&sample(), GroupBy::Model, None, None);
+        assert_eq!(report.rows[0].key, "Opus");
+        assert_eq!(report.rows[0].cost, 4.5);
+        assert_eq!(report.rows[0].sessions, 1);
+    }
+
+    #[test]
+    fn test_output_formats() {
+        let report = build(&sample(), GroupBy::Session, None, None);
+
+        let table = report.to_table();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "session  sessions  turns  input  output  cache_read  cache_write  cost");
+        assert_eq!(lines[1], "a               1      4   6000     600           0            0  4.50");
+        assert!(lines[3].starts_with("-----"));
+        assert_eq!(lines[4], "Total           2      6   8000     800           0            0  5.00");
+
+        let csv = report.to_csv();
+        assert_eq!(csv.lines().nth(2), Some("b,1,2,2000,200,0,0,0.5"));
+        assert_eq!(csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(json["group_by"], "session");
+        assert_eq!(json["rows"][0]["cost"], 4.5);
+        assert_eq!(json["total"]["turns"], 6);
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("1970-01-02"), Some(1));
+        assert_eq!(format_date(parse_date("2025-10-16").unwrap()), "2025-10-16");
+        assert_eq!(parse_date("2025-13-01"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+}
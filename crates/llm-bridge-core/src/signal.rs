@@ -1,89 +1,230 @@
+use nix::errno::Errno;
 use nix::libc;
-use nix::sys::signal::{self, Signal};
-use nix::unistd::Pid;
-use std::process::Command;
-use std::time::Duration;
+use std::fs;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum SignalError {
     #[error("Failed to find waybar process")]
     WaybarNotFound,
-    #[error("Timed out finding waybar process")]
-    Timeout,
     #[error("Failed to send signal: {0}")]
     SendFailed(#[from] nix::errno::Errno),
     #[error("Invalid signal number: {0}")]
     InvalidSignal(u8),
 }
 
-/// Signal waybar to refresh. Returns Ok even if waybar not found or timeout.
+/// Signal every waybar instance to refresh. Returns Ok even if waybar is not found.
 /// This is best-effort - the state file is already written, waybar will poll eventually.
-pub fn signal_waybar(signal_num: u8) -> Result<(), SignalError> {
-    let pids = match find_waybar_pids() {
-        Ok(pids) => pids,
-        Err(SignalError::WaybarNotFound | SignalError::Timeout) => return Ok(()),
-        Err(e) => return Err(e),
-    };
-
-    let sig = Signal::try_from(libc::SIGRTMIN() + signal_num as i32)
-        .map_err(|_| SignalError::InvalidSignal(signal_num))?;
+pub fn signal_waybar(signal_num: u8, processes: &[String]) -> Result<(), SignalError> {
+    let pids = ProcessMatcher::new(processes).find_pids();
+    if pids.is_empty() {
+        return Ok(());
+    }
 
     for pid in pids {
-        let _ = signal::kill(Pid::from_raw(pid), sig); // Best effort
+        let _ = signal_pid(pid, signal_num); // Best effort
     }
 
     Ok(())
 }
 
-fn find_waybar_pids() -> Result<Vec<i32>, SignalError> {
-    use std::process::Stdio;
-    use std::io::Read;
-
-    let mut child = Command::new("pgrep")
-        .arg("-x")
-        .arg("waybar")
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .spawn()
-        .map_err(|_| SignalError::WaybarNotFound)?;
-
-    // Wait with timeout (500ms should be plenty for pgrep under normal load)
-    let timeout = Duration::from_millis(500);
-    let start = std::time::Instant::now();
-
-    loop {
-        match child.try_wait() {
-            Ok(Some(status)) => {
-                if !status.success() {
-                    return Err(SignalError::WaybarNotFound);
-                }
-                break;
+/// Send `SIGRTMIN+signal_num` to one process
+pub fn signal_pid(pid: i32, signal_num: u8) -> Result<(), SignalError> {
+    let sig = libc::SIGRTMIN() + signal_num as i32;
+    if sig > libc::SIGRTMAX() {
+        return Err(SignalError::InvalidSignal(signal_num));
+    }
+    // nix's `Signal` has no real-time signals, so call kill(2) directly
+    // SAFETY: kill takes plain integers and touches no memory of ours
+    if unsafe { libc::kill(pid, sig) } == -1 {
+        return Err(Errno::last().into());
+    }
+    Ok(())
+}
+
+/// Finds bar processes by name, reading `/proc` directly.
+///
+/// Patterns are matched against the process name in `/proc/<pid>/comm` and
+/// against the file name of its `argv[0]`, since `comm` is cut to 15 bytes.
+/// `*` matches any run of characters and `?` a single one, so
+/// `*waybar-wrapped` also finds NixOS's `.waybar-wrapped`.
+///
+/// This process and every other run of the same executable are never
+/// matched: `waybar-llm-bridge` fits a pattern such as `*waybar*`, and the
+/// refresh signal's default action would terminate the daemon and hooks.
+#[derive(Debug, Clone)]
+pub struct ProcessMatcher {
+    patterns: Vec<String>,
+    own_pid: i32,
+    /// File name of the current executable
+    own_exe: Option<String>,
+}
+
+impl ProcessMatcher {
+    pub fn new(patterns: &[String]) -> Self {
+        let own_exe = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.file_name()?.to_str().map(String::from));
+        Self {
+            patterns: patterns.to_vec(),
+            own_pid: std::process::id() as i32,
+            own_exe,
+        }
+    }
+
+    /// Every running process that matches, in PID order
+    pub fn find_pids(&self) -> Vec<i32> {
+        let Ok(entries) = fs::read_dir("/proc") else {
+            return Vec::new();
+        };
+
+        let mut pids: Vec<i32> = entries
+            .flatten()
+            .filter_map(|e| e.file_name().to_str()?.parse().ok())
+            .filter(|&pid| self.matches_pid(pid))
+            .collect();
+        pids.sort_unstable();
+        pids
+    }
+
+    /// Whether `pid` is still a matching process; false once it has exited
+    /// or the PID was reused by something else
+    pub fn matches_pid(&self, pid: i32) -> bool {
+        if pid == self.own_pid {
+            return false;
+        }
+        let Ok(comm) = fs::read_to_string(format!("/proc/{}/comm", pid)) else {
+            return false;
+        };
+
+        // Kernel threads have an empty cmdline; a process that has exited
+        // since `comm` was read has none at all
+        let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+        let argv0 = cmdline.split(|&b| b == 0).next().unwrap_or_default();
+        let argv0 = String::from_utf8_lossy(argv0);
+        let name = argv0.rsplit('/').next().unwrap_or_default();
+        if !name.is_empty() && self.own_exe.as_deref() == Some(name) {
+            return false;
+        }
+
+        self.matches_name(comm.trim_end_matches('\n')) || (!name.is_empty() && self.matches_name(name))
+    }
+
+    pub fn matches_name(&self, name: &str) -> bool {
+        self.patterns.iter().any(|p| glob_match(p, name))
+    }
+}
+
+/// Match `name` against a pattern where `*` is any run of characters and `?` one character
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Iterative matching with backtracking to the last `*`
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
             }
-            Ok(None) => {
-                if start.elapsed() > timeout {
-                    let _ = child.kill();
-                    return Err(SignalError::Timeout);
-                }
-                std::thread::sleep(Duration::from_millis(10));
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
             }
-            Err(_) => return Err(SignalError::WaybarNotFound),
+            _ => match star {
+                Some((sp, sn)) => {
+                    p = sp + 1;
+                    n = sn + 1;
+                    star = Some((sp, sn + 1));
+                }
+                None => return false,
+            },
         }
     }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut output = String::new();
-    if let Some(mut stdout) = child.stdout.take() {
-        let _ = stdout.read_to_string(&mut output);
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("waybar", "waybar"));
+        assert!(!glob_match("waybar", ".waybar-wrapped"));
+        assert!(glob_match("*waybar*", ".waybar-wrapped"));
+        assert!(glob_match("*waybar*", "waybar"));
+        assert!(glob_match("way?ar", "waybar"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("waybar*", "my-waybar"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
     }
 
-    let pids: Vec<i32> = output
-        .lines()
-        .filter_map(|s| s.trim().parse().ok())
-        .collect();
+    #[test]
+    fn test_finds_processes_by_comm() {
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = child.id() as i32;
 
-    if pids.is_empty() {
-        return Err(SignalError::WaybarNotFound);
+        let matcher = ProcessMatcher::new(&["sleep".to_string()]);
+        assert!(matcher.matches_pid(pid));
+        assert!(matcher.find_pids().contains(&pid));
+
+        let other = ProcessMatcher::new(&["no-such-bar-process".to_string()]);
+        assert!(!other.matches_pid(pid));
+        assert!(!other.find_pids().contains(&pid));
+        assert!(!matcher.matches_pid(-1));
+
+        child.kill().ok();
+        child.wait().ok();
     }
 
-    Ok(pids)
+    #[test]
+    fn test_never_matches_own_executable() {
+        let me = std::process::id() as i32;
+        let comm = fs::read_to_string("/proc/self/comm").unwrap();
+        for pattern in ["*waybar*".to_string(), "*".to_string(), comm.trim_end().to_string()] {
+            assert!(!ProcessMatcher::new(std::slice::from_ref(&pattern)).matches_pid(me), "{}", pattern);
+        }
+
+        // Another run of the same executable, here `sleep` under its name
+        let own_exe = std::env::current_exe().unwrap();
+        let dir = std::env::temp_dir().join(format!("llm-bridge-signal-{}", me));
+        fs::create_dir_all(&dir).unwrap();
+        let twin = dir.join(own_exe.file_name().unwrap());
+        let _ = fs::remove_file(&twin);
+        let sleep = ["/bin/sleep", "/usr/bin/sleep"].into_iter().find(|p| fs::metadata(p).is_ok()).unwrap();
+        std::os::unix::fs::symlink(sleep, &twin).unwrap();
+        let mut child = std::process::Command::new(&twin).arg("30").spawn().unwrap();
+        let pid = child.id() as i32;
+        // Until the exec completes, the child still shows this process's cmdline
+        for _ in 0..100 {
+            let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+            if cmdline.starts_with(twin.as_os_str().as_encoded_bytes()) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        assert!(!ProcessMatcher::new(&["*".to_string()]).matches_pid(pid));
+        assert!(ProcessMatcher::new(&["*".to_string()]).find_pids().iter().all(|&p| p != pid && p != me));
+
+        child.kill().ok();
+        child.wait().ok();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_signal_pid_sends_realtime_signal() {
+        use std::os::unix::process::ExitStatusExt;
+
+        // `sleep` has no handler, so the signal ends it
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        signal_pid(child.id() as i32, 8).unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGRTMIN() + 8));
+
+        assert!(matches!(signal_pid(child.id() as i32, 200), Err(SignalError::InvalidSignal(200))));
+    }
 }
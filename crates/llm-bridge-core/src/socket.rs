@@ -1,43 +1,160 @@
+//! Datagram protocol between the hook commands and the daemon
+//!
+//! Each datagram is one JSON object tagged with the protocol version and the
+//! message kind:
+//!
+//! ```text
+//! {"v":1,"kind":"event","event_type":"tool-start","tool":"Read","session_id":"abc","time_ms":1735000000000}
+//! {"v":1,"kind":"status","session_id":"abc","payload":"{...statusline json...}"}
+//! {"v":1,"kind":"subscribe","name":"llm-bridge-watch-4242"}
+//! ```
+//!
+//! Subscribers bind an abstract socket under `name` and receive one Waybar
+//! JSON line per datagram whenever the bar output changes.
+//!
+//! Older bridges sent colon-delimited text (`EVENT:tool-start:Read`,
+//! `STATUS:<json>`); the daemon still accepts those while hooks are updated.
+
+use serde::{Deserialize, Serialize};
 use std::io;
-use std::os::unix::net::UnixDatagram;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
 use std::path::Path;
 use std::time::Duration;
+use thiserror::Error;
+
+use crate::provider::LlmEvent;
+
+/// Version written into every message; the daemon refuses newer ones
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ProtocolError {
+    #[error("Unsupported protocol version {0} (daemon speaks {PROTOCOL_VERSION})")]
+    UnsupportedVersion(u32),
+    #[error("Malformed message: {0}")]
+    Malformed(String),
+}
 
-#[derive(Debug, Clone)]
+/// An activity event from a hook
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EventMessage {
+    pub event_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// When the hook fired, in Unix milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_ms: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_input: Option<serde_json::Value>,
+    /// Why a tool call or the turn failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Notification text, or how a session started or ended
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Provider-specific data passed through untouched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<serde_json::Value>,
+}
+
+impl EventMessage {
+    pub fn new(event_type: impl Into<String>) -> Self {
+        Self {
+            event_type: event_type.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The event this message describes; `None` for unknown event types
+    pub fn llm_event(&self) -> Option<LlmEvent> {
+        let tool = || self.tool.clone().unwrap_or_else(|| "unknown".to_string());
+        Some(match self.event_type.as_str() {
+            "submit" => LlmEvent::Submit { prompt: None },
+            "tool-start" => LlmEvent::ToolStart { tool: tool(), input: self.tool_input.clone() },
+            "tool-end" => LlmEvent::ToolEnd { tool: tool(), error: self.error.clone() },
+            "stop" => LlmEvent::Stop { error: self.error.clone() },
+            "notification" => LlmEvent::Notification { message: self.message.clone() },
+            "subagent-stop" => LlmEvent::SubagentStop,
+            "pre-compact" => LlmEvent::PreCompact,
+            "session-start" => LlmEvent::SessionStart { source: self.message.clone() },
+            "session-end" => LlmEvent::SessionEnd { reason: self.message.clone() },
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub enum DaemonMessage {
-    Event { event_type: String, tool: Option<String> },
-    Status { payload: String },
+    Event(EventMessage),
+    /// Raw statusline JSON from the provider
+    Status {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+        payload: String,
+    },
+    /// Ask for bar updates on the abstract socket `name`. Sent again
+    /// periodically, so a restarted daemon picks its subscribers back up
+    Subscribe { name: String },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    v: u32,
+    #[serde(flatten)]
+    message: &'a DaemonMessage,
+}
+
+#[derive(Deserialize)]
+struct Version {
+    v: u32,
 }
 
 impl DaemonMessage {
     pub fn encode(&self) -> String {
-        match self {
-            DaemonMessage::Event { event_type, tool } => {
-                match tool {
-                    Some(t) => format!("EVENT:{}:{}", event_type, t),
-                    None => format!("EVENT:{}", event_type),
-                }
-            }
-            DaemonMessage::Status { payload } => format!("STATUS:{}", payload),
+        let envelope = Envelope { v: PROTOCOL_VERSION, message: self };
+        // Every field is a string, integer or JSON value, so this cannot fail
+        serde_json::to_string(&envelope).expect("daemon message serializes")
+    }
+
+    pub fn decode(s: &str) -> Result<Self, ProtocolError> {
+        if !s.trim_start().starts_with('{') {
+            return Self::decode_legacy(s)
+                .ok_or_else(|| ProtocolError::Malformed("unknown legacy message".to_string()));
+        }
+
+        // Check the version first so a newer sender gets a clear error
+        let version: Version =
+            serde_json::from_str(s).map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+        if version.v == 0 || version.v > PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version.v));
         }
+        serde_json::from_str(s).map_err(|e| ProtocolError::Malformed(e.to_string()))
     }
 
-    pub fn decode(s: &str) -> Option<Self> {
+    /// `EVENT:<type>[:<tool>]` or `STATUS:<json>`
+    #[allow(clippy::manual_map)]
+    fn decode_legacy(s: &str) -> Option<Self> {
         if let Some(rest) = s.strip_prefix("EVENT:") {
             let parts: Vec<&str> = rest.splitn(2, ':').collect();
-            Some(DaemonMessage::Event {
-                event_type: parts[0].to_string(),
+            Some(DaemonMessage::Event(EventMessage {
                 tool: parts.get(1).map(|s| s.to_string()),
-            })
+                ..EventMessage::new(parts[0])
+            }))
         } else if let Some(rest) = s.strip_prefix("STATUS:") {
-            Some(DaemonMessage::Status { payload: rest.to_string() })
+            Some(DaemonMessage::Status { session_id: None, payload: rest.to_string() })
         } else {
             None
         }
     }
 }
 
-/// Try to send message to daemon. Returns Ok(true) if sent, Ok(false) if daemon not available.
+/// Send message to daemon. Returns Ok(true) if sent, Ok(false) if daemon not running.
 pub fn send_to_daemon(socket_path: &Path, message: &DaemonMessage) -> io::Result<bool> {
     let socket = match UnixDatagram::unbound() {
         Ok(s) => s,
@@ -55,3 +172,202 @@ pub fn send_to_daemon(socket_path: &Path, message: &DaemonMessage) -> io::Result
         Err(e) => Err(e),
     }
 }
+
+/// Bind the abstract socket a `watch` process receives bar updates on.
+/// Abstract sockets vanish with the process, so nothing is left to clean up.
+pub fn bind_subscriber(name: &str) -> io::Result<UnixDatagram> {
+    UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(name)?)
+}
+
+/// Send one bar update to a subscriber. `sender` should be non-blocking so a
+/// subscriber that stopped reading cannot stall the daemon.
+pub fn send_to_subscriber(sender: &UnixDatagram, name: &str, line: &str) -> io::Result<()> {
+    sender
+        .send_to_addr(line.as_bytes(), &SocketAddr::from_abstract_name(name)?)
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_event() -> EventMessage {
+        EventMessage {
+            event_type: "tool-start".to_string(),
+            tool: Some("mcp__github:create_issue".to_string()),
+            session_id: Some("abc:123".to_string()),
+            cwd: Some("/home/me/my project".to_string()),
+            time_ms: Some(1_735_000_000_123),
+            tool_input: Some(serde_json::json!({"command": "cargo test", "timeout": 60})),
+            error: Some("exit status 101\nline two".to_string()),
+            message: None,
+            payload: Some(serde_json::json!({"hook_event_name": "PreToolUse"})),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let messages = [
+            DaemonMessage::Event(full_event()),
+            DaemonMessage::Event(EventMessage::new("stop")),
+            DaemonMessage::Status {
+                session_id: Some("abc".to_string()),
+                payload: r#"{"session_id":"abc"}"#.to_string(),
+            },
+            DaemonMessage::Status { session_id: None, payload: String::new() },
+            DaemonMessage::Subscribe { name: "llm-bridge-watch-42".to_string() },
+        ];
+
+        for message in messages {
+            assert_eq!(DaemonMessage::decode(&message.encode()), Ok(message));
+        }
+    }
+
+    #[test]
+    fn test_encoding_is_versioned_json() {
+        let encoded = DaemonMessage::Event(EventMessage::new("submit")).encode();
+        assert_eq!(encoded, r#"{"v":1,"kind":"event","event_type":"submit"}"#);
+    }
+
+    #[test]
+    fn test_rejects_unknown_versions() {
+        assert_eq!(
+            DaemonMessage::decode(r#"{"v":2,"kind":"event","event_type":"submit"}"#),
+            Err(ProtocolError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            DaemonMessage::decode(r#"{"v":0,"kind":"event","event_type":"submit"}"#),
+            Err(ProtocolError::UnsupportedVersion(0))
+        );
+        assert!(matches!(
+            DaemonMessage::decode(r#"{"kind":"event","event_type":"submit"}"#),
+            Err(ProtocolError::Malformed(_))
+        ));
+        assert!(matches!(
+            DaemonMessage::decode(r#"{"v":1,"kind":"telemetry"}"#),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_ignores_unknown_fields() {
+        let decoded = DaemonMessage::decode(r#"{"v":1,"kind":"event","event_type":"stop","extra":true}"#);
+        assert_eq!(decoded, Ok(DaemonMessage::Event(EventMessage::new("stop"))));
+    }
+
+    #[test]
+    fn test_subscriber_receives_updates() {
+        let name = format!("llm-bridge-test-{}", std::process::id());
+        let subscriber = bind_subscriber(&name).unwrap();
+        let sender = UnixDatagram::unbound().unwrap();
+
+        send_to_subscriber(&sender, &name, r#"{"text":"Idle"}"#).unwrap();
+
+        let mut buf = [0u8; 64];
+        let n = subscriber.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], br#"{"text":"Idle"}"#);
+
+        drop(subscriber);
+        assert!(send_to_subscriber(&sender, &name, "{}").is_err());
+    }
+
+    #[test]
+    fn test_llm_event_from_message() {
+        for event_type in [
+            "submit", "tool-start", "tool-end", "stop", "notification", "subagent-stop",
+            "pre-compact", "session-start", "session-end",
+        ] {
+            let event = EventMessage::new(event_type).llm_event().unwrap();
+            assert_eq!(event.event_type(), event_type);
+        }
+        assert!(EventMessage::new("launch").llm_event().is_none());
+
+        let notification = EventMessage {
+            message: Some("Claude needs your permission to use Bash".to_string()),
+            ..EventMessage::new("notification")
+        };
+        assert!(matches!(
+            notification.llm_event(),
+            Some(LlmEvent::Notification { message: Some(m) }) if m.contains("permission")
+        ));
+    }
+
+    #[test]
+    fn test_decode_legacy() {
+        assert_eq!(
+            DaemonMessage::decode("EVENT:tool-start:Read"),
+            Ok(DaemonMessage::Event(EventMessage {
+                tool: Some("Read".to_string()),
+                ..EventMessage::new("tool-start")
+            }))
+        );
+        assert_eq!(
+            DaemonMessage::decode(r#"STATUS:{"session_id":"abc"}"#),
+            Ok(DaemonMessage::Status { session_id: None, payload: r#"{"session_id":"abc"}"#.to_string() })
+        );
+        assert!(DaemonMessage::decode("SESSION:abc:EVENT:stop").is_err());
+        assert!(DaemonMessage::decode("HELLO").is_err());
+    }
+
+    /// Small deterministic generator so the fuzz tests need no extra crates
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            self.0 >> 33
+        }
+
+        fn string(&mut self) -> String {
+            const PIECES: &[&str] = &[
+                "a", "Z", "0", ":", "{", "}", "\"", "\\", "\n", "\t", " ", "EVENT:", "STATUS:",
+                r#""v":1"#, ",", "\u{0}", "é", "󰔟", "\u{7f}",
+            ];
+            let len = self.next() % 12;
+            (0..len).map(|_| PIECES[(self.next() as usize) % PIECES.len()]).collect()
+        }
+
+        fn opt_string(&mut self) -> Option<String> {
+            if self.next().is_multiple_of(2) { Some(self.string()) } else { None }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_round_trip() {
+        let mut rng = Lcg(0x5eed);
+        for _ in 0..2000 {
+            let message = if rng.next().is_multiple_of(4) {
+                DaemonMessage::Status { session_id: rng.opt_string(), payload: rng.string() }
+            } else {
+                DaemonMessage::Event(EventMessage {
+                    event_type: rng.string(),
+                    tool: rng.opt_string(),
+                    session_id: rng.opt_string(),
+                    cwd: rng.opt_string(),
+                    time_ms: rng.opt_string().map(|_| rng.next() as i64 - (1 << 30)),
+                    tool_input: rng.opt_string().map(serde_json::Value::String),
+                    error: rng.opt_string(),
+                    message: rng.opt_string(),
+                    payload: rng.opt_string().map(|s| serde_json::json!({ "raw": s })),
+                })
+            };
+            assert_eq!(DaemonMessage::decode(&message.encode()), Ok(message));
+        }
+    }
+
+    #[test]
+    fn test_fuzz_garbage_does_not_panic() {
+        let mut rng = Lcg(0xbad);
+        let valid = DaemonMessage::Event(full_event()).encode();
+        for _ in 0..2000 {
+            let _ = DaemonMessage::decode(&rng.string());
+
+            // Truncated and spliced versions of a valid message
+            let cut = (rng.next() as usize) % valid.len();
+            if let Some(prefix) = valid.get(..cut) {
+                let _ = DaemonMessage::decode(prefix);
+                let _ = DaemonMessage::decode(&format!("{}{}", prefix, rng.string()));
+            }
+        }
+    }
+}
@@ -1,9 +1,16 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::provider::UsageMetrics;
+use crate::block::Block;
+use crate::budget::{self, format_duration, Spent};
+use crate::config::{BudgetConfig, ContextConfig};
+use crate::ledger::{LedgerEntry, Spend};
+use crate::provider::{LlmEvent, UsageMetrics};
+use crate::rate::{self, Rates};
+use crate::template::{escape_markup, Context, Template, Value};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WaybarState {
@@ -13,6 +20,8 @@ pub struct WaybarState {
     #[serde(default)]
     pub activity: String,        // Set by events (Idle, Thinking, Read, Edit, etc.)
     #[serde(default)]
+    pub detail: String,          // What the current tool works on (main.rs, cargo test)
+    #[serde(default)]
     pub cost: f64,               // Set by statusline
     #[serde(default)]
     pub input_tokens: u64,       // Set by statusline (if available)
@@ -28,6 +37,52 @@ pub struct WaybarState {
     pub session_id: String,          // Claude session ID
     #[serde(default)]
     pub cwd: String,                 // Working directory
+    #[serde(default)]
+    pub context_tokens: u64,         // Tokens in the context window (statusline)
+    #[serde(default)]
+    pub context_window: u64,         // Context window size of the model
+    #[serde(default)]
+    pub error_count: u32,            // Failed tool calls and API errors this session
+    #[serde(default)]
+    pub last_error: String,          // Message of the most recent one
+    #[serde(default)]
+    pub error_until: i64,            // Unix timestamp the error phase stays shown until
+    #[serde(default)]
+    pub tool: String,                // Full name of the running tool, which a permission prompt is for
+    #[serde(default)]
+    pub today_cost: f64,             // Spend across all sessions, from the ledger
+    #[serde(default)]
+    pub week_cost: f64,
+    #[serde(default)]
+    pub month_cost: f64,
+    #[serde(default)]
+    pub budget: String,              // Budget closest to running out: daily, weekly or session
+    #[serde(default)]
+    pub budget_pct: u32,             // Share of it spent
+    #[serde(default)]
+    pub budget_remaining: f64,       // USD left in it
+    #[serde(default)]
+    pub cost_per_hour: f64,          // Spend rate over the rolling window
+    #[serde(default)]
+    pub tokens_per_min: u64,
+    #[serde(default)]
+    pub projected_today_cost: f64,   // Spend by midnight at the rate across all sessions
+    #[serde(default)]
+    pub block_tokens: u64,           // Tokens used in the current usage block, across all sessions
+    #[serde(default)]
+    pub block_limit: u64,
+    #[serde(default)]
+    pub block_pct: u32,
+    #[serde(default)]
+    pub block_reset: String,         // Time left until the block resets, e.g. "2h 14m"
+    #[serde(default)]
+    pub block_limit_in: String,      // Time until the limit is hit at the current rate, if before the reset
+    #[serde(default)]
+    pub sessions: u32,               // Sessions in the bar view
+    #[serde(default)]
+    pub icon_counts: String,         // Active sessions per activity icon, e.g. "2 󰔟 1 󰈔"
+    #[serde(default)]
+    pub session_list: String,        // One line per session when there are several
 
     // Computed from above based on format string
     #[serde(default)]
@@ -36,6 +91,9 @@ pub struct WaybarState {
     pub tooltip: String,
     #[serde(default)]
     pub class: String,
+    /// Modifier classes emitted alongside `class` (e.g. `context-warn`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub classes: Vec<String>,
     #[serde(default)]
     pub alt: String,
     #[serde(default)]
@@ -47,6 +105,7 @@ impl Default for WaybarState {
         Self {
             model: String::new(),
             activity: "Idle".to_string(),
+            detail: String::new(),
             cost: 0.0,
             input_tokens: 0,
             output_tokens: 0,
@@ -55,60 +114,296 @@ impl Default for WaybarState {
             last_activity_time: 0,
             session_id: String::new(),
             cwd: String::new(),
+            context_tokens: 0,
+            context_window: 0,
+            error_count: 0,
+            last_error: String::new(),
+            error_until: 0,
+            tool: String::new(),
+            today_cost: 0.0,
+            week_cost: 0.0,
+            month_cost: 0.0,
+            budget: String::new(),
+            budget_pct: 0,
+            budget_remaining: 0.0,
+            cost_per_hour: 0.0,
+            tokens_per_min: 0,
+            projected_today_cost: 0.0,
+            block_tokens: 0,
+            block_limit: 0,
+            block_pct: 0,
+            block_reset: String::new(),
+            block_limit_in: String::new(),
+            sessions: 0,
+            icon_counts: String::new(),
+            session_list: String::new(),
             text: "Idle".to_string(),
             tooltip: String::new(),
             class: "idle".to_string(),
+            classes: Vec::new(),
             alt: "idle".to_string(),
             percentage: 0,
         }
     }
 }
 
+/// Nerd Font icon for an activity.
+///
+/// `overrides` (the `[icons]` config table) is checked first by exact
+/// activity name; its `default` entry replaces the generic tool icon.
+pub fn activity_icon<'a>(activity: &str, overrides: &'a HashMap<String, String>) -> &'a str {
+    if let Some(icon) = overrides.get(activity) {
+        return icon;
+    }
+    builtin_icon(activity)
+        .or_else(|| overrides.get("default").map(String::as_str))
+        .unwrap_or("\u{f0327}") // 󰌧 tool (default for other tools)
+}
+
+fn builtin_icon(activity: &str) -> Option<&'static str> {
+    match activity {
+        "Thinking" | "Thinking..." => Some("\u{f0517}"), // 󰔟 brain
+        "Read" => Some("\u{f0214}"), // 󰈔 file
+        "Edit" => Some("\u{f03eb}"), // 󰏫 pencil
+        "Write" => Some("\u{f03eb}"), // 󰏫 pencil
+        "Bash" => Some("\u{f018d}"), // 󰆍 terminal
+        "Grep" | "Glob" => Some("\u{f0349}"), // 󰍉 search
+        "Task" => Some("\u{f0517}"), // 󰔟 agent/task
+        "Idle" => Some("\u{f04b2}"), // 󰒲 sleep
+        "Waiting for permission" | "Waiting for input" => Some("\u{f009a}"), // 󰂚 bell
+        a if a.starts_with("Error") => Some("\u{f0026}"), // 󰀦 alert
+        _ => None,
+    }
+}
+
+/// Placeholders available in format strings
+/// - {model} - model name
+/// - {activity} - current activity
+/// - {detail} - what the current tool works on, e.g. a file name or command
+/// - {icon} - Nerd Font icon for current activity
+/// - {cost} - cost in USD (4 decimals unless given, e.g. {cost:.2})
+/// - {tokens} - total tokens (input + output)
+/// - {input_tokens}, {output_tokens} - token counts
+/// - {cache} - total cache tokens (read + write)
+/// - {cache_read}, {cache_write} - cache token counts
+/// - {cwd} - working directory, with the home directory shown as ~
+/// - {context_pct} - how full the context window is, 0-100
+/// - {context_tokens}, {context_window} - tokens in context / window size
+/// - {errors} - failed tool calls and API errors this session
+/// - {last_error} - message of the most recent error
+/// - {today_cost}, {week_cost}, {month_cost} - spend across all sessions
+///   this local day, week (from Monday) and month
+/// - {budget} - the budget closest to running out: daily, weekly or session
+/// - {budget_pct}, {budget_remaining} - share of it spent / USD left
+/// - {cost_per_hour}, {tokens_per_min} - burn rate over the last few minutes
+/// - {projected_today_cost} - spend by midnight at the current rate
+/// - {block_tokens}, {block_limit}, {block_pct} - tokens used in the current
+///   5-hour usage block / its limit / share of it used
+/// - {block_reset} - time until the block resets
+/// - {block_limit_in} - time until the limit is hit at the current rate
+/// - {sessions} - sessions in the bar view
+/// - {icon_counts} - active sessions per activity icon, e.g. `2 󰔟 1 󰈔`
+/// - {session_list} - one line per session, empty unless there are several
+pub const PLACEHOLDERS: &[&str] = &[
+    "model",
+    "activity",
+    "detail",
+    "icon",
+    "cost",
+    "tokens",
+    "input_tokens",
+    "output_tokens",
+    "cache",
+    "cache_read",
+    "cache_write",
+    "cwd",
+    "context_pct",
+    "context_tokens",
+    "context_window",
+    "errors",
+    "last_error",
+    "today_cost",
+    "week_cost",
+    "month_cost",
+    "budget",
+    "budget_pct",
+    "budget_remaining",
+    "cost_per_hour",
+    "tokens_per_min",
+    "projected_today_cost",
+    "block_tokens",
+    "block_limit",
+    "block_pct",
+    "block_reset",
+    "block_limit_in",
+    "sessions",
+    "icon_counts",
+    "session_list",
+];
+
+/// Tooltip shown unless `tooltip_format` is configured; one line per known value
+pub const DEFAULT_TOOLTIP: &str = "{?session_list}{sessions} sessions | ${cost:.2} total\n\n{session_list}\n\n{/session_list}\
+{?model}Model: {model}\n{/model}Activity: {activity}{?detail} ({detail}){/detail}\
+{?tokens}\nTokens: {input_tokens} in / {output_tokens} out{/tokens}\
+{?cache}\nCache: {cache_read} read / {cache_write} write{/cache}\
+{?cost}\nCost: ${cost:.4}{/cost}\
+{?errors}\nErrors: {errors} (last: {last_error:.80}){/errors}\
+{?cost_per_hour}\nRate: ${cost_per_hour:.2}/h, {tokens_per_min} tokens/min, ${projected_today_cost:.2} by midnight{/cost_per_hour}\
+{?block_tokens}\nBlock: {block_tokens:human} tokens{?block_limit} ({block_pct}%){/block_limit}, resets in {block_reset}\
+{?block_limit_in}, limit in {block_limit_in}{/block_limit_in}{/block_tokens}\
+{?budget}\nBudget: {budget_pct}% of {budget} used, ${budget_remaining:.2} left{/budget}";
+
+struct StateContext<'a> {
+    state: &'a WaybarState,
+    icons: &'a HashMap<String, String>,
+}
+
+impl Context for StateContext<'_> {
+    fn value(&self, name: &str) -> Value {
+        let state = self.state;
+        match name {
+            "model" => Value::Text(state.model.clone()),
+            "activity" => Value::Text(state.activity.clone()),
+            "detail" => Value::Text(state.detail.clone()),
+            "icon" => Value::Text(activity_icon(&state.activity, self.icons).to_string()),
+            "cost" => Value::Float { value: state.cost, precision: 4 },
+            "tokens" => Value::Int(state.input_tokens + state.output_tokens),
+            "input_tokens" => Value::Int(state.input_tokens),
+            "output_tokens" => Value::Int(state.output_tokens),
+            "cache" => Value::Int(state.cache_read + state.cache_write),
+            "cache_read" => Value::Int(state.cache_read),
+            "cache_write" => Value::Int(state.cache_write),
+            "cwd" => Value::Text(shorten_home(&state.cwd)),
+            "context_pct" => Value::Int(state.percentage as u64),
+            "context_tokens" => Value::Int(state.context_tokens),
+            "context_window" => Value::Int(state.context_window),
+            "errors" => Value::Int(state.error_count as u64),
+            "last_error" => Value::Text(state.last_error.clone()),
+            "today_cost" => Value::Float { value: state.today_cost, precision: 2 },
+            "week_cost" => Value::Float { value: state.week_cost, precision: 2 },
+            "month_cost" => Value::Float { value: state.month_cost, precision: 2 },
+            "budget" => Value::Text(state.budget.clone()),
+            "budget_pct" => Value::Int(state.budget_pct as u64),
+            "budget_remaining" => Value::Float { value: state.budget_remaining, precision: 2 },
+            "cost_per_hour" => Value::Float { value: state.cost_per_hour, precision: 2 },
+            "tokens_per_min" => Value::Int(state.tokens_per_min),
+            "projected_today_cost" => Value::Float { value: state.projected_today_cost, precision: 2 },
+            "block_tokens" => Value::Int(state.block_tokens),
+            "block_limit" => Value::Int(state.block_limit),
+            "block_pct" => Value::Int(state.block_pct as u64),
+            "block_reset" => Value::Text(state.block_reset.clone()),
+            "block_limit_in" => Value::Text(state.block_limit_in.clone()),
+            "sessions" => Value::Int(state.sessions as u64),
+            "icon_counts" => Value::Text(state.icon_counts.clone()),
+            "session_list" => Value::Text(state.session_list.clone()),
+            _ => Value::Text(String::new()),
+        }
+    }
+}
+
+/// `/home/me/src/x` -> `~/src/x`
+fn shorten_home(path: &str) -> String {
+    let home = dirs::home_dir().unwrap_or_default();
+    match Path::new(path).strip_prefix(&home) {
+        Ok(rest) if !home.as_os_str().is_empty() => Path::new("~").join(rest).display().to_string(),
+        _ => path.to_string(),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum AgentPhase {
     Idle,
     Thinking,
     ToolUse { tool: String },
+    /// Blocked on the user, e.g. a permission prompt
+    AwaitingInput { reason: String },
+    /// Summarising the conversation to free up context
+    Compacting,
     Error { message: String },
 }
 
+impl AgentPhase {
+    /// Activity text, CSS class and alt for this phase
+    pub fn display(&self) -> (String, &'static str, &'static str) {
+        match self {
+            AgentPhase::Idle => ("Idle".to_string(), "idle", "idle"),
+            AgentPhase::Thinking => ("Thinking".to_string(), "thinking", "active"),
+            AgentPhase::ToolUse { tool } => {
+                // Use char count for UTF-8 safety (avoid slicing mid-character)
+                let truncated = if tool.chars().count() > 20 {
+                    let s: String = tool.chars().take(17).collect();
+                    format!("{}...", s)
+                } else {
+                    tool.clone()
+                };
+                (truncated, "tool-active", "active")
+            }
+            AgentPhase::AwaitingInput { reason } => (reason.clone(), "attention", "attention"),
+            AgentPhase::Compacting => ("Compacting".to_string(), "compacting", "active"),
+            AgentPhase::Error { message } => {
+                let truncated = if message.chars().count() > 30 {
+                    let s: String = message.chars().take(27).collect();
+                    format!("{}...", s)
+                } else {
+                    message.clone()
+                };
+                (format!("Error: {}", truncated), "error", "error")
+            }
+        }
+    }
+}
+
+/// File a session's state is written to inside the sessions directory
+pub fn session_path(sessions_dir: &Path, session_id: &str) -> PathBuf {
+    sessions_dir.join(format!("{}.json", session_id))
+}
+
 impl WaybarState {
     /// Get Nerd Font icon for current activity
-    /// Maps activity states to appropriate icons
     pub fn get_activity_icon(&self) -> &str {
-        match self.activity.as_str() {
-            "Thinking" | "Thinking..." => "\u{f0517}", // 󰔟 brain
-            "Read" => "\u{f0214}", // 󰈔 file
-            "Edit" => "\u{f03eb}", // 󰏫 pencil
-            "Write" => "\u{f03eb}", // 󰏫 pencil
-            "Bash" => "\u{f018d}", // 󰆍 terminal
-            "Grep" | "Glob" => "\u{f0349}", // 󰍉 search
-            "Task" => "\u{f0517}", // 󰔟 agent/task
-            "Idle" => "\u{f04b2}", // 󰒲 sleep
-            _ => "\u{f0327}", // 󰌧 tool (default for other tools)
-        }
+        builtin_icon(&self.activity).unwrap_or("\u{f0327}")
     }
 
-    /// Check if activity has timed out (60 seconds) and reset to Idle if needed
-    /// Returns true if activity was reset
-    pub fn check_activity_timeout(&mut self) -> bool {
-        const TIMEOUT_SECS: i64 = 60;
+    /// Whether the agent is blocked on the user (permission prompt, question)
+    pub fn is_awaiting_input(&self) -> bool {
+        self.class == "attention"
+    }
 
-        if self.activity == "Idle" {
-            return false; // Already idle, no timeout
-        }
+    /// Whether the activity falls back to Idle after a quiet period. Waiting
+    /// for the user does not: it lasts until they answer.
+    pub fn can_time_out(&self) -> bool {
+        self.activity != "Idle" && !self.is_awaiting_input()
+    }
 
-        if self.last_activity_time == 0 {
-            return false; // No timestamp set yet
+    /// Unix time the shown activity resets to Idle, if it ever does. An
+    /// error lasts until `error_until` however long ago the last event was;
+    /// anything else lasts `timeout_secs` after it.
+    pub fn timeout_at(&self, timeout_secs: u64) -> Option<i64> {
+        if !self.can_time_out() || self.last_activity_time == 0 {
+            return None; // Idle, waiting for the user, or no timestamp yet
         }
+        if self.class == "error" && self.error_until > 0 {
+            return Some(self.error_until);
+        }
+        // Whole seconds compared with `>`: due a second after the limit
+        Some(self.last_activity_time + timeout_secs as i64 + 1)
+    }
+
+    /// Check if activity has timed out and reset to Idle if needed
+    /// Returns true if activity was reset
+    pub fn check_activity_timeout(&mut self, timeout_secs: u64) -> bool {
+        let Some(due) = self.timeout_at(timeout_secs) else {
+            return false;
+        };
 
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs() as i64;
 
-        if now - self.last_activity_time > TIMEOUT_SECS {
+        if now >= due {
             self.activity = "Idle".to_string();
+            self.detail.clear();
             self.class = "idle".to_string();
             self.alt = "idle".to_string();
             return true;
@@ -117,108 +412,209 @@ impl WaybarState {
         false
     }
 
-    /// Compute text field from format string with placeholder replacement
-    /// Supported placeholders:
-    /// - {model} - model name
-    /// - {activity} - current activity
-    /// - {icon} - Nerd Font icon for current activity
-    /// - {cost} or {cost:.N} - cost with optional precision
-    /// - {tokens} - total tokens (input + output)
-    /// - {input_tokens} - input tokens
-    /// - {output_tokens} - output tokens
-    /// - {cache_read} - cache read tokens
-    /// - {cache_write} - cache write tokens
+    /// Render `format` without icon overrides; parse errors are shown as text.
+    /// Prefer parsing once with [`Template::parse`] and calling [`Self::render`].
     pub fn compute_text(&self, format: &str) -> String {
-        let mut result = format.to_string();
+        match Template::parse(format, PLACEHOLDERS) {
+            Ok(template) => self.render(&template, &HashMap::new()),
+            Err(e) => e.to_string(),
+        }
+    }
 
-        // Replace model
-        result = result.replace("{model}", &self.model);
+    /// Render a text or tooltip template against this state, using configured
+    /// icon overrides. Waybar reads both as Pango markup, so state values are
+    /// escaped.
+    pub fn render(&self, template: &Template, icons: &HashMap<String, String>) -> String {
+        template.render_markup(&StateContext { state: self, icons })
+    }
 
-        // Replace activity
-        result = result.replace("{activity}", &self.activity);
+    /// Render the default tooltip (see [`DEFAULT_TOOLTIP`])
+    pub fn compute_tooltip(&self) -> String {
+        match Template::parse(DEFAULT_TOOLTIP, PLACEHOLDERS) {
+            Ok(template) => self.render(&template, &HashMap::new()),
+            Err(e) => e.to_string(),
+        }
+    }
 
-        // Replace icon
-        result = result.replace("{icon}", self.get_activity_icon());
+    /// Record context-window usage, setting `percentage` and the
+    /// `context-warn`/`context-critical` classes
+    pub fn set_context(&mut self, used: u64, window: u64, thresholds: &ContextConfig) {
+        self.context_tokens = used;
+        self.context_window = window;
+        self.percentage = used
+            .saturating_mul(100)
+            .checked_div(window)
+            .map_or(0, |pct| pct.min(100) as u8);
+
+        self.classes.retain(|c| !c.starts_with("context-"));
+        if self.percentage >= thresholds.critical_pct {
+            self.classes.push("context-critical".to_string());
+        } else if self.percentage >= thresholds.warn_pct {
+            self.classes.push("context-warn".to_string());
+        }
+    }
 
-        // Replace cost with various precisions
-        // Handle {cost:.2}, {cost:.4}, etc.
-        for precision in [0, 1, 2, 3, 4, 5, 6] {
-            let placeholder = format!("{{cost:.{}}}", precision);
-            if result.contains(&placeholder) {
-                result = result.replace(&placeholder, &format!("{:.prec$}", self.cost, prec = precision));
+    /// JSON for Waybar's custom module; `class` becomes a list when
+    /// modifier classes are set
+    pub fn to_waybar_json(&self) -> serde_json::Result<String> {
+        let mut value = serde_json::to_value(self)?;
+        if let Some(object) = value.as_object_mut() {
+            object.remove("classes");
+            if !self.classes.is_empty() {
+                let mut classes = vec![self.class.clone()];
+                classes.extend(self.classes.iter().cloned());
+                object.insert("class".to_string(), classes.into());
             }
         }
-        // Handle plain {cost} (default precision 4)
-        result = result.replace("{cost}", &format!("{:.4}", self.cost));
-
-        // Replace token counts
-        let total_tokens = self.input_tokens + self.output_tokens;
-        result = result.replace("{tokens}", &total_tokens.to_string());
-        result = result.replace("{input_tokens}", &self.input_tokens.to_string());
-        result = result.replace("{output_tokens}", &self.output_tokens.to_string());
-        result = result.replace("{cache_read}", &self.cache_read.to_string());
-        result = result.replace("{cache_write}", &self.cache_write.to_string());
+        serde_json::to_string(&value)
+    }
 
-        result
+    /// Replace token counts and cost with transcript-derived usage
+    pub fn set_usage(&mut self, usage: &UsageMetrics) {
+        self.input_tokens = usage.input_tokens;
+        self.output_tokens = usage.output_tokens;
+        self.cache_read = usage.cache_read;
+        self.cache_write = usage.cache_write;
+        self.cost = usage.estimated_cost;
     }
 
-    /// Compute detailed tooltip with all available information
-    pub fn compute_tooltip(&self) -> String {
-        let mut parts = Vec::new();
+    /// Set the spend totals shown by `{today_cost}`, `{week_cost}` and `{month_cost}`
+    pub fn set_spend(&mut self, spend: &Spend) {
+        self.today_cost = spend.today;
+        self.week_cost = spend.week;
+        self.month_cost = spend.month;
+    }
 
-        if !self.model.is_empty() {
-            parts.push(format!("Model: {}", self.model));
+    /// What this session and all sessions have spent, for budget checks
+    pub fn spent(&self) -> Spent {
+        Spent {
+            session: self.cost,
+            today: self.today_cost,
+            week: self.week_cost,
         }
+    }
 
-        if !self.activity.is_empty() {
-            parts.push(format!("Activity: {}", self.activity));
+    /// Check the budgets against `spent()`, setting `{budget}`,
+    /// `{budget_pct}`, `{budget_remaining}` and the `budget-warn`/`budget-over`
+    /// classes. Call after `set_spend`.
+    pub fn set_budget(&mut self, config: &BudgetConfig) {
+        self.classes.retain(|c| !c.starts_with("budget-"));
+        match budget::tightest(config, &self.spent()) {
+            Some(tightest) => {
+                self.budget = tightest.name.to_string();
+                self.budget_pct = tightest.pct().floor() as u32;
+                self.budget_remaining = tightest.remaining();
+                if let Some(class) = tightest.level(config.warn_pct).class() {
+                    self.classes.push(class.to_string());
+                }
+            }
+            None => {
+                self.budget.clear();
+                self.budget_pct = 0;
+                self.budget_remaining = 0.0;
+            }
         }
+    }
+
+    /// Set `{cost_per_hour}` and `{tokens_per_min}` from this session's
+    /// rates, and `{projected_today_cost}` from today's spend growing at the
+    /// rate of `all` sessions from Unix time `now`. Call after `set_spend`.
+    pub fn set_rates(&mut self, session: &Rates, all: &Rates, now: i64) {
+        self.cost_per_hour = session.cost_per_hour;
+        self.tokens_per_min = session.tokens_per_min.round() as u64;
+        self.projected_today_cost = rate::projected_today(self.today_cost, all.cost_per_hour, now);
+    }
+
+    /// Set the `{block_*}` placeholders from the usage block in progress at
+    /// Unix time `now`, if there is one, and its token `limit` (0 if unknown)
+    pub fn set_block(&mut self, current: Option<Block>, limit: u64, now: i64) {
+        let block = current.unwrap_or_default();
+        self.block_tokens = block.tokens;
+        self.block_limit = limit;
+        self.block_pct = match limit {
+            0 => 0,
+            _ => (block.tokens as f64 / limit as f64 * 100.0).floor() as u32,
+        };
+        self.block_reset = match current {
+            Some(block) => format_duration((block.end() - now) as u64),
+            None => String::new(),
+        };
+        self.block_limit_in = current
+            .and_then(|block| block.limit_eta(limit, now))
+            .map_or_else(String::new, |eta| format_duration(eta as u64));
+    }
 
-        if self.input_tokens > 0 || self.output_tokens > 0 {
-            parts.push(format!(
-                "Tokens: {} in / {} out",
-                self.input_tokens, self.output_tokens
-            ));
+    /// A ledger snapshot of this session at Unix time `time`. The token
+    /// fields here only cover the last request, so token counts come from
+    /// `usage`, the session's cumulative transcript usage, when known.
+    pub fn ledger_entry(&self, time: i64, usage: Option<&UsageMetrics>) -> LedgerEntry {
+        let usage = usage.cloned().unwrap_or_default();
+        LedgerEntry {
+            time,
+            session_id: self.session_id.clone(),
+            cwd: self.cwd.clone(),
+            model: self.model.clone(),
+            cost: self.cost,
+            input_tokens: usage.input_tokens,
+            output_tokens: usage.output_tokens,
+            cache_read: usage.cache_read,
+            cache_write: usage.cache_write,
+            turns: usage.turns,
+            imported: false,
         }
+    }
+
+    /// Set activity, class and alt for a phase, leaving usage untouched.
+    /// Clears `detail`, which belongs to the tool call that was running.
+    pub fn set_phase(&mut self, phase: &AgentPhase) {
+        let (activity, class, alt) = phase.display();
+        self.activity = activity;
+        self.detail.clear();
+        self.class = class.to_string();
+        self.alt = alt.to_string();
+    }
 
-        if self.cache_read > 0 || self.cache_write > 0 {
-            parts.push(format!(
-                "Cache: {} read / {} write",
-                self.cache_read, self.cache_write
-            ));
+    /// Apply the phase `event` leads to, at Unix time `now`.
+    ///
+    /// An error is counted and stays on the bar for `error_secs`, so the next
+    /// tool call does not hide it at once; only another error, a wait for the
+    /// user or the user starting over replaces it. Waiting for the user in turn
+    /// lasts until they answer. Returns whether the phase is now shown.
+    pub fn apply_phase(&mut self, phase: &AgentPhase, event: &LlmEvent, now: i64, error_secs: u64) -> bool {
+        if let AgentPhase::Error { message } = phase {
+            self.error_count += 1;
+            self.last_error = message.clone();
+            self.error_until = now + error_secs as i64;
         }
 
-        if self.cost > 0.0 {
-            parts.push(format!("Cost: ${:.4}", self.cost));
+        let ends_wait = event.ends_wait(&self.tool);
+        match event {
+            LlmEvent::ToolStart { tool, .. } => self.tool = tool.clone(),
+            // A prompt during a tool call is about that tool
+            LlmEvent::Notification { .. } => {}
+            _ if ends_wait => self.tool.clear(),
+            _ => {}
         }
 
-        parts.join("\n")
+        let overrides_error = event.ends_error()
+            || matches!(phase, AgentPhase::Error { .. } | AgentPhase::AwaitingInput { .. });
+        let showing_error = self.class == "error" && now < self.error_until;
+        if (self.is_awaiting_input() && !ends_wait) || (showing_error && !overrides_error) {
+            return false;
+        }
+        self.set_phase(phase);
+        true
     }
 
     /// Create state from agent phase, setting the activity field
     pub fn from_phase(phase: &AgentPhase, usage: Option<&UsageMetrics>) -> Self {
-        let (activity, class, alt) = match phase {
-            AgentPhase::Idle => ("Idle".to_string(), "idle".to_string(), "idle".to_string()),
-            AgentPhase::Thinking => ("Thinking".to_string(), "thinking".to_string(), "active".to_string()),
-            AgentPhase::ToolUse { tool } => (tool.clone(), "tool-active".to_string(), "active".to_string()),
-            AgentPhase::Error { message } => (format!("Error: {}", message), "error".to_string(), "error".to_string()),
-        };
-
-        let mut state = Self {
-            activity: activity.clone(),
-            class,
-            alt,
-            text: activity, // Default text is activity
-            ..Default::default()
-        };
+        let mut state = Self::default();
+        state.set_phase(phase);
+        state.text = escape_markup(&state.activity); // Default text is activity
 
         // Update usage metrics if provided
         if let Some(u) = usage {
-            state.input_tokens = u.input_tokens;
-            state.output_tokens = u.output_tokens;
-            state.cache_read = u.cache_read;
-            state.cache_write = u.cache_write;
-            state.cost = u.estimated_cost;
+            state.set_usage(u);
             state.tooltip = state.compute_tooltip();
         }
 
@@ -246,23 +642,20 @@ impl WaybarState {
         // Ensure directory exists
         fs::create_dir_all(sessions_dir)?;
 
-        let session_path = sessions_dir.join(format!("{}.json", self.session_id));
-        self.write_atomic(&session_path)
+        self.write_atomic(&session_path(sessions_dir, &self.session_id))
     }
 
+    /// Read a state file as written; callers apply `check_activity_timeout`
     pub fn read_from(path: &Path) -> std::io::Result<Self> {
         let content = fs::read_to_string(path)?;
-        let mut state: Self = serde_json::from_str(&content)
+        let state: Self = serde_json::from_str(&content)
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-
-        // Check for activity timeout and reset to Idle if needed
-        state.check_activity_timeout();
-
         Ok(state)
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison, clippy::field_reassign_with_default)]
 mod tests {
     use super::*;
 
@@ -339,6 +732,58 @@ mod tests {
         assert_eq!(result, "\u{f0517} Thinking"); // brain icon + Thinking
     }
 
+    #[test]
+    fn test_render_with_icon_overrides() {
+        let icons = HashMap::from([
+            ("Bash".to_string(), "$".to_string()),
+            ("default".to_string(), "*".to_string()),
+        ]);
+        let template = Template::parse("{icon} {activity}", PLACEHOLDERS).unwrap();
+
+        let state = WaybarState {
+            activity: "Bash".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(state.render(&template, &icons), "$ Bash");
+
+        let state = WaybarState {
+            activity: "WebFetch".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(state.render(&template, &icons), "* WebFetch");
+
+        // Activities without an override keep the built-in icon
+        let state = WaybarState {
+            activity: "Read".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(state.render(&template, &icons), "\u{f0214} Read");
+    }
+
+    #[test]
+    fn test_compute_text_conditional_cost_and_human_tokens() {
+        let format = "{activity}{?cost} | ${cost:.2}{/cost} | {tokens:human}";
+        let state = WaybarState {
+            activity: "Read".to_string(),
+            input_tokens: 12450,
+            output_tokens: 3201,
+            ..Default::default()
+        };
+        assert_eq!(state.compute_text(format), "Read | 15.7k");
+
+        let state = WaybarState { cost: 1.5, ..state };
+        assert_eq!(state.compute_text(format), "Read | $1.50 | 15.7k");
+    }
+
+    #[test]
+    fn test_compute_text_reports_unknown_placeholder() {
+        let state = WaybarState::default();
+        assert_eq!(
+            state.compute_text("{activty}"),
+            "unknown placeholder {activty} at position 0"
+        );
+    }
+
     #[test]
     fn test_compute_text_complex_format() {
         let state = WaybarState {
@@ -354,6 +799,19 @@ mod tests {
         assert_eq!(result, "Opus 4.5 | \u{f03eb} Edit | 15000K | $1.23");
     }
 
+    #[test]
+    fn test_compute_text_escapes_values_as_markup() {
+        let mut state = WaybarState::from_phase(&AgentPhase::ToolUse { tool: "Grep".to_string() }, None);
+        state.detail = "Vec<T>".to_string();
+        assert_eq!(state.compute_text("<b>{activity}</b> {detail}"), "<b>Grep</b> Vec&lt;T&gt;");
+        state.detail = "a && b".to_string();
+        // Cut to width before escaping, so no entity is split
+        assert_eq!(state.compute_text("{detail:.4}"), "a &amp;…");
+
+        let error = WaybarState::from_phase(&AgentPhase::Error { message: "a && b".to_string() }, None);
+        assert_eq!(error.text, "Error: a &amp;&amp; b");
+    }
+
     #[test]
     fn test_get_activity_icon_returns_correct_icons() {
         let test_cases = vec![
@@ -393,7 +851,7 @@ mod tests {
         };
 
         // Should not timeout if already idle
-        let result = state.check_activity_timeout();
+        let result = state.check_activity_timeout(60);
         assert_eq!(result, false);
         assert_eq!(state.activity, "Idle");
     }
@@ -407,7 +865,7 @@ mod tests {
         };
 
         // Should not timeout if no timestamp
-        let result = state.check_activity_timeout();
+        let result = state.check_activity_timeout(60);
         assert_eq!(result, false);
         assert_eq!(state.activity, "Thinking");
     }
@@ -428,7 +886,7 @@ mod tests {
         };
 
         // Should not timeout (within 60 seconds)
-        let result = state.check_activity_timeout();
+        let result = state.check_activity_timeout(60);
         assert_eq!(result, false);
         assert_eq!(state.activity, "Read");
         assert_eq!(state.class, "tool-active");
@@ -449,14 +907,117 @@ mod tests {
             ..Default::default()
         };
 
+        // A longer configured timeout keeps the activity
+        assert!(!state.check_activity_timeout(300));
+        assert_eq!(state.activity, "Edit");
+
         // Should timeout and reset to Idle
-        let result = state.check_activity_timeout();
+        let result = state.check_activity_timeout(60);
         assert_eq!(result, true);
         assert_eq!(state.activity, "Idle");
         assert_eq!(state.class, "idle");
         assert_eq!(state.alt, "idle");
     }
 
+    #[test]
+    fn test_error_times_out_at_error_until() {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let error = |last_activity_time: i64, error_until: i64| WaybarState {
+            activity: "Error: Bash: exit code 1".to_string(),
+            class: "error".to_string(),
+            last_activity_time,
+            error_until,
+            ..Default::default()
+        };
+
+        // error_secs longer than the activity timeout keeps the error
+        let mut state = error(now - 120, now + 60);
+        assert_eq!(state.timeout_at(60), Some(now + 60));
+        assert!(!state.check_activity_timeout(60));
+        assert_eq!(state.class, "error");
+
+        // A shorter one clears it before the activity timeout
+        let mut state = error(now - 10, now);
+        assert!(state.check_activity_timeout(60));
+        assert_eq!(state.activity, "Idle");
+    }
+
+    #[test]
+    fn test_awaiting_input_does_not_time_out() {
+        let mut state = WaybarState::from_phase(
+            &AgentPhase::AwaitingInput { reason: "Waiting for permission".to_string() },
+            None,
+        );
+        state.last_activity_time = 1; // long ago
+
+        assert!(state.is_awaiting_input());
+        assert!(!state.check_activity_timeout(60));
+        assert_eq!(state.activity, "Waiting for permission");
+        assert_eq!(state.get_activity_icon(), "\u{f009a}");
+    }
+
+    #[test]
+    fn test_permission_wait_ends_with_its_tool_or_the_turn() {
+        let mut state = WaybarState::default();
+        let apply = |state: &mut WaybarState, event: LlmEvent| {
+            state.apply_phase(&event.phase().unwrap(), &event, 1000, 30)
+        };
+        let tool_end = |tool: &str| LlmEvent::ToolEnd { tool: tool.to_string(), error: None };
+        let permission = || LlmEvent::Notification {
+            message: Some("Claude needs your permission to use mcp__github__create_issue".to_string()),
+        };
+
+        apply(&mut state, LlmEvent::ToolStart { tool: "mcp__github__create_issue".to_string(), input: None });
+        apply(&mut state, permission());
+        assert!(!apply(&mut state, tool_end("Read")));
+        assert!(!apply(&mut state, LlmEvent::SubagentStop));
+        assert!(state.is_awaiting_input());
+
+        // Approved, the tool finishes; the full name is kept, not the
+        // truncated activity
+        assert!(apply(&mut state, tool_end("mcp__github__create_issue")));
+        assert_eq!(state.activity, "Thinking");
+
+        apply(&mut state, permission());
+        assert!(apply(&mut state, LlmEvent::Stop { error: None }));
+        assert_eq!(state.activity, "Idle");
+    }
+
+    #[test]
+    fn test_error_is_counted_and_stays_shown() {
+        let mut state = WaybarState::default();
+        let tool_end = |error: Option<&str>| LlmEvent::ToolEnd {
+            tool: "Bash".to_string(),
+            error: error.map(str::to_string),
+        };
+        let apply = |state: &mut WaybarState, event: &LlmEvent, now: i64| {
+            state.apply_phase(&event.phase().unwrap(), event, now, 30)
+        };
+
+        assert!(apply(&mut state, &tool_end(Some("exit code 1")), 1000));
+        assert_eq!(state.activity, "Error: Bash: exit code 1");
+        assert_eq!(state.class, "error");
+        assert_eq!(state.get_activity_icon(), "\u{f0026}");
+
+        // The next tool call does not replace it until the error expires
+        let tool_start = LlmEvent::ToolStart { tool: "Read".to_string(), input: None };
+        assert!(!apply(&mut state, &tool_start, 1010));
+        assert_eq!(state.class, "error");
+        assert!(apply(&mut state, &tool_start, 1030));
+        assert_eq!(state.activity, "Read");
+
+        assert!(apply(&mut state, &tool_end(Some("exit code 2")), 1040));
+        assert!(apply(&mut state, &LlmEvent::Submit { prompt: None }, 1041));
+        assert_eq!(state.activity, "Thinking");
+
+        assert_eq!(state.error_count, 2);
+        assert_eq!(state.last_error, "Bash: exit code 2");
+        assert!(state.compute_tooltip().ends_with("\nErrors: 2 (last: Bash: exit code 2)"));
+    }
+
     #[test]
     fn test_compute_tooltip_with_all_data() {
         let state = WaybarState {
@@ -492,6 +1053,158 @@ mod tests {
         assert_eq!(tooltip, "Activity: Idle");
     }
 
+    #[test]
+    fn test_compute_tooltip_exact_layout() {
+        let state = WaybarState {
+            activity: "Read".to_string(),
+            cost: 0.5,
+            cache_write: 10,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            state.compute_tooltip(),
+            "Activity: Read\nCache: 0 read / 10 write\nCost: $0.5000"
+        );
+    }
+
+    #[test]
+    fn test_detail_shows_in_tooltip_until_phase_changes() {
+        let mut state = WaybarState::from_phase(&AgentPhase::ToolUse { tool: "Edit".to_string() }, None);
+        state.detail = "main.rs".to_string();
+
+        assert_eq!(state.compute_text("{activity} {detail}"), "Edit main.rs");
+        assert_eq!(state.compute_tooltip(), "Activity: Edit (main.rs)");
+
+        state.set_phase(&AgentPhase::Thinking);
+        assert_eq!(state.compute_tooltip(), "Activity: Thinking");
+    }
+
+    #[test]
+    fn test_tooltip_escapes_user_content() {
+        let state = WaybarState {
+            model: "Opus <4.5>".to_string(),
+            cwd: "/srv/R&D".to_string(),
+            ..Default::default()
+        };
+        let template = Template::parse("<b>{model}</b>\n<tt>{cwd}</tt>", PLACEHOLDERS).unwrap();
+
+        assert_eq!(
+            state.render(&template, &HashMap::new()),
+            "<b>Opus &lt;4.5&gt;</b>\n<tt>/srv/R&amp;D</tt>"
+        );
+    }
+
+    #[test]
+    fn test_set_context_percentage_and_classes() {
+        let thresholds = ContextConfig::default();
+        let mut state = WaybarState::default();
+
+        state.set_context(50_000, 200_000, &thresholds);
+        assert_eq!(state.percentage, 25);
+        assert!(state.classes.is_empty());
+        assert_eq!(state.compute_text("{context_pct}%"), "25%");
+
+        state.set_context(150_000, 200_000, &thresholds);
+        assert_eq!(state.percentage, 75);
+        assert_eq!(state.classes, vec!["context-warn"]);
+
+        // Classes are replaced, not accumulated
+        state.set_context(190_000, 200_000, &thresholds);
+        assert_eq!(state.classes, vec!["context-critical"]);
+
+        // A 1M window puts the same usage well below the thresholds
+        state.set_context(190_000, 1_000_000, &thresholds);
+        assert_eq!(state.percentage, 19);
+        assert!(state.classes.is_empty());
+
+        state.set_context(300_000, 200_000, &thresholds);
+        assert_eq!(state.percentage, 100);
+    }
+
+    #[test]
+    fn test_set_budget_classes_and_placeholders() {
+        let config = BudgetConfig {
+            daily: 10.0,
+            session: 5.0,
+            ..Default::default()
+        };
+        let mut state = WaybarState {
+            cost: 1.0,
+            classes: vec!["context-warn".to_string()],
+            ..Default::default()
+        };
+
+        state.set_spend(&Spend { today: 8.5, week: 8.5, month: 8.5 });
+        state.set_budget(&config);
+        assert_eq!(state.classes, vec!["context-warn", "budget-warn"]);
+        assert_eq!(state.compute_text("{budget} {budget_pct}% ${budget_remaining}"), "daily 85% $1.50");
+
+        state.cost = 6.0;
+        state.set_budget(&config);
+        assert_eq!(state.classes, vec!["context-warn", "budget-over"]);
+        assert_eq!(state.compute_text("{budget} {budget_pct}% ${budget_remaining}"), "session 120% $0.00");
+
+        state.set_budget(&BudgetConfig::default());
+        assert_eq!(state.classes, vec!["context-warn"]);
+        assert_eq!(state.compute_text("{?budget}shown{/budget}"), "");
+    }
+
+    #[test]
+    fn test_set_rates_placeholders() {
+        let mut state = WaybarState::default();
+        state.set_spend(&Spend { today: 3.0, week: 3.0, month: 3.0 });
+        state.set_rates(&Rates::default(), &Rates::default(), 1_760_605_200);
+        assert_eq!(state.projected_today_cost, 3.0);
+        assert_eq!(state.compute_text("{?cost_per_hour}shown{/cost_per_hour}"), "");
+
+        let session = Rates { cost_per_hour: 1.5, tokens_per_min: 1234.6 };
+        let all = Rates { cost_per_hour: 4.0, tokens_per_min: 5000.0 };
+        state.set_rates(&session, &all, 1_760_605_200);
+        assert_eq!(state.compute_text("${cost_per_hour}/h {tokens_per_min}/min"), "$1.50/h 1235/min");
+        assert!(state.projected_today_cost > 3.0 && state.projected_today_cost <= 3.0 + 4.0 * 24.0);
+    }
+
+    #[test]
+    fn test_set_block_placeholders() {
+        let mut state = WaybarState::default();
+        let block = Block {
+            start: 0,
+            first: 600,
+            last: 1800,
+            tokens: 10_000,
+        };
+
+        state.set_block(Some(block), 40_000, 1800);
+        assert_eq!(
+            state.compute_text("{block_tokens:human} {block_pct}% {block_reset} {block_limit_in}"),
+            "10k 25% 4h 30m 1h"
+        );
+
+        // No limit known: no share or projection
+        state.set_block(Some(block), 0, 1800);
+        assert_eq!(state.compute_text("{block_pct}/{block_reset}/{block_limit_in}"), "0/4h 30m/");
+
+        state.set_block(None, 40_000, 30_000);
+        assert_eq!(state.compute_text("{?block_tokens}shown{/block_tokens}"), "");
+    }
+
+    #[test]
+    fn test_waybar_json_lists_modifier_classes() {
+        let mut state = WaybarState {
+            class: "thinking".to_string(),
+            ..Default::default()
+        };
+        let json: serde_json::Value = serde_json::from_str(&state.to_waybar_json().unwrap()).unwrap();
+        assert_eq!(json["class"], "thinking");
+
+        state.set_context(95, 100, &ContextConfig::default());
+        let json: serde_json::Value = serde_json::from_str(&state.to_waybar_json().unwrap()).unwrap();
+        assert_eq!(json["class"], serde_json::json!(["thinking", "context-critical"]));
+        assert_eq!(json["percentage"], 95);
+        assert!(json.get("classes").is_none());
+    }
+
     #[test]
     fn test_from_phase_idle() {
         let state = WaybarState::from_phase(&AgentPhase::Idle, None);
@@ -545,6 +1258,7 @@ mod tests {
             cache_read: 2000,
             cache_write: 100,
             estimated_cost: 0.25,
+            turns: 2,
         };
 
         let state = WaybarState::from_phase(&AgentPhase::Thinking, Some(&usage));
@@ -0,0 +1,472 @@
+//! Format string templates.
+//!
+//! ```text
+//! {name}            value with its default formatting
+//! {name:spec}       [align][width][.precision][human]
+//!                   align: < left, > right, ^ center (numbers default right)
+//!                   .N: decimals for numbers, max display width for text
+//!                   human: 15651 -> 15.7k
+//! {?name}...{/name} section shown only if name is non-zero / non-empty
+//! {!name}...{/name} section shown only if name is zero / empty
+//! {{ and }}         literal braces
+//! ```
+//!
+//! Placeholders are checked against a known set when the template is parsed,
+//! so typos fail at startup rather than showing up in the bar.
+//!
+//! Templates rendered as Pango markup ([`Template::render_markup`]) keep the
+//! template's own text as markup and escape every substituted value.
+
+use thiserror::Error;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum TemplateError {
+    #[error("unknown placeholder {{{name}}} at position {pos}")]
+    UnknownPlaceholder { name: String, pos: usize },
+    #[error("invalid format spec {spec:?} for {{{name}}} at position {pos}")]
+    InvalidSpec { name: String, spec: String, pos: usize },
+    #[error("unclosed '{{' at position {pos} (use '{{{{' for a literal brace)")]
+    UnclosedBrace { pos: usize },
+    #[error("unmatched '}}' at position {pos} (use '}}}}' for a literal brace)")]
+    UnmatchedBrace { pos: usize },
+    #[error("section {{?{name}}} opened at position {pos} is never closed")]
+    UnclosedSection { name: String, pos: usize },
+    #[error("unexpected {{/{name}}} at position {pos}")]
+    UnexpectedClose { name: String, pos: usize },
+}
+
+/// A value supplied to a template
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Int(u64),
+    /// Floats carry the precision used when the placeholder gives none
+    Float { value: f64, precision: usize },
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Text(s) => !s.is_empty(),
+            Value::Int(n) => *n != 0,
+            Value::Float { value, .. } => *value != 0.0,
+        }
+    }
+}
+
+/// Supplies values for placeholders accepted at parse time
+pub trait Context {
+    fn value(&self, name: &str) -> Value;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Spec {
+    align: Option<Align>,
+    width: Option<usize>,
+    precision: Option<usize>,
+    human: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Literal(String),
+    Placeholder { name: String, spec: Spec },
+    Section { name: String, negated: bool, body: Vec<Node> },
+}
+
+/// A parsed format string
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    /// Parse `source`, accepting only placeholders listed in `known`
+    pub fn parse(source: &str, known: &[&str]) -> Result<Self, TemplateError> {
+        // Stack of open sections: (name, negated, start position, parent nodes)
+        let mut stack: Vec<(String, bool, usize, Vec<Node>)> = Vec::new();
+        let mut nodes = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.char_indices().peekable();
+
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '{' if chars.peek().map(|&(_, c)| c) == Some('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek().map(|&(_, c)| c) == Some('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => return Err(TemplateError::UnmatchedBrace { pos }),
+                '{' => {
+                    let end = source[pos..]
+                        .find('}')
+                        .map(|i| pos + i)
+                        .ok_or(TemplateError::UnclosedBrace { pos })?;
+                    let tag = &source[pos + 1..end];
+                    while chars.peek().is_some_and(|&(i, _)| i <= end) {
+                        chars.next();
+                    }
+
+                    if !literal.is_empty() {
+                        nodes.push(Node::Literal(std::mem::take(&mut literal)));
+                    }
+
+                    if let Some(name) = tag.strip_prefix('/') {
+                        match stack.pop() {
+                            Some((open, negated, _, parent)) if open == name => {
+                                let body = std::mem::replace(&mut nodes, parent);
+                                nodes.push(Node::Section { name: open, negated, body });
+                            }
+                            _ => {
+                                return Err(TemplateError::UnexpectedClose {
+                                    name: name.to_string(),
+                                    pos,
+                                })
+                            }
+                        }
+                    } else if let Some((negated, name)) = tag
+                        .strip_prefix('?')
+                        .map(|n| (false, n))
+                        .or_else(|| tag.strip_prefix('!').map(|n| (true, n)))
+                    {
+                        check_known(name, known, pos)?;
+                        stack.push((name.to_string(), negated, pos, std::mem::take(&mut nodes)));
+                    } else {
+                        let (name, spec) = match tag.split_once(':') {
+                            Some((name, spec)) => (name, Some(spec)),
+                            None => (tag, None),
+                        };
+                        check_known(name, known, pos)?;
+                        let spec = match spec {
+                            Some(spec) => parse_spec(spec).ok_or_else(|| TemplateError::InvalidSpec {
+                                name: name.to_string(),
+                                spec: spec.to_string(),
+                                pos,
+                            })?,
+                            None => Spec::default(),
+                        };
+                        nodes.push(Node::Placeholder { name: name.to_string(), spec });
+                    }
+                }
+                c => literal.push(c),
+            }
+        }
+
+        if let Some((name, _, pos, _)) = stack.pop() {
+            return Err(TemplateError::UnclosedSection { name, pos });
+        }
+        if !literal.is_empty() {
+            nodes.push(Node::Literal(literal));
+        }
+
+        Ok(Self { nodes })
+    }
+
+    pub fn render(&self, context: &impl Context) -> String {
+        let mut out = String::new();
+        render_nodes(&self.nodes, context, false, &mut out);
+        out
+    }
+
+    /// Render as Pango markup: values are escaped, template text is not
+    pub fn render_markup(&self, context: &impl Context) -> String {
+        let mut out = String::new();
+        render_nodes(&self.nodes, context, true, &mut out);
+        out
+    }
+}
+
+/// Escape text for inclusion in Pango markup
+pub fn escape_markup(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\'' => out.push_str("&#39;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn check_known(name: &str, known: &[&str], pos: usize) -> Result<(), TemplateError> {
+    if known.contains(&name) {
+        Ok(())
+    } else {
+        Err(TemplateError::UnknownPlaceholder { name: name.to_string(), pos })
+    }
+}
+
+fn parse_spec(spec: &str) -> Option<Spec> {
+    let mut result = Spec::default();
+    let mut rest = spec;
+
+    if let Some(rest_after) = rest.strip_suffix("human") {
+        result.human = true;
+        rest = rest_after;
+    }
+
+    result.align = match rest.chars().next() {
+        Some('<') => Some(Align::Left),
+        Some('>') => Some(Align::Right),
+        Some('^') => Some(Align::Center),
+        _ => None,
+    };
+    if result.align.is_some() {
+        rest = &rest[1..];
+    }
+
+    let (width, precision) = match rest.split_once('.') {
+        Some((width, precision)) => (width, Some(precision)),
+        None => (rest, None),
+    };
+    if !width.is_empty() {
+        result.width = Some(width.parse().ok()?);
+    }
+    if let Some(precision) = precision {
+        result.precision = Some(precision.parse().ok()?);
+    }
+
+    Some(result)
+}
+
+fn render_nodes(nodes: &[Node], context: &impl Context, markup: bool, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Literal(text) => out.push_str(text),
+            Node::Placeholder { name, spec } => {
+                let value = format_value(&context.value(name), spec);
+                if markup {
+                    out.push_str(&escape_markup(&value));
+                } else {
+                    out.push_str(&value);
+                }
+            }
+            Node::Section { name, negated, body } => {
+                if context.value(name).is_truthy() != *negated {
+                    render_nodes(body, context, markup, out);
+                }
+            }
+        }
+    }
+}
+
+fn format_value(value: &Value, spec: &Spec) -> String {
+    let (text, numeric) = match value {
+        Value::Text(s) => (truncate(s, spec.precision), false),
+        Value::Int(n) if spec.human => (humanize(*n as f64, spec.precision), true),
+        Value::Int(n) => (n.to_string(), true),
+        Value::Float { value, .. } if spec.human => (humanize(*value, spec.precision), true),
+        Value::Float { value, precision } => {
+            (format!("{:.prec$}", value, prec = spec.precision.unwrap_or(*precision)), true)
+        }
+    };
+
+    let align = spec
+        .align
+        .unwrap_or(if numeric { Align::Right } else { Align::Left });
+    pad(text, spec.width, align)
+}
+
+/// 950 -> "950", 15651 -> "15.7k", 2_400_000 -> "2.4M"
+pub fn humanize(value: f64, precision: Option<usize>) -> String {
+    const UNITS: [(f64, &str); 3] = [(1e9, "B"), (1e6, "M"), (1e3, "k")];
+
+    let Some(&(scale, unit)) = UNITS.iter().find(|(scale, _)| value.abs() >= *scale) else {
+        return match precision {
+            Some(p) if value.fract() != 0.0 => format!("{:.p$}", value),
+            _ => format!("{}", value.round()),
+        };
+    };
+
+    let scaled = format!("{:.prec$}", value / scale, prec = precision.unwrap_or(1));
+    // Without an explicit precision, "15.0k" reads better as "15k"
+    let scaled = match precision {
+        None if scaled.contains('.') => scaled.trim_end_matches('0').trim_end_matches('.').to_string(),
+        _ => scaled,
+    };
+    format!("{}{}", scaled, unit)
+}
+
+/// Cut `text` to at most `max` display columns, marking the cut with '…'
+pub fn truncate(text: &str, max: Option<usize>) -> String {
+    let Some(max) = max else {
+        return text.to_string();
+    };
+    if text.width() <= max {
+        return text.to_string();
+    }
+    if max == 0 {
+        return String::new();
+    }
+
+    let mut out = String::new();
+    let mut width = 0;
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        if width + w > max - 1 {
+            break;
+        }
+        width += w;
+        out.push(c);
+    }
+    out.push('…');
+    out
+}
+
+fn pad(text: String, width: Option<usize>, align: Align) -> String {
+    let Some(width) = width else {
+        return text;
+    };
+    let fill = width.saturating_sub(text.width());
+    if fill == 0 {
+        return text;
+    }
+    let (left, right) = match align {
+        Align::Left => (0, fill),
+        Align::Right => (fill, 0),
+        Align::Center => (fill / 2, fill - fill / 2),
+    };
+    format!("{}{}{}", " ".repeat(left), text, " ".repeat(right))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Vars;
+
+    impl Context for Vars {
+        fn value(&self, name: &str) -> Value {
+            match name {
+                "model" => Value::Text("Opus 4.5".to_string()),
+                "cwd" => Value::Text("日本語のプロジェクト".to_string()),
+                "empty" => Value::Text(String::new()),
+                "tokens" => Value::Int(15651),
+                "zero" => Value::Int(0),
+                "cost" => Value::Float { value: 2.51609, precision: 4 },
+                _ => Value::Text(String::new()),
+            }
+        }
+    }
+
+    const KNOWN: &[&str] = &["model", "cwd", "empty", "tokens", "zero", "cost"];
+
+    fn render(source: &str) -> String {
+        Template::parse(source, KNOWN).unwrap().render(&Vars)
+    }
+
+    #[test]
+    fn test_placeholders_and_precision() {
+        assert_eq!(render("{model} | ${cost:.2}"), "Opus 4.5 | $2.52");
+        assert_eq!(render("{cost}"), "2.5161");
+        assert_eq!(render("{cost:.0}"), "3");
+        assert_eq!(render("{tokens}"), "15651");
+    }
+
+    #[test]
+    fn test_human_numbers() {
+        assert_eq!(render("{tokens:human}"), "15.7k");
+        assert_eq!(render("{tokens:.2human}"), "15.65k");
+        assert_eq!(humanize(950.0, None), "950");
+        assert_eq!(humanize(15000.0, None), "15k");
+        assert_eq!(humanize(2_400_000.0, None), "2.4M");
+        assert_eq!(humanize(3_000_000_000.0, None), "3B");
+    }
+
+    #[test]
+    fn test_width_and_alignment() {
+        assert_eq!(render("[{model:<10}]"), "[Opus 4.5  ]");
+        assert_eq!(render("[{model:>10}]"), "[  Opus 4.5]");
+        assert_eq!(render("[{model:^12}]"), "[  Opus 4.5  ]");
+        // Numbers align right by default
+        assert_eq!(render("[{tokens:8}]"), "[   15651]");
+        assert_eq!(render("[{tokens:<8human}]"), "[15.7k   ]");
+    }
+
+    #[test]
+    fn test_truncation_counts_display_width() {
+        assert_eq!(render("{model:.6}"), "Opus …");
+        assert_eq!(render("{model:.20}"), "Opus 4.5");
+        // Each CJK character is two columns wide
+        assert_eq!(render("{cwd:.7}"), "日本語…");
+        assert_eq!(render("[{cwd:<10.7}]"), "[日本語…   ]");
+    }
+
+    #[test]
+    fn test_conditional_sections() {
+        assert_eq!(render("{model}{?cost} | ${cost:.2}{/cost}"), "Opus 4.5 | $2.52");
+        assert_eq!(render("{model}{?zero} | {zero}{/zero}"), "Opus 4.5");
+        assert_eq!(render("{!empty}no cwd{/empty}"), "no cwd");
+        assert_eq!(render("{?tokens}{?zero}x{/zero}y{/tokens}"), "y");
+    }
+
+    #[test]
+    fn test_brace_escapes() {
+        assert_eq!(render("{{model}} = {model}"), "{model} = Opus 4.5");
+        assert_eq!(render("}}"), "}");
+    }
+
+    #[test]
+    fn test_markup_escapes_values_but_not_template() {
+        struct Cwd;
+        impl Context for Cwd {
+            fn value(&self, _name: &str) -> Value {
+                Value::Text("~/R&D/<draft>".to_string())
+            }
+        }
+
+        let template = Template::parse("<b>Dir:</b> {cwd:<16}|", &["cwd"]).unwrap();
+        assert_eq!(
+            template.render_markup(&Cwd),
+            "<b>Dir:</b> ~/R&amp;D/&lt;draft&gt;   |"
+        );
+        assert_eq!(template.render(&Cwd), "<b>Dir:</b> ~/R&D/<draft>   |");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Template::parse("{activity} {modle}", &["activity"]),
+            Err(TemplateError::UnknownPlaceholder { name: "modle".to_string(), pos: 11 })
+        );
+        assert!(matches!(
+            Template::parse("{?modle}x{/modle}", KNOWN),
+            Err(TemplateError::UnknownPlaceholder { .. })
+        ));
+        assert!(matches!(
+            Template::parse("{cost:.x}", KNOWN),
+            Err(TemplateError::InvalidSpec { .. })
+        ));
+        assert!(matches!(
+            Template::parse("{model", KNOWN),
+            Err(TemplateError::UnclosedBrace { pos: 0 })
+        ));
+        assert!(matches!(
+            Template::parse("model}", KNOWN),
+            Err(TemplateError::UnmatchedBrace { pos: 5 })
+        ));
+        assert!(matches!(
+            Template::parse("{?cost}x", KNOWN),
+            Err(TemplateError::UnclosedSection { .. })
+        ));
+        assert!(matches!(
+            Template::parse("{?cost}x{/model}", KNOWN),
+            Err(TemplateError::UnexpectedClose { .. })
+        ));
+    }
+}
@@ -1,26 +1,62 @@
-use std::os::unix::net::UnixDatagram;
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::fs;
+use std::path::Path;
 
-use llm_bridge_core::{WaybarState, AgentPhase, socket::DaemonMessage};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixDatagram};
 
-const DEBOUNCE_MS: u64 = 16;
-const MAX_DEBOUNCE_MS: u64 = 50;
-const DISK_FLUSH_MS: u64 = 100;
+use llm_bridge_claude::block::BlockTracker;
+use llm_bridge_claude::statusline::StatuslinePayload;
+use llm_bridge_claude::tool_summary::summarize_tool_input;
+use llm_bridge_claude::ClaudeProvider;
+use llm_bridge_core::{Config, LlmEvent, LlmProvider, Template, TemplateError, WaybarState, aggregate};
+use llm_bridge_core::block::{self, Block};
+use llm_bridge_core::budget::Spent;
+use llm_bridge_core::ledger::Ledger;
+use llm_bridge_core::metrics::{Format, Metrics};
+use llm_bridge_core::rate::{BurnRate, Rates};
+use llm_bridge_core::state::session_path;
+use llm_bridge_core::signal::{ProcessMatcher, signal_pid};
+use llm_bridge_core::socket::{DaemonMessage, EventMessage, send_to_subscriber};
+
+/// How long found bar PIDs are trusted before `/proc` is scanned again for new bars
+const PID_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// How often transcripts are rescanned for usage blocks; a scan reads every
+/// project directory, so it stays off the per-event path
+const BLOCK_REFRESH: Duration = Duration::from_secs(30);
 
 pub struct Daemon {
-    socket_path: PathBuf,
-    state_path: PathBuf,
-    sessions_dir: PathBuf,
-    signal_num: u8,
-    format: String,
+    config: Config,
+    template: Template,
+    tooltip_template: Template,
+
+    // In-memory state per session id; events without one use ""
+    sessions: HashMap<String, WaybarState>,
+    last_session: Option<String>,
+    dirty_sessions: HashSet<String>,
+
+    // Durable cost history and the transcript usage recorded in it
+    ledger: Ledger,
+    provider: ClaudeProvider,
+    // Cost and token growth per session over the rate window
+    rates: HashMap<String, BurnRate>,
+    // Usage blocks across every transcript, not only tracked sessions
+    block_tracker: BlockTracker,
+    blocks: Vec<Block>,
+    blocks_updated: Instant,
+    // Counters for the OpenMetrics export
+    metrics: Metrics,
 
-    // In-memory state
-    state: WaybarState,
+    // `watch` processes fed with every output change, by abstract socket name
+    subscribers: Vec<String>,
+    publisher: Option<std::os::unix::net::UnixDatagram>,
+    last_output: Option<String>,
 
     // Waybar PID cache
-    waybar_pid: Option<i32>,
+    matcher: ProcessMatcher,
+    waybar_pids: Vec<i32>,
     pid_cache_time: Instant,
 
     // Debouncing
@@ -34,40 +70,90 @@ pub struct Daemon {
 }
 
 impl Daemon {
-    pub fn new(
-        socket_path: PathBuf,
-        state_path: PathBuf,
-        sessions_dir: PathBuf,
-        signal_num: u8,
-        format: String,
-    ) -> Self {
-        // Load existing state if available
-        let state = WaybarState::read_from(&state_path).unwrap_or_default();
-
-        Self {
-            socket_path,
-            state_path,
-            sessions_dir,
-            signal_num,
-            format,
-            state,
-            waybar_pid: None,
+    pub fn new(config: Config) -> Result<Self, TemplateError> {
+        let template = config.text_template()?;
+        let tooltip_template = config.tooltip_template()?;
+        let matcher = ProcessMatcher::new(&config.waybar_processes);
+        let ledger = Ledger::open(&config.ledger_path).unwrap_or_else(|e| {
+            eprintln!("Warning: not reading {}: {}", config.ledger_path.display(), e);
+            Ledger::new(&config.ledger_path)
+        });
+        let provider = crate::claude_provider(&config);
+        let block_tracker = BlockTracker::new(&config.transcript_dir);
+
+        let mut daemon = Self {
+            config,
+            template,
+            tooltip_template,
+            sessions: HashMap::new(),
+            last_session: None,
+            dirty_sessions: HashSet::new(),
+            ledger,
+            provider,
+            rates: HashMap::new(),
+            block_tracker,
+            blocks: Vec::new(),
+            blocks_updated: Instant::now(),
+            metrics: Metrics::default(),
+            subscribers: Vec::new(),
+            publisher: None,
+            last_output: None,
+            matcher,
+            waybar_pids: Vec::new(),
             pid_cache_time: Instant::now(),
             pending_signal: false,
             first_event_time: None,
             last_event_time: Instant::now(),
             dirty: false,
             last_disk_write: Instant::now(),
+        };
+        daemon.update_blocks();
+        daemon.restore_sessions();
+        Ok(daemon)
+    }
+
+    /// Pick up sessions written by a previous daemon run
+    fn restore_sessions(&mut self) {
+        let Ok(entries) = fs::read_dir(&self.config.sessions_dir) else {
+            return;
+        };
+
+        for path in entries.flatten().map(|e| e.path()) {
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            let Ok(mut state) = WaybarState::read_from(&path) else {
+                continue;
+            };
+            if state.session_id.is_empty() || self.is_stale(&state) {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            if state.check_activity_timeout(self.config.timeouts.activity_secs) {
+                self.dirty_sessions.insert(state.session_id.clone());
+            }
+            self.render_session(&mut state);
+            self.sessions.insert(state.session_id.clone(), state);
         }
+
+        // Write the combined view even if nothing was restored
+        self.dirty = true;
     }
 
     pub fn handle_message(&mut self, msg: DaemonMessage) {
         match msg {
-            DaemonMessage::Event { event_type, tool } => {
-                self.handle_event(&event_type, tool);
+            DaemonMessage::Event(event) => {
+                let sid = self.route(event.session_id.clone());
+                self.handle_event(&sid, event);
+            }
+            DaemonMessage::Status { session_id, payload } => {
+                let status = StatuslinePayload::from_json(&payload);
+                let sid = self.route(session_id.or_else(|| status.session_id.clone()));
+                self.handle_status(&sid, &status);
             }
-            DaemonMessage::Status { payload } => {
-                self.handle_status(&payload);
+            DaemonMessage::Subscribe { name } => {
+                self.subscribe(name);
+                return;
             }
         }
 
@@ -79,235 +165,458 @@ impl Daemon {
         self.dirty = true;
     }
 
-    fn handle_event(&mut self, event_type: &str, tool: Option<String>) {
-        let phase = match event_type {
-            "submit" => AgentPhase::Thinking,
-            "tool-start" => AgentPhase::ToolUse {
-                tool: tool.unwrap_or_else(|| "unknown".to_string()),
-            },
-            "tool-end" => AgentPhase::Thinking,
-            "stop" => AgentPhase::Idle,
-            _ => return,
+    /// Session a message belongs to: its own id, else the session last heard from
+    fn route(&mut self, session_id: Option<String>) -> String {
+        let sid = session_id
+            .filter(|s| !s.is_empty())
+            .or_else(|| self.last_session.clone())
+            .unwrap_or_default();
+        self.last_session = Some(sid.clone()).filter(|s| !s.is_empty());
+        self.dirty_sessions.insert(sid.clone());
+        sid
+    }
+
+    fn session_mut(&mut self, sid: &str) -> &mut WaybarState {
+        self.sessions.entry(sid.to_string()).or_insert_with(|| WaybarState {
+            session_id: sid.to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn handle_event(&mut self, sid: &str, event: EventMessage) {
+        let Some(llm_event) = event.llm_event() else {
+            return;
+        };
+        self.metrics
+            .record_event(sid, &llm_event, event.time_ms.unwrap_or_else(unix_millis));
+        let Some(phase) = llm_event.phase() else {
+            self.end_session(sid);
+            return;
         };
 
-        let (activity, class, alt) = match &phase {
-            AgentPhase::Idle => ("Idle".to_string(), "idle".to_string(), "idle".to_string()),
-            AgentPhase::Thinking => ("Thinking".to_string(), "thinking".to_string(), "active".to_string()),
-            AgentPhase::ToolUse { tool } => {
-                // Use char count for UTF-8 safety (avoid slicing mid-character)
-                let truncated = if tool.chars().count() > 20 {
-                    let s: String = tool.chars().take(17).collect();
-                    format!("{}...", s)
-                } else {
-                    tool.clone()
-                };
-                (truncated, "tool-active".to_string(), "active".to_string())
-            }
-            AgentPhase::Error { message } => {
-                (format!("Error: {}", message), "error".to_string(), "error".to_string())
+        // Prefer the hook's own timestamp; datagrams can sit in the queue
+        let now = event.time_ms.map(|ms| ms / 1000).unwrap_or_else(unix_now);
+        let mut state = std::mem::take(self.session_mut(sid));
+        let shown = state.apply_phase(&phase, &llm_event, now, self.config.timeouts.error_secs);
+        if let (true, LlmEvent::ToolStart { tool, input: Some(input) }) = (shown, &llm_event) {
+            state.detail = summarize_tool_input(tool, input).unwrap_or_default();
+        }
+        state.last_activity_time = now;
+        if state.cwd.is_empty() {
+            if let Some(cwd) = event.cwd {
+                state.cwd = cwd;
             }
-        };
+        }
+        self.render_session(&mut state);
+        self.sessions.insert(sid.to_string(), state);
+    }
 
-        self.state.activity = activity;
-        self.state.class = class;
-        self.state.alt = alt;
-        self.state.last_activity_time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or(Duration::ZERO)
-            .as_secs() as i64;
-        self.state.text = self.state.compute_text(&self.format);
+    /// Forget a session that has ended and remove its file
+    fn end_session(&mut self, sid: &str) {
+        self.sessions.remove(sid);
+        self.dirty_sessions.remove(sid);
+        self.rates.remove(sid);
+        self.metrics.end_session(sid);
+        if !sid.is_empty() {
+            let _ = fs::remove_file(session_path(&self.config.sessions_dir, sid));
+        }
+        if self.last_session.as_deref() == Some(sid) {
+            self.last_session = None;
+        }
     }
 
-    fn handle_status(&mut self, payload: &str) {
-        // Parse the status payload
-        #[derive(serde::Deserialize)]
-        struct StatusPayload {
-            session_id: Option<String>,
-            cwd: Option<String>,
-            model: Option<ModelInfo>,
-            cost: Option<CostInfo>,
-            context_window: Option<ContextWindow>,
+    fn handle_status(&mut self, sid: &str, status: &StatuslinePayload) {
+        let mut state = std::mem::take(self.session_mut(sid));
+        let before = Spent::new(state.cost, &self.ledger.spend(unix_now()));
+        status.apply_to(&mut state, &self.config.context);
+        if state.last_activity_time == 0 {
+            // Count a statusline-only session as seen, so it can go stale
+            state.last_activity_time = unix_now();
         }
 
-        #[derive(serde::Deserialize)]
-        struct ModelInfo {
-            id: Option<String>,
-            display_name: Option<String>,
+        let usage = status
+            .transcript_path
+            .as_deref()
+            .and_then(|path| self.provider.parse_usage(Path::new(path)).ok());
+        let entry = state.ledger_entry(unix_now(), usage.as_ref());
+        if crate::snapshot_due(&self.ledger, &entry) {
+            if let Err(e) = self.ledger.record(entry) {
+                eprintln!("Failed to write {}: {}", self.ledger.path().display(), e);
+            }
+        }
+        let after = Spent::new(state.cost, &self.ledger.spend(unix_now()));
+        crate::alert_budgets(&self.config, &before, &after);
+        let tokens = usage.map_or(0, |u| u.input_tokens + u.output_tokens);
+        let window = self.config.rate.window_secs;
+        self.rates
+            .entry(sid.to_string())
+            .or_insert_with(|| BurnRate::new(window))
+            .record(unix_now(), state.cost, tokens);
+        self.render_session(&mut state);
+        self.sessions.insert(sid.to_string(), state);
+    }
+
+    /// Read new transcript lines into the usage blocks. Without a
+    /// transcript directory there are no blocks to show.
+    fn update_blocks(&mut self) {
+        if self.block_tracker.update(unix_now()).is_ok() {
+            self.blocks = self.block_tracker.blocks();
         }
+        self.blocks_updated = Instant::now();
+    }
 
-        #[derive(serde::Deserialize)]
-        struct CostInfo {
-            total_cost_usd: Option<f64>,
+    fn render_session(&self, state: &mut WaybarState) {
+        let now = unix_now();
+        state.set_spend(&self.ledger.spend(now));
+        state.set_budget(&self.config.budget);
+        let session = self.rates.get(&state.session_id).map(|rate| rate.rates(now)).unwrap_or_default();
+        let mut all = Rates::default();
+        for rate in self.rates.values() {
+            all.add(&rate.rates(now));
         }
+        state.set_rates(&session, &all, now);
+        let limit = match self.config.block.token_limit {
+            0 => block::largest(&self.blocks, now),
+            limit => limit,
+        };
+        state.set_block(block::current(&self.blocks, now), limit, now);
+        state.text = state.render(&self.template, &self.config.icons);
+        state.tooltip = state.render(&self.tooltip_template, &self.config.icons);
+    }
 
-        #[derive(serde::Deserialize)]
-        struct ContextWindow {
-            current_usage: Option<CurrentUsage>,
+    /// The bar view across all sessions, rendered with the configured templates
+    pub fn combined_state(&self) -> WaybarState {
+        if self.sessions.is_empty() {
+            let mut state = WaybarState::default();
+            self.render_session(&mut state);
+            return state;
         }
+        let sessions: Vec<WaybarState> = self.sessions.values().cloned().collect();
+        let mut state = aggregate::combine(&sessions, &self.config.icons);
+        state.text = state.render(&self.template, &self.config.icons);
+        state.tooltip = state.render(&self.tooltip_template, &self.config.icons);
+        state
+    }
 
-        #[derive(serde::Deserialize)]
-        struct CurrentUsage {
-            input_tokens: Option<u64>,
-            output_tokens: Option<u64>,
-            cache_creation_input_tokens: Option<u64>,
-            cache_read_input_tokens: Option<u64>,
+    /// Seconds without events after which `state` is dropped. Sessions
+    /// waiting for the user are kept longer, but not forever: one closed at
+    /// a permission prompt never sends `session-end`.
+    fn stale_secs(&self, state: &WaybarState) -> i64 {
+        if state.is_awaiting_input() {
+            self.config.timeouts.awaiting_input_secs as i64
+        } else {
+            self.config.timeouts.stale_session_secs as i64
         }
+    }
 
-        if let Ok(status) = serde_json::from_str::<StatusPayload>(payload) {
-            if let Some(model) = status.model {
-                self.state.model = model.display_name
-                    .or(model.id)
-                    .unwrap_or_else(|| "Claude".to_string());
-            }
+    fn is_stale(&self, state: &WaybarState) -> bool {
+        state.last_activity_time > 0 && unix_now() - state.last_activity_time > self.stale_secs(state)
+    }
 
-            if let Some(cost) = status.cost {
-                self.state.cost = cost.total_cost_usd.unwrap_or(0.0);
-            }
+    /// When the next session activity times out or goes stale
+    fn sweep_deadline(&self) -> Option<Instant> {
+        let activity = self.config.timeouts.activity_secs;
 
-            if let Some(sid) = status.session_id {
-                self.state.session_id = sid;
-            }
+        // The stale timeout compares whole seconds with `>`, so it fires a second after the limit
+        let due_secs = self
+            .sessions
+            .values()
+            .filter(|state| state.last_activity_time > 0)
+            .flat_map(|state| {
+                [state.timeout_at(activity), Some(state.last_activity_time + self.stale_secs(state) + 1)]
+            })
+            .flatten()
+            .min()?;
 
-            if let Some(cwd) = status.cwd {
-                self.state.cwd = cwd;
-            }
+        let wait_ms = (due_secs * 1000 - unix_millis()).max(0);
+        Some(Instant::now() + Duration::from_millis(wait_ms as u64))
+    }
 
-            if let Some(cw) = status.context_window {
-                if let Some(usage) = cw.current_usage {
-                    self.state.input_tokens = usage.input_tokens.unwrap_or(0);
-                    self.state.output_tokens = usage.output_tokens.unwrap_or(0);
-                    self.state.cache_read = usage.cache_read_input_tokens.unwrap_or(0);
-                    self.state.cache_write = usage.cache_creation_input_tokens.unwrap_or(0);
-                }
+    /// Check if a session activity or stale timeout has passed
+    pub fn should_sweep(&self) -> bool {
+        self.sweep_deadline().is_some_and(|d| d <= Instant::now())
+    }
+
+    /// Reset timed-out activities to Idle and drop stale sessions
+    pub fn do_sweep(&mut self) {
+        let stale: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|(_, state)| self.is_stale(state))
+            .map(|(sid, _)| sid.clone())
+            .collect();
+        for sid in &stale {
+            self.end_session(sid);
+        }
+
+        let mut timed_out = Vec::new();
+        for (sid, state) in &mut self.sessions {
+            if state.check_activity_timeout(self.config.timeouts.activity_secs) {
+                timed_out.push(sid.clone());
+            }
+        }
+        for sid in &timed_out {
+            if let Some(mut state) = self.sessions.remove(sid) {
+                self.render_session(&mut state);
+                self.sessions.insert(sid.clone(), state);
             }
+            self.dirty_sessions.insert(sid.clone());
+        }
 
-            self.state.text = self.state.compute_text(&self.format);
-            self.state.tooltip = self.state.compute_tooltip();
+        if !stale.is_empty() || !timed_out.is_empty() {
+            self.dirty = true;
+            self.pending_signal = true;
+            self.first_event_time.get_or_insert_with(Instant::now);
         }
     }
 
-    /// Check if we should signal waybar (debounce logic)
-    pub fn should_signal(&self) -> bool {
+    /// When the pending waybar signal is due (debounce logic)
+    fn signal_deadline(&self) -> Option<Instant> {
         if !self.pending_signal {
-            return false;
+            return None;
         }
 
-        let now = Instant::now();
-        let since_last = now.duration_since(self.last_event_time);
-        let since_first = self.first_event_time
-            .map(|t| now.duration_since(t))
-            .unwrap_or(Duration::ZERO);
+        // Signal once the debounce window passes OR the max delay is reached
+        let quiet = self.last_event_time + Duration::from_millis(self.config.daemon.debounce_ms);
+        let capped = self
+            .first_event_time
+            .map(|t| t + Duration::from_millis(self.config.daemon.max_debounce_ms));
+        Some(capped.map_or(quiet, |c| c.min(quiet)))
+    }
 
-        // Signal if: debounce window passed OR max delay reached
-        since_last >= Duration::from_millis(DEBOUNCE_MS)
-            || since_first >= Duration::from_millis(MAX_DEBOUNCE_MS)
+    /// Check if we should signal waybar
+    pub fn should_signal(&self) -> bool {
+        self.signal_deadline().is_some_and(|d| d <= Instant::now())
     }
 
-    /// Signal waybar and reset debounce state
+    /// Push the new output to subscribers and signal any bars that poll the
+    /// state file, and reset debounce state
     pub fn do_signal(&mut self) {
-        if let Some(pid) = self.waybar_pid {
-            // Try cached PID first
-            if self.signal_pid(pid).is_err() {
-                // PID stale, refresh
-                self.refresh_waybar_pid();
-                if let Some(new_pid) = self.waybar_pid {
-                    let _ = self.signal_pid(new_pid);
-                }
-            }
-        } else {
-            self.refresh_waybar_pid();
-            if let Some(pid) = self.waybar_pid {
-                let _ = self.signal_pid(pid);
-            }
+        if let Some(first) = self.first_event_time {
+            self.metrics.record_signal(first.elapsed());
         }
+        // A `watch` module and a signal-based one may run side by side
+        self.publish();
+        self.signal_waybar();
 
         self.pending_signal = false;
         self.first_event_time = None;
     }
 
-    fn signal_pid(&self, pid: i32) -> Result<(), nix::errno::Errno> {
-        use nix::sys::signal::{self, Signal};
-        use nix::unistd::Pid;
-        use nix::libc;
+    /// The line Waybar shows for the combined state
+    fn bar_output(&self) -> Option<String> {
+        self.combined_state().to_waybar_json().ok()
+    }
 
-        let sig = Signal::try_from(libc::SIGRTMIN() + self.signal_num as i32)
-            .map_err(|_| nix::errno::Errno::EINVAL)?;
-        signal::kill(Pid::from_raw(pid), sig)
+    /// Add a `watch` process and send it the current output right away
+    fn subscribe(&mut self, name: String) {
+        if self.subscribers.contains(&name) {
+            return; // Periodic renewal
+        }
+        let Some(output) = self.bar_output() else {
+            return;
+        };
+        if self.send_to(&name, &output) {
+            self.subscribers.push(name);
+        }
     }
 
-    fn refresh_waybar_pid(&mut self) {
-        use std::process::Command;
+    /// Send the output to every subscriber if it changed, dropping those that are gone
+    fn publish(&mut self) {
+        let Some(output) = self.bar_output() else {
+            return;
+        };
+        if self.last_output.as_ref() == Some(&output) {
+            return;
+        }
 
-        if let Ok(output) = Command::new("pgrep").arg("-x").arg("waybar").output() {
-            if output.status.success() {
-                if let Ok(s) = String::from_utf8(output.stdout) {
-                    if let Some(pid) = s.lines().next().and_then(|l| l.trim().parse().ok()) {
-                        self.waybar_pid = Some(pid);
-                        self.pid_cache_time = Instant::now();
-                        return;
-                    }
-                }
-            }
+        let subscribers = std::mem::take(&mut self.subscribers);
+        self.subscribers = subscribers
+            .into_iter()
+            .filter(|name| self.send_to(name, &output))
+            .collect();
+        self.last_output = Some(output);
+    }
+
+    /// Returns false once the subscriber is gone
+    fn send_to(&mut self, name: &str, output: &str) -> bool {
+        if self.publisher.is_none() {
+            let Ok(socket) = std::os::unix::net::UnixDatagram::unbound() else {
+                return false;
+            };
+            let _ = socket.set_nonblocking(true);
+            self.publisher = Some(socket);
+        }
+        let Some(publisher) = &self.publisher else {
+            return false;
+        };
+
+        match send_to_subscriber(publisher, name, output) {
+            Ok(()) => true,
+            // Its queue is full; keep it and let it catch up on the next change
+            Err(e) => e.kind() == std::io::ErrorKind::WouldBlock,
+        }
+    }
+
+    /// Signal every cached bar, rescanning `/proc` when the cache is empty or old
+    fn signal_waybar(&mut self) {
+        // Drop PIDs that exited or now belong to another program
+        let matcher = &self.matcher;
+        self.waybar_pids.retain(|&pid| matcher.matches_pid(pid));
+
+        if self.waybar_pids.is_empty() || self.pid_cache_time.elapsed() >= PID_CACHE_TTL {
+            self.waybar_pids = self.matcher.find_pids();
+            self.pid_cache_time = Instant::now();
         }
-        self.waybar_pid = None;
+
+        let signal_num = self.config.signal;
+        self.waybar_pids.retain(|&pid| signal_pid(pid, signal_num).is_ok());
+    }
+
+    /// When the next disk flush is due, if anything changed
+    fn flush_deadline(&self) -> Option<Instant> {
+        self.dirty
+            .then(|| self.last_disk_write + Duration::from_millis(self.config.daemon.disk_flush_ms))
     }
 
     /// Check if we should flush to disk
     pub fn should_flush(&self) -> bool {
-        self.dirty && self.last_disk_write.elapsed() >= Duration::from_millis(DISK_FLUSH_MS)
+        self.flush_deadline().is_some_and(|d| d <= Instant::now())
     }
 
-    /// Flush state to disk
+    /// Flush changed sessions and the combined view to disk
     pub fn do_flush(&mut self) {
-        let _ = self.state.write_session_file(&self.sessions_dir);
-        let _ = self.state.write_atomic(&self.state_path);
+        for sid in self.dirty_sessions.drain() {
+            if let Some(state) = self.sessions.get(&sid) {
+                // Anonymous sessions have no file; write_session_file skips them
+                let _ = state.write_session_file(&self.config.sessions_dir);
+            }
+        }
+        let _ = self.combined_state().write_atomic(&self.config.state_path);
+        if let Some(path) = &self.config.metrics.textfile_path {
+            if let Err(e) = write_atomic(path, &self.metrics_text(Format::Prometheus)) {
+                eprintln!("Failed to write {}: {}", path.display(), e);
+            }
+        }
         self.dirty = false;
         self.last_disk_write = Instant::now();
     }
 
+    /// When the usage blocks are next rescanned
+    fn blocks_deadline(&self) -> Instant {
+        self.blocks_updated + BLOCK_REFRESH
+    }
+
+    pub fn should_refresh_blocks(&self) -> bool {
+        self.blocks_deadline() <= Instant::now()
+    }
+
+    /// Rescan transcripts for usage blocks and re-render every session, so
+    /// `{block_reset}` counts down even while nothing happens
+    pub fn do_refresh_blocks(&mut self) {
+        self.update_blocks();
+
+        let sids: Vec<String> = self.sessions.keys().cloned().collect();
+        let mut changed = false;
+        for sid in sids {
+            let Some(mut state) = self.sessions.remove(&sid) else {
+                continue;
+            };
+            let before = (state.text.clone(), state.tooltip.clone());
+            self.render_session(&mut state);
+            if (&state.text, &state.tooltip) != (&before.0, &before.1) {
+                self.dirty_sessions.insert(sid.clone());
+                changed = true;
+            }
+            self.sessions.insert(sid, state);
+        }
+
+        if changed {
+            self.dirty = true;
+            self.pending_signal = true;
+            self.first_event_time.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Metrics for every tracked session and the counters since startup
+    pub fn metrics_text(&self, format: Format) -> String {
+        let sessions: Vec<&WaybarState> = self.sessions.values().collect();
+        self.metrics.render(&sessions, &self.ledger, format)
+    }
+
     /// Bind and return the socket
     pub fn bind_socket(&self) -> std::io::Result<UnixDatagram> {
         // Remove old socket if exists
-        let _ = fs::remove_file(&self.socket_path);
+        let _ = fs::remove_file(&self.config.socket_path);
 
-        let socket = UnixDatagram::bind(&self.socket_path)?;
-        socket.set_nonblocking(true)?;
+        let socket = UnixDatagram::bind(&self.config.socket_path)?;
 
         // Set permissions to user-only
         #[cfg(unix)]
         {
             use std::os::unix::fs::PermissionsExt;
             let perms = std::fs::Permissions::from_mode(0o600);
-            let _ = fs::set_permissions(&self.socket_path, perms);
+            let _ = fs::set_permissions(&self.config.socket_path, perms);
         }
 
         Ok(socket)
     }
 
-    /// Main daemon loop
-    pub fn run(&mut self) -> std::io::Result<()> {
+    /// Main daemon loop. Sleeps until a datagram arrives or the next
+    /// signal, flush or timeout is due, so it uses no CPU while idle.
+    pub async fn run(&mut self) -> std::io::Result<()> {
         let socket = self.bind_socket()?;
 
-        eprintln!("llm-bridge daemon listening on {:?}", self.socket_path);
+        eprintln!("llm-bridge daemon listening on {:?}", self.config.socket_path);
+
+        let metrics_listener = match self.config.metrics.listen {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                eprintln!("Serving metrics on http://{}/metrics", addr);
+                Some(listener)
+            }
+            None => None,
+        };
 
-        let mut buf = [0u8; 65536];
+        let mut buf = vec![0u8; 65536];
 
         loop {
-            // Try to receive a message (non-blocking)
-            match socket.recv(&mut buf) {
-                Ok(n) => {
-                    if let Ok(s) = std::str::from_utf8(&buf[..n]) {
-                        if let Some(msg) = DaemonMessage::decode(s) {
-                            self.handle_message(msg);
+            let deadline = [
+                self.signal_deadline(),
+                self.flush_deadline(),
+                self.sweep_deadline(),
+                Some(self.blocks_deadline()),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            let timer = async {
+                match deadline {
+                    Some(d) => tokio::time::sleep_until(d.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                received = socket.recv(&mut buf) => match received {
+                    Ok(n) => {
+                        if let Ok(s) = std::str::from_utf8(&buf[..n]) {
+                            match DaemonMessage::decode(s) {
+                                Ok(msg) => self.handle_message(msg),
+                                Err(e) => eprintln!("Ignoring message: {}", e),
+                            }
                         }
                     }
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // No message available, that's fine
-                }
-                Err(e) => {
-                    eprintln!("Socket error: {}", e);
-                }
+                    Err(e) => eprintln!("Socket error: {}", e),
+                },
+                accepted = accept(metrics_listener.as_ref()) => match accepted {
+                    // Rendered now, so the state is never shared with the task
+                    Ok(stream) => {
+                        tokio::spawn(serve_metrics(stream, self.metrics_text(Format::OpenMetrics)));
+                    }
+                    Err(e) => eprintln!("Metrics listener error: {}", e),
+                },
+                _ = timer => {}
             }
 
             // Check debounce timer and signal if ready
@@ -315,13 +624,639 @@ impl Daemon {
                 self.do_signal();
             }
 
+            if self.should_sweep() {
+                self.do_sweep();
+            }
+
+            if self.should_refresh_blocks() {
+                self.do_refresh_blocks();
+            }
+
             // Check disk flush timer
             if self.should_flush() {
                 self.do_flush();
             }
+        }
+    }
+}
+
+/// Next connection on `listener`; never resolves without one
+async fn accept(listener: Option<&TcpListener>) -> std::io::Result<TcpStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(stream, _)| stream),
+        None => std::future::pending().await,
+    }
+}
+
+/// Longest wait for a scraper to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answer one HTTP request: `body` for `GET /metrics`, 404 for anything else
+async fn serve_metrics(mut stream: TcpStream, body: String) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let read = tokio::time::timeout(REQUEST_TIMEOUT, async {
+        // Only the request line matters; read up to the end of the headers
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+    })
+    .await;
+    if read.is_err() {
+        return;
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", Format::OpenMetrics.content_type(), body),
+        _ => ("404 Not Found", "text/plain; charset=utf-8", "Not found; try /metrics\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Replace `path` with `contents` in one step, so a collector never reads
+/// half a file
+fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+fn unix_now() -> i64 {
+    unix_millis() / 1000
+}
+
+fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_daemon(name: &str) -> Daemon {
+        let dir = std::env::temp_dir().join(format!("llm-bridge-daemon-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = Config {
+            state_path: dir.join("state.json"),
+            sessions_dir: dir.join("sessions"),
+            socket_path: dir.join("daemon.sock"),
+            ledger_path: dir.join("ledger.jsonl"),
+            transcript_dir: dir.join("projects"),
+            ..Default::default()
+        };
+        Daemon::new(config).unwrap()
+    }
+
+    fn event(event_type: &str, tool: Option<&str>, session_id: Option<&str>) -> DaemonMessage {
+        DaemonMessage::Event(EventMessage {
+            tool: tool.map(str::to_string),
+            session_id: session_id.map(str::to_string),
+            ..EventMessage::new(event_type)
+        })
+    }
+
+    #[test]
+    fn test_events_are_routed_per_session() {
+        let mut daemon = test_daemon("routing");
+
+        daemon.handle_message(event("submit", None, Some("a")));
+        daemon.handle_message(event("tool-start", Some("Read"), Some("b")));
+
+        assert_eq!(daemon.sessions["a"].activity, "Thinking");
+        assert_eq!(daemon.sessions["b"].activity, "Read");
+        assert_eq!(daemon.combined_state().activity, "2 active");
+    }
+
+    #[test]
+    fn test_combined_state_uses_the_configured_templates() {
+        let mut daemon = test_daemon("templates");
+        daemon.config.format = "{sessions}: {icon_counts}".to_string();
+        daemon.template = daemon.config.text_template().unwrap();
+
+        daemon.handle_message(event("submit", None, Some("a")));
+        assert_eq!(daemon.combined_state().text, "1: 1 \u{f0517}");
+        daemon.handle_message(event("tool-start", Some("Read"), Some("b")));
+        let combined = daemon.combined_state();
+        // Both sessions were active in the same second, so in either order
+        assert!(["2: 1 \u{f0214} 1 \u{f0517}", "2: 1 \u{f0517} 1 \u{f0214}"].contains(&combined.text.as_str()));
+        assert!(combined.tooltip.starts_with("2 sessions | $0.00 total\n\n"), "{}", combined.tooltip);
+        assert!(combined.tooltip.contains("\nb: Read ($0.00)") && combined.tooltip.contains("\na: Thinking ($0.00)"));
+    }
+
+    #[test]
+    fn test_status_and_anonymous_events_follow_last_session() {
+        let mut daemon = test_daemon("status");
+
+        daemon.handle_message(DaemonMessage::Status {
+            session_id: None,
+            payload: r#"{"session_id":"a","cwd":"/work/a","cost":{"total_cost_usd":0.5}}"#.to_string(),
+        });
+        daemon.handle_message(event("tool-start", Some("Edit"), None));
+
+        assert_eq!(daemon.sessions.len(), 1);
+        assert_eq!(daemon.sessions["a"].cost, 0.5);
+        assert_eq!(daemon.sessions["a"].activity, "Edit");
+    }
+
+    #[test]
+    fn test_tool_start_sets_detail_from_input() {
+        let mut daemon = test_daemon("detail");
+
+        daemon.handle_message(DaemonMessage::Event(EventMessage {
+            tool: Some("Bash".to_string()),
+            session_id: Some("a".to_string()),
+            tool_input: Some(serde_json::json!({"command": "cargo test --workspace"})),
+            ..EventMessage::new("tool-start")
+        }));
+        assert_eq!(daemon.sessions["a"].detail, "cargo test");
+        assert!(daemon.sessions["a"].tooltip.contains("Bash (cargo test)"));
+
+        daemon.handle_message(event("tool-end", Some("Bash"), Some("a")));
+        assert_eq!(daemon.sessions["a"].detail, "");
+    }
+
+    #[test]
+    fn test_status_costs_go_to_the_ledger() {
+        let mut daemon = test_daemon("ledger");
+        daemon.config.format = "{today_cost:.2}/{month_cost:.2}".to_string();
+        daemon.template = daemon.config.text_template().unwrap();
+        let status = |sid: &str, cost: f64| DaemonMessage::Status {
+            session_id: None,
+            payload: format!(r#"{{"session_id":"{}","cost":{{"total_cost_usd":{}}}}}"#, sid, cost),
+        };
+
+        daemon.handle_message(status("a", 0.5));
+        daemon.handle_message(status("b", 0.5));
+        assert_eq!(daemon.sessions["b"].text, "1.00/1.00");
+
+        // Within a minute of its last snapshot, a session's growth waits
+        // for the next one, as in direct mode
+        daemon.handle_message(status("a", 1.25));
+        assert_eq!(daemon.ledger.session("a").unwrap().cost, 0.5);
+        let lines = fs::read_to_string(&daemon.config.ledger_path).unwrap();
+        assert_eq!(lines.lines().count(), 2);
+
+        // A restarted daemon still knows what was spent
+        let restarted = Daemon::new(daemon.config.clone()).unwrap();
+        assert_eq!(restarted.ledger.spend(unix_now()).today, 1.0);
+    }
+
+    #[test]
+    fn test_status_updates_feed_burn_rates() {
+        let mut daemon = test_daemon("rates");
+        daemon.config.format = "{cost_per_hour:.2}/{projected_today_cost:.2}".to_string();
+        daemon.template = daemon.config.text_template().unwrap();
+        let status = |sid: &str, cost: f64| DaemonMessage::Status {
+            session_id: None,
+            payload: format!(r#"{{"session_id":"{}","cost":{{"total_cost_usd":{}}}}}"#, sid, cost),
+        };
+
+        // Five minutes ago, a was at $0 and b at $1
+        for (sid, cost) in [("a", 0.0), ("b", 1.0)] {
+            let mut rate = BurnRate::new(daemon.config.rate.window_secs);
+            rate.record(unix_now() - 300, cost, 0);
+            daemon.rates.insert(sid.to_string(), rate);
+        }
+        let close = |rate: f64, expected: f64| (rate - expected).abs() < 0.1;
+
+        daemon.handle_message(status("a", 0.5));
+        assert!(close(daemon.sessions["a"].cost_per_hour, 6.0));
+        daemon.handle_message(status("b", 1.25));
+        assert!(close(daemon.sessions["b"].cost_per_hour, 3.0));
+        // Projected from today's $1.75 at the $9/h of both sessions
+        let projected = daemon.sessions["b"].projected_today_cost;
+        assert!((1.75..=1.75 + 9.1 * 24.0).contains(&projected), "{}", projected);
+
+        let combined = daemon.combined_state();
+        assert!(close(combined.cost_per_hour, 9.0));
+
+        daemon.handle_message(event("session-end", None, Some("a")));
+        assert!(!daemon.rates.contains_key("a"));
+    }
+
+    #[test]
+    fn test_status_updates_read_usage_blocks() {
+        let mut daemon = test_daemon("blocks");
+        daemon.config.block.token_limit = 10_000;
+        let project = daemon.config.transcript_dir.join("-src-x");
+        fs::create_dir_all(&project).unwrap();
+
+        // A response half an hour ago, from a session the daemon never saw
+        let time = unix_now() - 1800;
+        let (year, month, day) = llm_bridge_core::ledger::civil_from_days(time.div_euclid(86_400));
+        let secs = time.rem_euclid(86_400);
+        fs::write(
+            project.join("other.jsonl"),
+            format!(
+                r#"{{"type":"assistant","timestamp":"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z","message":{{"id":"m1","usage":{{"input_tokens":500,"output_tokens":1500}}}}}}"#,
+                year,
+                month,
+                day,
+                secs / 3600,
+                secs % 3600 / 60,
+                secs % 60
+            ) + "\n",
+        )
+        .unwrap();
+
+        daemon.handle_message(DaemonMessage::Status {
+            session_id: None,
+            payload: r#"{"session_id":"a","cost":{"total_cost_usd":0.1}}"#.to_string(),
+        });
+        // Status updates do not scan transcripts; the refresh timer does
+        assert_eq!(daemon.sessions["a"].block_tokens, 0);
+        assert!(!daemon.should_refresh_blocks());
+        daemon.dirty_sessions.clear();
+        daemon.do_refresh_blocks();
+        assert!(daemon.dirty_sessions.contains("a"));
+        let state = &daemon.sessions["a"];
+        assert_eq!((state.block_tokens, state.block_pct), (2000, 20));
+        assert!(!state.block_reset.is_empty());
+    }
+
+    #[test]
+    fn test_metrics_are_counted_and_written() {
+        let mut daemon = test_daemon("metrics");
+        let textfile = daemon.config.ledger_path.with_file_name("llm.prom");
+        daemon.config.metrics.textfile_path = Some(textfile.clone());
+
+        daemon.handle_message(event("tool-start", Some("Bash"), Some("a")));
+        daemon.handle_message(event("tool-end", Some("Bash"), Some("a")));
+        daemon.handle_message(DaemonMessage::Status {
+            session_id: None,
+            payload: r#"{"session_id":"a","cost":{"total_cost_usd":0.75}}"#.to_string(),
+        });
+        daemon.do_signal();
+        daemon.do_flush();
+
+        let text = fs::read_to_string(&textfile).unwrap();
+        for line in [
+            "llm_bridge_sessions 1",
+            "llm_bridge_session_cost_usd{session=\"a\"} 0.75",
+            "llm_bridge_tool_invocations_total{tool=\"Bash\"} 1",
+            "llm_bridge_tool_duration_seconds_count{tool=\"Bash\"} 1",
+            "llm_bridge_signal_latency_seconds_count 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+        }
+        assert!(daemon.metrics_text(Format::OpenMetrics).ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn test_metrics_are_served_over_http() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let get = |path: &'static str| {
+            runtime.block_on(async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+                let (server, _) = listener.accept().await.unwrap();
+                let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+                client.write_all(request.as_bytes()).await.unwrap();
+                serve_metrics(server, "llm_bridge_sessions 0\n# EOF\n".to_string()).await;
+                let mut response = String::new();
+                client.read_to_string(&mut response).await.unwrap();
+                response
+            })
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Type: application/openmetrics-text"));
+        assert!(response.ends_with("\r\n\r\nllm_bridge_sessions 0\n# EOF\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_budget_alerts_fire_once_per_crossing() {
+        let mut daemon = test_daemon("budget");
+        let alerts = daemon.config.ledger_path.with_file_name("alerts.txt");
+        daemon.config.budget.daily = 1.0;
+        daemon.config.budget.notify_command = vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            format!("echo \"$0\" >> {}", alerts.display()),
+        ];
+        let status = |sid: &str, cost: f64| DaemonMessage::Status {
+            session_id: None,
+            payload: format!(r#"{{"session_id":"{}","cost":{{"total_cost_usd":{}}}}}"#, sid, cost),
+        };
+
+        daemon.handle_message(status("a", 0.5));
+        daemon.handle_message(status("b", 0.35));
+        assert_eq!(daemon.sessions["b"].classes, vec!["budget-warn"]);
+        daemon.handle_message(status("b", 0.4));
+        daemon.handle_message(status("c", 0.25));
+        assert_eq!(daemon.sessions["c"].classes, vec!["budget-over"]);
+        assert_eq!(daemon.sessions["c"].budget_remaining, 0.0);
+
+        // The commands run in the background, in no particular order
+        for _ in 0..100 {
+            if fs::read_to_string(&alerts).is_ok_and(|s| s.lines().count() >= 2) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let mut lines: Vec<String> = fs::read_to_string(&alerts).unwrap().lines().map(String::from).collect();
+        lines.sort();
+        assert_eq!(lines, vec!["Daily budget 85% used: $0.85 of $1.00", "Daily budget spent: $1.10 of $1.00"]);
+    }
+
+    #[test]
+    fn test_event_time_and_cwd_are_applied() {
+        let mut daemon = test_daemon("event-fields");
+
+        daemon.handle_message(DaemonMessage::Event(EventMessage {
+            session_id: Some("a".to_string()),
+            cwd: Some("/work/a".to_string()),
+            time_ms: Some(unix_now() * 1000 - 5_000),
+            ..EventMessage::new("submit")
+        }));
+
+        assert_eq!(daemon.sessions["a"].cwd, "/work/a");
+        assert_eq!(daemon.sessions["a"].last_activity_time, unix_now() - 5);
+    }
 
-            // Small sleep to prevent busy-waiting
+    #[test]
+    fn test_notification_and_session_end() {
+        let mut daemon = test_daemon("lifecycle");
+
+        daemon.handle_message(event("pre-compact", None, Some("a")));
+        assert_eq!(daemon.sessions["a"].class, "compacting");
+
+        daemon.handle_message(DaemonMessage::Event(EventMessage {
+            session_id: Some("a".to_string()),
+            message: Some("Claude needs your permission to use Bash".to_string()),
+            ..EventMessage::new("notification")
+        }));
+        assert_eq!(daemon.sessions["a"].activity, "Waiting for permission");
+        assert_eq!(daemon.sessions["a"].class, "attention");
+
+        daemon.do_flush();
+        let file = session_path(&daemon.config.sessions_dir, "a");
+        assert!(file.exists());
+
+        daemon.handle_message(event("session-end", None, Some("a")));
+        assert!(daemon.sessions.is_empty());
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn test_awaiting_input_lasts_until_answered() {
+        let mut daemon = test_daemon("attention");
+
+        daemon.handle_message(event("tool-start", Some("Bash"), Some("a")));
+        daemon.handle_message(event("notification", None, Some("a")));
+        daemon.handle_message(event("tool-end", Some("Read"), Some("a")));
+        daemon.handle_message(event("subagent-stop", None, Some("a")));
+        assert_eq!(daemon.sessions["a"].activity, "Waiting for input");
+        daemon.handle_message(event("tool-end", Some("Bash"), Some("a")));
+        assert_eq!(daemon.sessions["a"].activity, "Thinking");
+
+        daemon.handle_message(event("stop", None, Some("a")));
+        daemon.handle_message(event("notification", None, Some("a")));
+        assert_eq!(daemon.sessions["a"].activity, "Waiting for input");
+
+        // Neither times out nor goes stale while waiting
+        let stale = daemon.config.timeouts.stale_session_secs as i64;
+        daemon.sessions.get_mut("a").unwrap().last_activity_time = unix_now() - stale - 10;
+        assert!(daemon.sweep_deadline().unwrap() > Instant::now() + Duration::from_secs(60));
+        daemon.do_sweep();
+        assert!(daemon.sessions["a"].is_awaiting_input());
+
+        daemon.handle_message(event("submit", None, Some("a")));
+        assert_eq!(daemon.sessions["a"].activity, "Thinking");
+    }
+
+    #[test]
+    fn test_abandoned_awaiting_session_is_swept() {
+        let mut daemon = test_daemon("abandoned");
+
+        daemon.handle_message(DaemonMessage::Event(EventMessage {
+            session_id: Some("a".to_string()),
+            message: Some("Claude needs your permission to use Bash".to_string()),
+            ..EventMessage::new("notification")
+        }));
+        daemon.handle_message(event("submit", None, Some("b")));
+
+        // Closed at the prompt, the session never sends session-end
+        let awaiting = daemon.config.timeouts.awaiting_input_secs as i64;
+        daemon.sessions.get_mut("a").unwrap().last_activity_time = unix_now() - awaiting - 1;
+        assert!(daemon.should_sweep());
+        daemon.do_sweep();
+        assert!(!daemon.sessions.contains_key("a"));
+        assert_eq!(daemon.combined_state().activity, "Thinking");
+    }
+
+    #[test]
+    fn test_failed_tool_sets_sticky_error() {
+        let mut daemon = test_daemon("error");
+
+        daemon.handle_message(DaemonMessage::Event(EventMessage {
+            tool: Some("Bash".to_string()),
+            session_id: Some("a".to_string()),
+            error: Some("exit code 101".to_string()),
+            ..EventMessage::new("tool-end")
+        }));
+        daemon.handle_message(event("tool-start", Some("Read"), Some("a")));
+
+        let state = &daemon.sessions["a"];
+        assert_eq!(state.class, "error");
+        assert_eq!(state.error_count, 1);
+        assert!(state.tooltip.contains("Errors: 1 (last: Bash: exit code 101)"));
+
+        daemon.handle_message(event("submit", None, Some("a")));
+        assert_eq!(daemon.sessions["a"].activity, "Thinking");
+    }
+
+    #[test]
+    fn test_flush_writes_session_files_and_combined_state() {
+        let mut daemon = test_daemon("flush");
+
+        daemon.handle_message(event("submit", None, Some("a")));
+        daemon.handle_message(event("submit", None, Some("b")));
+        daemon.do_flush();
+
+        let sessions_dir = &daemon.config.sessions_dir;
+        assert_eq!(WaybarState::read_from(&sessions_dir.join("a.json")).unwrap().session_id, "a");
+        assert!(sessions_dir.join("b.json").exists());
+        let combined = WaybarState::read_from(&daemon.config.state_path).unwrap();
+        assert_eq!(combined.activity, "2 active");
+
+        // A restarted daemon picks the sessions back up
+        let restarted = Daemon::new(daemon.config.clone()).unwrap();
+        assert_eq!(restarted.sessions.len(), 2);
+    }
+
+    #[test]
+    fn test_idle_daemon_sets_no_timers() {
+        let mut daemon = test_daemon("idle");
+        daemon.do_flush();
+
+        assert_eq!(daemon.signal_deadline(), None);
+        assert_eq!(daemon.flush_deadline(), None);
+        assert_eq!(daemon.sweep_deadline(), None);
+    }
+
+    #[test]
+    fn test_timers_follow_events_and_timeouts() {
+        let mut daemon = test_daemon("timers");
+        daemon.do_flush();
+        let before = Instant::now();
+
+        daemon.handle_message(event("tool-start", Some("Read"), Some("a")));
+
+        let signal = daemon.signal_deadline().unwrap();
+        assert!(signal <= Instant::now() + Duration::from_millis(daemon.config.daemon.max_debounce_ms));
+        assert!(daemon.flush_deadline().is_some());
+
+        // The activity timeout comes before the stale timeout
+        let sweep = daemon.sweep_deadline().unwrap();
+        let activity = Duration::from_secs(daemon.config.timeouts.activity_secs);
+        assert!(sweep >= before + activity && sweep <= Instant::now() + activity + Duration::from_secs(1));
+
+        daemon.handle_message(event("stop", None, Some("a")));
+        let stale = Duration::from_secs(daemon.config.timeouts.stale_session_secs);
+        assert!(daemon.sweep_deadline().unwrap() >= before + stale);
+        // An error is swept when it expires, even if that is after the activity timeout
+        daemon.config.timeouts.error_secs = 90;
+        daemon.handle_message(DaemonMessage::Event(EventMessage {
+            tool: Some("Bash".to_string()),
+            session_id: Some("a".to_string()),
+            error: Some("exit code 1".to_string()),
+            ..EventMessage::new("tool-end")
+        }));
+        let error = Duration::from_secs(90);
+        let sweep = daemon.sweep_deadline().unwrap();
+        assert!(sweep >= before + error - Duration::from_secs(1) && sweep <= Instant::now() + error);
+    }
+
+    #[test]
+    fn test_subscribers_get_each_changed_output_once() {
+        let mut daemon = test_daemon("subscribe");
+        let name = format!("llm-bridge-daemon-test-{}", std::process::id());
+        let watcher = llm_bridge_core::socket::bind_subscriber(&name).unwrap();
+        watcher.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 4096];
+        let mut recv = || {
+            watcher
+                .recv(&mut buf)
+                .ok()
+                .map(|n| serde_json::from_slice::<serde_json::Value>(&buf[..n]).unwrap())
+        };
+
+        daemon.handle_message(DaemonMessage::Subscribe { name: name.clone() });
+        assert_eq!(recv().unwrap()["alt"], "idle");
+
+        // Renewing the subscription sends nothing new
+        daemon.handle_message(DaemonMessage::Subscribe { name: name.clone() });
+        assert!(recv().is_none());
+
+        daemon.handle_message(event("submit", None, Some("a")));
+        daemon.do_signal();
+        assert_eq!(recv().unwrap()["alt"], "active");
+
+        daemon.handle_message(event("submit", None, Some("a")));
+        daemon.do_signal();
+        assert!(recv().is_none());
+        assert!(!daemon.pending_signal);
+
+        drop(watcher);
+        daemon.handle_message(event("stop", None, Some("a")));
+        daemon.do_signal();
+        assert!(daemon.subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_bars_are_signalled_alongside_subscribers() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut daemon = test_daemon("both");
+        let name = format!("llm-bridge-daemon-both-{}", std::process::id());
+        let watcher = llm_bridge_core::socket::bind_subscriber(&name).unwrap();
+        watcher.set_nonblocking(true).unwrap();
+        daemon.handle_message(DaemonMessage::Subscribe { name });
+
+        // A `sleep` stands in for a signal-based bar; the signal ends it
+        let mut bar = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let comm = format!("/proc/{}/comm", bar.id());
+        while fs::read_to_string(&comm).unwrap_or_default().trim() != "sleep" {
             std::thread::sleep(Duration::from_millis(1));
         }
+        daemon.matcher = ProcessMatcher::new(&["sleep".to_string()]);
+        daemon.waybar_pids = vec![bar.id() as i32];
+        daemon.pid_cache_time = Instant::now();
+
+        daemon.handle_message(event("submit", None, Some("a")));
+        daemon.do_signal();
+
+        let mut buf = [0u8; 4096];
+        watcher.recv(&mut buf).unwrap(); // The initial output
+        watcher.recv(&mut buf).unwrap();
+        assert!(bar.wait().unwrap().signal().is_some());
+    }
+
+    #[test]
+    fn test_cached_pid_that_no_longer_matches_is_dropped() {
+        let mut daemon = test_daemon("pids");
+        daemon.matcher = ProcessMatcher::new(&["no-such-bar-process".to_string()]);
+        // Our own PID stands in for a bar PID that was reused by another program
+        daemon.waybar_pids = vec![std::process::id() as i32];
+
+        daemon.signal_waybar();
+
+        assert!(daemon.waybar_pids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_handles_datagrams() {
+        let mut daemon = test_daemon("run");
+        let config = daemon.config.clone();
+        fs::create_dir_all(config.socket_path.parent().unwrap()).unwrap();
+
+        let client = async {
+            let sent = async {
+                while !llm_bridge_core::send_to_daemon(&config.socket_path, &event("submit", None, Some("a")))
+                    .unwrap_or(false)
+                {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), sent).await.unwrap();
+
+            loop {
+                if let Ok(state) = WaybarState::read_from(&config.state_path) {
+                    if state.activity == "Thinking" {
+                        return state;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+
+        tokio::select! {
+            result = daemon.run() => panic!("daemon stopped: {:?}", result),
+            state = tokio::time::timeout(Duration::from_secs(5), client) => {
+                assert_eq!(state.unwrap().session_id, "a");
+            }
+        }
     }
 }
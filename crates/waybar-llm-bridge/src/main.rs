@@ -1,14 +1,21 @@
-mod aggregator;
 mod daemon;
 
 use clap::{Parser, Subcommand, ValueEnum};
-use serde::Deserialize;
-use std::io::{self, BufRead, IsTerminal};
-use std::path::PathBuf;
-use llm_bridge_core::{Config, WaybarState, AgentPhase, signal::signal_waybar};
-use llm_bridge_core::socket::{DaemonMessage, send_to_daemon};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use llm_bridge_core::{Config, ConfigError, TemplateError, WaybarState, AgentPhase, signal::signal_waybar};
+use llm_bridge_core::state::session_path;
+use llm_bridge_core::budget::{self, Allowance, BudgetLevel, Spent};
+use llm_bridge_core::ledger::{read_entries, Ledger, LedgerEntry};
+use llm_bridge_core::report::{self, GroupBy};
+use llm_bridge_core::socket::{DaemonMessage, EventMessage, bind_subscriber, send_to_daemon};
 use llm_bridge_claude::ClaudeProvider;
-use llm_bridge_core::LlmProvider;
+use llm_bridge_claude::hooks::block_decision;
+use llm_bridge_claude::import::import_transcripts;
+use llm_bridge_claude::pricing::PricingTable;
+use llm_bridge_claude::statusline::StatuslinePayload;
+use llm_bridge_claude::tool_summary::summarize_tool_input;
+use llm_bridge_core::{HookEvent, LlmEvent, LlmProvider};
 use notify::{Watcher, RecursiveMode, Event, EventKind};
 use std::sync::mpsc::channel;
 use std::time::Duration;
@@ -17,15 +24,26 @@ use std::time::Duration;
 #[command(name = "waybar-llm-bridge")]
 #[command(about = "Bridge LLM agents to Waybar status bar")]
 struct Cli {
-    #[arg(long, env = "LLM_BRIDGE_STATE_PATH")]
+    /// Config file (default: $XDG_CONFIG_HOME/llm-waybar/config.toml)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// State file path [env: LLM_BRIDGE_STATE_PATH]
+    #[arg(long)]
     state_path: Option<PathBuf>,
 
-    #[arg(long, env = "LLM_BRIDGE_SIGNAL", default_value = "8")]
-    signal: u8,
+    /// Waybar signal number, sent as SIGRTMIN+N [env: LLM_BRIDGE_SIGNAL]
+    #[arg(long)]
+    signal: Option<u8>,
 
-    #[arg(long, env = "LLM_BRIDGE_FORMAT")]
+    /// Display format string [env: LLM_BRIDGE_FORMAT]
+    #[arg(long)]
     format: Option<String>,
 
+    /// Tooltip format string, Pango markup [env: LLM_BRIDGE_TOOLTIP_FORMAT]
+    #[arg(long)]
+    tooltip_format: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -50,17 +68,19 @@ enum Commands {
     },
     /// Output current state as Waybar JSON
     Status,
+    /// Print a Waybar JSON line on every change, for a long-running Waybar `exec`
+    Watch,
     /// Run as background daemon (new high-performance mode)
     Daemon {
         /// Watch transcript file for changes (legacy mode)
         #[arg(long)]
         log_path: Option<PathBuf>,
 
-        /// Aggregate mode: watch sessions directory (legacy mode)
-        #[arg(long)]
+        /// Deprecated: the socket daemon now combines sessions itself
+        #[arg(long, hide = true)]
         aggregate: bool,
 
-        /// Sessions directory (for aggregate mode)
+        /// Directory for per-session state files
         #[arg(long)]
         sessions_dir: Option<PathBuf>,
 
@@ -82,6 +102,40 @@ enum Commands {
         #[arg(long)]
         dry_run: bool,
     },
+    /// Add past sessions' usage from the transcript directory to the ledger
+    Import,
+    /// Check budgets or lift their enforcement for a while
+    Budget {
+        #[command(subcommand)]
+        action: BudgetAction,
+    },
+    /// Break down spend and usage from the ledger
+    Report {
+        /// First day to include, YYYY-MM-DD in local time
+        #[arg(long)]
+        since: Option<String>,
+        /// Last day to include, YYYY-MM-DD in local time
+        #[arg(long)]
+        until: Option<String>,
+        #[arg(long, value_enum, default_value = "day")]
+        group_by: ReportGroupBy,
+        #[arg(long = "format", value_enum, default_value = "table")]
+        output: ReportFormat,
+    },
+}
+
+#[derive(Subcommand)]
+enum BudgetAction {
+    /// Show each budget and what has been spent against it
+    Status,
+    /// Let prompts and tool calls through despite a spent budget
+    Allow {
+        /// How long, e.g. 30m, 1h or 1h30m
+        #[arg(long = "for", value_name = "DURATION", value_parser = parse_duration_arg)]
+        duration: u64,
+    },
+    /// End an allowance early
+    Revoke,
 }
 
 #[derive(Clone, ValueEnum)]
@@ -90,75 +144,68 @@ enum EventType {
     ToolStart,
     ToolEnd,
     Stop,
+    Notification,
+    SubagentStop,
+    PreCompact,
+    SessionStart,
+    SessionEnd,
 }
 
-/// JSON input from Claude Code's statusLine hook
-#[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-struct StatuslineInput {
-    session_id: Option<String>,
-    transcript_path: Option<String>,
-    cwd: Option<String>,
-    model: Option<ModelInfo>,
-    cost: Option<CostInfo>,
-    context_window: Option<ContextWindow>,
-}
-
-#[derive(Debug, Deserialize)]
-struct ModelInfo {
-    id: Option<String>,
-    display_name: Option<String>,
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportGroupBy {
+    Day,
+    Project,
+    Model,
+    Session,
 }
 
-#[derive(Debug, Deserialize)]
-struct CostInfo {
-    total_cost_usd: Option<f64>,
-}
-
-#[derive(Debug, Deserialize)]
-struct ContextWindow {
-    current_usage: Option<CurrentUsage>,
-}
-
-#[derive(Debug, Deserialize)]
-struct CurrentUsage {
-    input_tokens: Option<u64>,
-    output_tokens: Option<u64>,
-    cache_creation_input_tokens: Option<u64>,
-    cache_read_input_tokens: Option<u64>,
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportFormat {
+    Table,
+    Json,
+    Csv,
 }
 
 fn main() {
     let cli = Cli::parse();
-    let config = Config::from_env();
-    let state_path = cli.state_path.unwrap_or(config.state_path);
-    let format = cli.format.unwrap_or(config.format);
+    let config = match load_config(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     let result = match cli.command {
         Commands::Event { r#type, tool, payload, session_id } => {
-            handle_event(r#type, tool, payload, session_id, &state_path, &config.sessions_dir, cli.signal, &format)
+            handle_event(r#type, tool, payload, session_id, &config)
         }
         Commands::SyncUsage { log_path } => {
-            handle_sync_usage(&log_path, &state_path, cli.signal)
+            handle_sync_usage(&log_path, &config)
         }
         Commands::Status => {
-            handle_status(&state_path)
+            handle_status(&config)
+        }
+        Commands::Watch => {
+            handle_watch(&config)
         }
         Commands::Daemon { log_path, aggregate, sessions_dir, socket } => {
-            // New socket daemon mode (default when no legacy flags)
-            if socket || (log_path.is_none() && !aggregate) {
-                handle_daemon_socket(&state_path, &config.sessions_dir, cli.signal, &format)
-            } else if aggregate {
-                let sessions = sessions_dir.unwrap_or(config.sessions_dir);
-                handle_daemon_aggregate(&sessions, &state_path, cli.signal)
-            } else if let Some(log) = log_path {
-                handle_daemon(&log, &state_path, cli.signal)
-            } else {
-                Err("Either --log-path, --aggregate, or --socket is required".into())
+            let mut config = config;
+            if let Some(dir) = sessions_dir {
+                config.sessions_dir = dir;
+            }
+            if aggregate {
+                eprintln!("Warning: --aggregate is deprecated; the socket daemon combines sessions");
+            }
+
+            // Socket daemon mode (default unless watching a transcript)
+            match log_path {
+                Some(log) if !socket && !aggregate => handle_daemon(&log, &config),
+                _ => handle_daemon_socket(&config),
             }
         }
         Commands::Statusline => {
-            handle_statusline(&state_path, &config.sessions_dir, cli.signal, &format)
+            handle_statusline(&config)
         }
         Commands::InstallHooks { dry_run } => {
             handle_install_hooks(dry_run)
@@ -166,6 +213,15 @@ fn main() {
         Commands::UninstallHooks { dry_run } => {
             handle_uninstall_hooks(dry_run)
         }
+        Commands::Import => {
+            handle_import(&config)
+        }
+        Commands::Budget { action } => {
+            handle_budget(action, &config)
+        }
+        Commands::Report { since, until, group_by, output } => {
+            handle_report(since.as_deref(), until.as_deref(), group_by, output, &config)
+        }
     };
 
     if let Err(e) = result {
@@ -174,31 +230,63 @@ fn main() {
     }
 }
 
+/// Config file and environment, overridden by command-line flags
+fn load_config(cli: &Cli) -> Result<Config, ConfigError> {
+    let mut config = Config::load(cli.config.as_deref())?;
+    if let Some(state_path) = &cli.state_path {
+        config.state_path = state_path.clone();
+    }
+    if let Some(signal) = cli.signal {
+        config.signal = signal;
+    }
+    if let Some(format) = &cli.format {
+        config.format = format.clone();
+    }
+    if let Some(tooltip_format) = &cli.tooltip_format {
+        config.tooltip_format = tooltip_format.clone();
+    }
+    config.validate()?;
+    Ok(config)
+}
+
 fn handle_event(
     event_type: EventType,
     tool: Option<String>,
-    _payload: Option<String>,
+    payload: Option<String>,
     session_id: Option<String>,
-    state_path: &PathBuf,
-    sessions_dir: &PathBuf,
-    signal: u8,
-    format: &str,
+    config: &Config,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let config = Config::from_env();
-
     // Try daemon first (fire-and-forget, <1ms)
     let event_str = match event_type {
         EventType::Submit => "submit",
         EventType::ToolStart => "tool-start",
         EventType::ToolEnd => "tool-end",
         EventType::Stop => "stop",
+        EventType::Notification => "notification",
+        EventType::SubagentStop => "subagent-stop",
+        EventType::PreCompact => "pre-compact",
+        EventType::SessionStart => "session-start",
+        EventType::SessionEnd => "session-end",
     };
 
-    let message = DaemonMessage::Event {
-        event_type: event_str.to_string(),
-        tool: tool.clone(),
-    };
+    // Claude hooks pipe their JSON payload on stdin
+    let payload = payload.or_else(read_hook_stdin);
+    let provider = ClaudeProvider::default();
+    let hook = provider.parse_event(event_str, payload.as_deref()).or_else(|e| {
+        eprintln!("Warning: ignoring hook payload: {}", e);
+        provider.parse_event(event_str, None)
+    })?;
+
+    // Flags override what the hook reported
+    let tool = tool.or_else(|| hook.tool().map(str::to_string));
+    let session_id = session_id.or_else(|| hook.session_id.clone());
 
+    // Stop new work while a budget is spent, before the bar shows it starting
+    if let Some(decision) = budget_block(config, event_str, session_id.as_deref()) {
+        println!("{}", decision);
+        return Ok(());
+    }
+    let message = event_message(event_str, &hook, tool.clone(), session_id.clone());
     if send_to_daemon(&config.socket_path, &message).unwrap_or(false) {
         // Daemon handled it, we're done
         return Ok(());
@@ -206,97 +294,205 @@ fn handle_event(
 
     // Fallback: direct mode (daemon not running)
     // Read existing state to preserve data from other sources (like statusline)
-    let mut state = WaybarState::read_from(state_path).unwrap_or_default();
-
-    // Determine the phase based on event type
-    let phase = match event_type {
-        EventType::Submit => AgentPhase::Thinking,
-        EventType::ToolStart => AgentPhase::ToolUse {
-            tool: tool.unwrap_or_else(|| "unknown".to_string()),
-        },
-        EventType::ToolEnd => AgentPhase::Thinking,
-        EventType::Stop => AgentPhase::Idle,
-    };
+    let mut state = WaybarState::read_from(&config.state_path).unwrap_or_default();
 
     // Update only activity-related fields (activity, class, alt)
-    // Preserve tooltip which may contain usage/cost data from statusline
-    let (activity, class, alt) = match &phase {
-        AgentPhase::Idle => ("Idle".to_string(), "idle".to_string(), "idle".to_string()),
-        AgentPhase::Thinking => ("Thinking".to_string(), "thinking".to_string(), "active".to_string()),
-        AgentPhase::ToolUse { tool } => {
-            // Truncate very long tool names (>20 chars)
-            let truncated = if tool.len() > 20 {
-                format!("{}...", &tool[..17])
-            } else {
-                tool.clone()
-            };
-            (truncated, "tool-active".to_string(), "active".to_string())
+    // Usage and cost from statusline are preserved in the state fields
+    let phase = match hook.event.phase() {
+        Some(AgentPhase::ToolUse { .. }) => AgentPhase::ToolUse {
+            tool: tool.unwrap_or_else(|| "unknown".to_string()),
         },
-        AgentPhase::Error { message } => (format!("Error: {}", message), "error".to_string(), "error".to_string()),
+        Some(phase) => phase,
+        // The session ended; nothing of it stays on the bar
+        None => AgentPhase::Idle,
     };
-
-    state.activity = activity;
-    state.class = class;
-    state.alt = alt;
-    // Note: tooltip is preserved from previous state (may contain cost/usage data)
+    let now = unix_millis() / 1000;
+    let shown = state.apply_phase(&phase, &hook.event, now, config.timeouts.error_secs);
+    if let (true, AgentPhase::ToolUse { tool }, LlmEvent::ToolStart { input: Some(input), .. }) =
+        (shown, &phase, &hook.event)
+    {
+        state.detail = summarize_tool_input(tool, input).unwrap_or_default();
+    }
 
     // Update last activity time (current Unix timestamp)
-    state.last_activity_time = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as i64;
+    state.last_activity_time = now;
+    if let Some(cwd) = hook.cwd {
+        state.cwd = cwd;
+    }
 
-    // Compute text from format string
-    state.text = state.compute_text(format);
+    render_display(&mut state, config)?;
 
     // Set session_id if provided
     if let Some(sid) = session_id {
         state.session_id = sid;
     }
 
-    // Write to session-specific file
-    let _ = state.write_session_file(sessions_dir);
+    // Write to session-specific file, or drop it once the session ended
+    if matches!(hook.event, LlmEvent::SessionEnd { .. }) {
+        if !state.session_id.is_empty() {
+            let _ = std::fs::remove_file(session_path(&config.sessions_dir, &state.session_id));
+        }
+    } else {
+        let _ = state.write_session_file(&config.sessions_dir);
+    }
 
-    state.write_atomic(state_path)?;
+    state.write_atomic(&config.state_path)?;
 
-    let _ = signal_waybar(signal); // Ignore if waybar not running
+    let _ = signal_waybar(config.signal, &config.waybar_processes); // Ignore if waybar not running
     Ok(())
 }
 
+/// The datagram telling the daemon about `hook`. Only the fields the daemon
+/// reads are sent: the whole hook payload can carry a tool's full output,
+/// far more than fits in one datagram.
+fn event_message(
+    event_str: &str,
+    hook: &HookEvent,
+    tool: Option<String>,
+    session_id: Option<String>,
+) -> DaemonMessage {
+    let (tool_input, error, message) = match &hook.event {
+        LlmEvent::ToolStart { input, .. } => (input.clone(), None, None),
+        LlmEvent::ToolEnd { error, .. } | LlmEvent::Stop { error } => (None, error.clone(), None),
+        LlmEvent::Notification { message }
+        | LlmEvent::SessionStart { source: message }
+        | LlmEvent::SessionEnd { reason: message } => (None, None, message.clone()),
+        _ => (None, None, None),
+    };
+
+    DaemonMessage::Event(EventMessage {
+        tool,
+        session_id,
+        cwd: hook
+            .cwd
+            .clone()
+            .or_else(|| std::env::current_dir().ok().map(|d| d.to_string_lossy().into_owned())),
+        time_ms: Some(unix_millis()),
+        tool_input,
+        error,
+        message,
+        ..EventMessage::new(event_str)
+    })
+}
+
+/// How long `event` waits for a hook payload on stdin
+const STDIN_WAIT: u16 = 100;
+
+/// Read the hook payload, waiting only briefly so that running `event` by
+/// hand or from a script whose stdin never closes does not hang
+fn read_hook_stdin() -> Option<String> {
+    use nix::poll::{poll, PollFd, PollFlags};
+    use std::io::Read;
+    use std::os::fd::AsFd;
+
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        return None;
+    }
+    let mut fds = [PollFd::new(stdin.as_fd(), PollFlags::POLLIN)];
+    if poll(&mut fds, STDIN_WAIT).ok()? == 0 {
+        return None;
+    }
+
+    let mut input = String::new();
+    stdin.lock().read_to_string(&mut input).ok()?;
+    Some(input).filter(|s| !s.trim().is_empty())
+}
+
+fn unix_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis() as i64
+}
+
 fn handle_sync_usage(
-    log_path: &PathBuf,
-    state_path: &PathBuf,
-    signal: u8,
+    log_path: &Path,
+    config: &Config,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let provider = ClaudeProvider::new();
+    let provider = claude_provider(config);
     let usage = provider.parse_usage(log_path)?;
 
-    // Read current state and update tooltip
-    let mut state = WaybarState::read_from(state_path).unwrap_or_default();
-    state.tooltip = format!(
-        "Tokens: {} in / {} out\nCache: {} read / {} write\nCost: ${:.4}",
-        usage.input_tokens,
-        usage.output_tokens,
-        usage.cache_read,
-        usage.cache_write,
-        usage.estimated_cost
-    );
+    // Read current state and update usage
+    let mut state = WaybarState::read_from(&config.state_path).unwrap_or_default();
+    state.set_usage(&usage);
+    render_display(&mut state, config)?;
 
-    state.write_atomic(state_path)?;
-    let _ = signal_waybar(signal);
+    state.write_atomic(&config.state_path)?;
+    let _ = signal_waybar(config.signal, &config.waybar_processes);
     Ok(())
 }
 
-fn handle_status(state_path: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
-    let state = WaybarState::read_from(state_path).unwrap_or_default();
-    println!("{}", serde_json::to_string(&state)?);
+/// Render text and tooltip from the configured templates
+fn render_display(state: &mut WaybarState, config: &Config) -> Result<(), TemplateError> {
+    state.text = state.render(&config.text_template()?, &config.icons);
+    state.tooltip = state.render(&config.tooltip_template()?, &config.icons);
     Ok(())
 }
 
+/// Claude provider priced from the built-in table plus the user's pricing file,
+/// with transcript cursors persisted in the configured cursor directory
+fn claude_provider(config: &Config) -> ClaudeProvider {
+    ClaudeProvider::with_pricing(pricing_table(config)).with_cursor_dir(config.cursor_dir.clone())
+}
+
+/// The built-in pricing table plus the user's pricing file
+fn pricing_table(config: &Config) -> PricingTable {
+    PricingTable::load(&config.pricing_path).unwrap_or_else(|e| {
+        eprintln!("Warning: ignoring {}: {}", config.pricing_path.display(), e);
+        PricingTable::builtin()
+    })
+}
+
+fn handle_status(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", status_json(config)?);
+    Ok(())
+}
+
+/// Waybar JSON for the state file
+fn status_json(config: &Config) -> Result<String, Box<dyn std::error::Error>> {
+    let mut state = WaybarState::read_from(&config.state_path).unwrap_or_default();
+    if state.check_activity_timeout(config.timeouts.activity_secs) {
+        render_display(&mut state, config)?;
+    }
+    Ok(state.to_waybar_json()?)
+}
+
+/// How often `watch` renews its subscription, so a restarted daemon finds it again
+const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(10);
+
+fn handle_watch(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let name = format!("llm-bridge-watch-{}", std::process::id());
+    let socket = bind_subscriber(&name)?;
+    socket.set_read_timeout(Some(RESUBSCRIBE_INTERVAL))?;
+    let subscribe = DaemonMessage::Subscribe { name };
+
+    let mut stdout = io::stdout();
+    if !send_to_daemon(&config.socket_path, &subscribe).unwrap_or(false) {
+        // No daemon yet: show the last known state until one starts
+        writeln!(stdout, "{}", status_json(config)?)?;
+    }
+
+    let mut buf = vec![0u8; 65536];
+    loop {
+        match socket.recv(&mut buf) {
+            Ok(n) => {
+                // Fails once Waybar closes the pipe, which ends the process
+                stdout.write_all(&buf[..n])?;
+                stdout.write_all(b"\n")?;
+                stdout.flush()?;
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                let _ = send_to_daemon(&config.socket_path, &subscribe);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
 fn handle_daemon(
-    log_path: &PathBuf,
-    state_path: &PathBuf,
-    signal: u8,
+    log_path: &Path,
+    config: &Config,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let (tx, rx) = channel();
 
@@ -312,21 +508,17 @@ fn handle_daemon(
 
     eprintln!("Watching {} for changes...", log_path.display());
 
-    let provider = ClaudeProvider::new();
+    let provider = claude_provider(config);
 
     loop {
         match rx.recv_timeout(Duration::from_secs(60)) {
             Ok(()) => {
                 if let Ok(usage) = provider.parse_usage(log_path) {
-                    let mut state = WaybarState::read_from(state_path).unwrap_or_default();
-                    state.tooltip = format!(
-                        "Tokens: {} in / {} out\nCost: ${:.4}",
-                        usage.input_tokens,
-                        usage.output_tokens,
-                        usage.estimated_cost
-                    );
-                    let _ = state.write_atomic(state_path);
-                    let _ = signal_waybar(signal);
+                    let mut state = WaybarState::read_from(&config.state_path).unwrap_or_default();
+                    state.set_usage(&usage);
+                    render_display(&mut state, config)?;
+                    let _ = state.write_atomic(&config.state_path);
+                    let _ = signal_waybar(config.signal, &config.waybar_processes);
                 }
             }
             Err(std::sync::mpsc::RecvTimeoutError::Timeout) => continue,
@@ -337,13 +529,7 @@ fn handle_daemon(
     Ok(())
 }
 
-fn handle_statusline(
-    state_path: &PathBuf,
-    sessions_dir: &PathBuf,
-    signal: u8,
-    format: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let config = Config::from_env();
+fn handle_statusline(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     let stdin = io::stdin();
 
     if stdin.is_terminal() {
@@ -362,122 +548,232 @@ fn handle_statusline(
     }
 
     // Parse just enough to output status line quickly
-    let status_input: StatuslineInput = serde_json::from_str(&input).unwrap_or(StatuslineInput {
-        session_id: None,
-        transcript_path: None,
-        cwd: None,
-        model: None,
-        cost: None,
-        context_window: None,
-    });
-
-    let model_name = status_input
-        .model
-        .as_ref()
-        .and_then(|m| m.display_name.as_ref().or(m.id.as_ref()))
-        .map(|s| s.as_str())
-        .unwrap_or("Claude");
-
-    let cost = status_input
-        .cost
-        .as_ref()
-        .and_then(|c| c.total_cost_usd)
-        .unwrap_or(0.0);
+    let status_input = StatuslinePayload::from_json(&input);
+    let model_name = status_input.model_name().unwrap_or("Claude");
+    let cost = status_input.total_cost().unwrap_or(0.0);
 
     // Output status line immediately (Claude is waiting for this)
     println!("{} | ${:.2}", model_name, cost);
 
     // Try to send to daemon for async state update
-    let message = DaemonMessage::Status { payload: input.clone() };
+    let message = DaemonMessage::Status {
+        session_id: status_input.session_id.clone(),
+        payload: input.clone(),
+    };
     if send_to_daemon(&config.socket_path, &message).unwrap_or(false) {
         return Ok(());
     }
 
     // Fallback: direct mode
-    let mut state = WaybarState::read_from(state_path).unwrap_or_default();
+    let mut state = WaybarState::read_from(&config.state_path).unwrap_or_default();
+    let same_session = status_input.session_id.as_deref() == Some(state.session_id.as_str());
+    let previous_cost = if same_session { state.cost } else { 0.0 };
+    state.check_activity_timeout(config.timeouts.activity_secs);
     state.model = model_name.to_string();
     state.cost = cost;
+    status_input.apply_to(&mut state, &config.context);
 
-    if let Some(ref sid) = status_input.session_id {
-        state.session_id = sid.clone();
+    // Fallback transcript parsing
+    let usage = status_input
+        .transcript_path
+        .as_ref()
+        .map(PathBuf::from)
+        .filter(|path| path.exists())
+        .and_then(|path| claude_provider(config).parse_usage(&path).ok());
+    if state.input_tokens == 0 && state.output_tokens == 0 {
+        if let Some(usage) = &usage {
+            state.input_tokens = usage.input_tokens;
+            state.output_tokens = usage.output_tokens;
+            state.cache_read = usage.cache_read;
+            state.cache_write = usage.cache_write;
+            if state.cost == 0.0 {
+                state.cost = usage.estimated_cost;
+            }
+        }
     }
-    if let Some(ref cwd) = status_input.cwd {
-        state.cwd = cwd.clone();
+
+    // Record the session's spend and show the period totals
+    let now = unix_millis() / 1000;
+    if let Ok(mut ledger) = Ledger::open_recent(&config.ledger_path) {
+        let before = Spent::new(previous_cost, &ledger.spend(now));
+        let entry = state.ledger_entry(now, usage.as_ref());
+        if snapshot_due(&ledger, &entry) {
+            let _ = ledger.record(entry);
+        }
+        state.set_spend(&ledger.spend(now));
+        alert_budgets(config, &before, &state.spent());
     }
+    state.set_budget(&config.budget);
+
+    render_display(&mut state, config)?;
+    let _ = state.write_session_file(&config.sessions_dir);
+    state.write_atomic(&config.state_path)?;
+    let _ = signal_waybar(config.signal, &config.waybar_processes);
 
-    // Extract tokens from context_window
-    if let Some(ref cw) = status_input.context_window {
-        if let Some(ref usage) = cw.current_usage {
-            state.input_tokens = usage.input_tokens.unwrap_or(0);
-            state.output_tokens = usage.output_tokens.unwrap_or(0);
-            state.cache_read = usage.cache_read_input_tokens.unwrap_or(0);
-            state.cache_write = usage.cache_creation_input_tokens.unwrap_or(0);
+    Ok(())
+}
+
+/// Shortest time between ledger snapshots of a session
+const SNAPSHOT_SECS: i64 = 60;
+
+/// Whether a statusline update should snapshot `entry`: every update would
+/// add a line, so only a new prompt or a minute passing does. The growth in
+/// between is carried by the next snapshot.
+fn snapshot_due(ledger: &Ledger, entry: &LedgerEntry) -> bool {
+    ledger
+        .session(&entry.session_id)
+        .is_none_or(|last| entry.turns != last.turns || entry.time - last.time >= SNAPSHOT_SECS)
+}
+
+/// Run the notify command for each budget that spend crossed a level of
+fn alert_budgets(config: &Config, before: &Spent, after: &Spent) {
+    for message in budget::alerts(&config.budget, before, after) {
+        if let Err(e) = budget::notify(&config.budget.notify_command, &message) {
+            eprintln!("Failed to run budget notify_command: {}", e);
         }
     }
+}
 
-    // Fallback transcript parsing
-    if state.input_tokens == 0 && state.output_tokens == 0 {
-        if let Some(transcript_path) = status_input.transcript_path.as_ref() {
-            let transcript_pathbuf = PathBuf::from(transcript_path);
-            if transcript_pathbuf.exists() {
-                let provider = ClaudeProvider::new();
-                if let Ok(usage) = provider.parse_usage(&transcript_pathbuf) {
-                    state.input_tokens = usage.input_tokens;
-                    state.output_tokens = usage.output_tokens;
-                    state.cache_read = usage.cache_read;
-                    state.cache_write = usage.cache_write;
-                    if state.cost == 0.0 {
-                        state.cost = usage.estimated_cost;
-                    }
+/// Where `budget allow` keeps its allowance, next to the ledger
+fn allowance_path(config: &Config) -> PathBuf {
+    config.ledger_path.with_file_name("budget-allow.json")
+}
+
+/// The hook output that stops a prompt or tool call while budgets are
+/// enforced and one is spent
+fn budget_block(config: &Config, event_str: &str, session_id: Option<&str>) -> Option<serde_json::Value> {
+    let hook_event_name = match event_str {
+        "submit" => "UserPromptSubmit",
+        "tool-start" => "PreToolUse",
+        _ => return None,
+    };
+    if !config.budget.enforce {
+        return None;
+    }
+    let reason = budget_block_reason(config, session_id)?;
+    Some(block_decision(hook_event_name, &reason))
+}
+
+/// Why new prompts and tool calls are blocked: a budget is spent and no
+/// allowance is active. Spend comes from the ledger, which the statusline
+/// keeps current.
+fn budget_block_reason(config: &Config, session_id: Option<&str>) -> Option<String> {
+    let now = unix_millis() / 1000;
+    if Allowance::read(&allowance_path(config)).is_some_and(|a| a.is_active(now)) {
+        return None;
+    }
+    let ledger = Ledger::open_recent(&config.ledger_path).ok()?;
+    let session_cost = session_id.and_then(|sid| ledger.session(sid)).map_or(0.0, |last| last.cost);
+    let budget = budget::exceeded(&config.budget, &Spent::new(session_cost, &ledger.spend(now)))?;
+    Some(format!(
+        "{}. Blocked by waybar-llm-bridge; run `waybar-llm-bridge budget allow --for 1h` to continue.",
+        budget.message(BudgetLevel::Over)
+    ))
+}
+
+fn parse_duration_arg(text: &str) -> Result<u64, String> {
+    budget::parse_duration(text).ok_or_else(|| format!("invalid duration '{}', expected e.g. 30m, 1h or 1h30m", text))
+}
+
+fn handle_budget(action: BudgetAction, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let now = unix_millis() / 1000;
+    let path = allowance_path(config);
+    match action {
+        BudgetAction::Status => {
+            let spend = Ledger::open_recent(&config.ledger_path)?.spend(now);
+            let budgets = budget::budgets(&config.budget, &Spent::new(0.0, &spend));
+            if budgets.is_empty() {
+                println!("No budgets set; add daily, weekly or session limits under [budget] in the config file");
+            }
+            for budget in budgets {
+                if budget.name == "session" {
+                    println!("{:<8} ${:.2} per session", budget.name, budget.limit);
+                } else {
+                    println!(
+                        "{:<8} ${:.2} of ${:.2} ({:.0}%)",
+                        budget.name,
+                        budget.spent,
+                        budget.limit,
+                        budget.pct()
+                    );
                 }
             }
+            match Allowance::read(&path).filter(|a| a.is_active(now)) {
+                Some(allowance) => println!(
+                    "Enforcement lifted for another {}",
+                    budget::format_duration((allowance.until - now) as u64)
+                ),
+                None if config.budget.enforce => println!("Enforced: spent budgets block prompts and tool calls"),
+                None => println!("Not enforced: set budget.enforce = true to block prompts and tool calls"),
+            }
+        }
+        BudgetAction::Allow { duration } => {
+            Allowance { until: now + duration as i64 }.write(&path)?;
+            println!("Budgets will not block prompts or tool calls for {}", budget::format_duration(duration));
         }
+        BudgetAction::Revoke => match std::fs::remove_file(&path) {
+            Ok(()) => println!("Budgets are enforced again"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => println!("No allowance to revoke"),
+            Err(e) => return Err(e.into()),
+        },
     }
+    Ok(())
+}
 
-    state.text = state.compute_text(format);
-    state.tooltip = state.compute_tooltip();
-    let _ = state.write_session_file(sessions_dir);
-    state.write_atomic(state_path)?;
-    let _ = signal_waybar(signal);
-
+fn handle_import(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut ledger = Ledger::open(&config.ledger_path)?;
+    let summary = import_transcripts(&config.transcript_dir, &pricing_table(config), &mut ledger)?;
+
+    println!("Read {} transcripts from {}", summary.transcripts, config.transcript_dir.display());
+    println!(
+        "Added {} snapshots from {} sessions, ${:.2}, to {}",
+        summary.snapshots,
+        summary.sessions,
+        summary.cost,
+        config.ledger_path.display()
+    );
+    if summary.skipped > 0 {
+        println!("Skipped {} sessions recorded while they ran", summary.skipped);
+    }
     Ok(())
 }
 
-fn handle_daemon_aggregate(
-    sessions_dir: &PathBuf,
-    state_path: &PathBuf,
-    signal: u8,
+fn handle_report(
+    since: Option<&str>,
+    until: Option<&str>,
+    group_by: ReportGroupBy,
+    output: ReportFormat,
+    config: &Config,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    use aggregator::SessionAggregator;
-
-    let aggregator = SessionAggregator::new(
-        sessions_dir.clone(),
-        state_path.clone(),
-        signal,
-    );
+    let day = |date: Option<&str>| -> Result<Option<i64>, String> {
+        date.map(|d| report::parse_date(d).ok_or_else(|| format!("invalid date '{}', expected YYYY-MM-DD", d)))
+            .transpose()
+    };
+    let group_by = match group_by {
+        ReportGroupBy::Day => GroupBy::Day,
+        ReportGroupBy::Project => GroupBy::Project,
+        ReportGroupBy::Model => GroupBy::Model,
+        ReportGroupBy::Session => GroupBy::Session,
+    };
 
-    aggregator.watch()
+    let entries = read_entries(&config.ledger_path)?;
+    let report = report::build(&entries, group_by, day(since)?, day(until)?);
+    match output {
+        ReportFormat::Table => println!("{}", report.to_table()),
+        ReportFormat::Json => println!("{}", report.to_json()?),
+        ReportFormat::Csv => println!("{}", report.to_csv()),
+    }
+    Ok(())
 }
 
-fn handle_daemon_socket(
-    state_path: &PathBuf,
-    sessions_dir: &PathBuf,
-    signal: u8,
-    format: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+fn handle_daemon_socket(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     use daemon::Daemon;
 
-    let config = Config::from_env();
+    let mut daemon = Daemon::new(config.clone())?;
 
-    let mut daemon = Daemon::new(
-        config.socket_path,
-        state_path.clone(),
-        sessions_dir.clone(),
-        signal,
-        format.to_string(),
-    );
-
-    daemon.run()?;
+    // One thread is plenty: the daemon only waits on its socket and timers
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(daemon.run())?;
     Ok(())
 }
 
@@ -512,7 +808,7 @@ fn handle_install_hooks(dry_run: bool) -> Result<(), Box<dyn std::error::Error>>
             "matcher": "*",
             "hooks": [{
                 "type": "command",
-                "command": format!("{} event --type tool-start --tool \"$CLAUDE_TOOL_NAME\"", bin_path)
+                "command": format!("{} event --type tool-start", bin_path)
             }]
         }],
         "PostToolUse": [{
@@ -528,6 +824,41 @@ fn handle_install_hooks(dry_run: bool) -> Result<(), Box<dyn std::error::Error>>
                 "type": "command",
                 "command": format!("{} event --type stop", bin_path)
             }]
+        }],
+        "Notification": [{
+            "matcher": "",
+            "hooks": [{
+                "type": "command",
+                "command": format!("{} event --type notification", bin_path)
+            }]
+        }],
+        "SubagentStop": [{
+            "matcher": "",
+            "hooks": [{
+                "type": "command",
+                "command": format!("{} event --type subagent-stop", bin_path)
+            }]
+        }],
+        "PreCompact": [{
+            "matcher": "",
+            "hooks": [{
+                "type": "command",
+                "command": format!("{} event --type pre-compact", bin_path)
+            }]
+        }],
+        "SessionStart": [{
+            "matcher": "",
+            "hooks": [{
+                "type": "command",
+                "command": format!("{} event --type session-start", bin_path)
+            }]
+        }],
+        "SessionEnd": [{
+            "matcher": "",
+            "hooks": [{
+                "type": "command",
+                "command": format!("{} event --type session-end", bin_path)
+            }]
         }]
     });
 
@@ -677,3 +1008,101 @@ fn handle_uninstall_hooks(dry_run: bool) -> Result<(), Box<dyn std::error::Error
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spent_budget_blocks_prompts_and_tool_calls() {
+        let dir = std::env::temp_dir().join(format!("llm-bridge-enforce-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut config = Config {
+            ledger_path: dir.join("ledger.jsonl"),
+            ..Default::default()
+        };
+        config.budget.daily = 5.0;
+        config.budget.enforce = true;
+        let now = unix_millis() / 1000;
+        Ledger::new(&config.ledger_path)
+            .record(LedgerEntry {
+                time: now,
+                session_id: "a".to_string(),
+                cost: 6.0,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let denied = budget_block(&config, "tool-start", Some("a")).unwrap();
+        assert_eq!(denied["hookSpecificOutput"]["permissionDecision"], "deny");
+        let reason = denied["hookSpecificOutput"]["permissionDecisionReason"].as_str().unwrap();
+        assert!(reason.contains("budget allow --for 1h"), "{}", reason);
+        let blocked = budget_block(&config, "submit", Some("b")).unwrap();
+        assert_eq!(blocked["decision"], "block");
+        assert!(budget_block(&config, "stop", Some("a")).is_none());
+
+        // An allowance lets work through until it ends
+        Allowance { until: now + 3600 }.write(&allowance_path(&config)).unwrap();
+        assert!(budget_block(&config, "tool-start", Some("a")).is_none());
+        Allowance { until: now - 1 }.write(&allowance_path(&config)).unwrap();
+        assert!(budget_block(&config, "submit", Some("a")).is_some());
+
+        config.budget.enforce = false;
+        assert!(budget_block(&config, "submit", Some("a")).is_none());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_direct_mode_snapshots_once_a_minute_or_per_prompt() {
+        let path = std::env::temp_dir().join(format!("llm-bridge-snapshots-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut ledger = Ledger::new(&path);
+        let entry = |time, cost, turns| LedgerEntry {
+            time,
+            session_id: "a".to_string(),
+            cost,
+            turns,
+            ..Default::default()
+        };
+
+        assert!(snapshot_due(&ledger, &entry(1000, 1.0, 1)));
+        ledger.record(entry(1000, 1.0, 1)).unwrap();
+        assert!(!snapshot_due(&ledger, &entry(1030, 1.5, 1)));
+        assert!(snapshot_due(&ledger, &entry(1030, 1.5, 2)));
+        assert!(snapshot_due(&ledger, &entry(1060, 1.5, 1)));
+
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(llm_bridge_core::ledger::index_path(&path)).ok();
+    }
+
+    #[test]
+    fn test_large_tool_response_still_reaches_the_daemon() {
+        let path = std::env::temp_dir().join(format!("llm-bridge-event-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let daemon = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        let payload = serde_json::json!({
+            "hook_event_name": "PostToolUse",
+            "session_id": "a",
+            "tool_name": "Read",
+            "tool_response": {"content": "x".repeat(512 * 1024)},
+        })
+        .to_string();
+        let hook = ClaudeProvider::default().parse_event("tool-end", Some(&payload)).unwrap();
+
+        let message = event_message("tool-end", &hook, Some("Read".to_string()), Some("a".to_string()));
+        assert!(send_to_daemon(&path, &message).unwrap());
+
+        // Read as the daemon does, into a 64 KiB buffer
+        let mut buf = vec![0u8; 65536];
+        let n = daemon.recv(&mut buf).unwrap();
+        let DaemonMessage::Event(event) = DaemonMessage::decode(std::str::from_utf8(&buf[..n]).unwrap()).unwrap()
+        else {
+            panic!("expected an event");
+        };
+        assert_eq!((event.event_type.as_str(), event.tool.as_deref()), ("tool-end", Some("Read")));
+        assert_eq!(event.session_id.as_deref(), Some("a"));
+
+        std::fs::remove_file(&path).ok();
+    }
+}
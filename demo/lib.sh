@@ -20,6 +20,7 @@ else
     # Use isolated demo paths
     export LLM_BRIDGE_STATE_PATH="${LLM_BRIDGE_STATE_PATH:-/tmp/llm_demo_state.json}"
     export LLM_BRIDGE_SESSIONS_DIR="${LLM_BRIDGE_SESSIONS_DIR:-/tmp/llm_demo_sessions}"
+    export LLM_BRIDGE_SOCKET_PATH="${LLM_BRIDGE_SOCKET_PATH:-/tmp/llm_demo.sock}"
 fi
 export STATE_FILE="$LLM_BRIDGE_STATE_PATH"
 export SESSIONS_DIR="$LLM_BRIDGE_SESSIONS_DIR"
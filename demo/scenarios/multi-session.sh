@@ -16,9 +16,10 @@ echo -e "\n${YELLOW}═══ Multi-Session Demo ═══${NC}\n"
 echo "This demo shows aggregated state from multiple Claude sessions."
 echo
 
-# Start the aggregator daemon in background
-echo "Starting aggregator daemon..."
-$BIN daemon --aggregate &
+# Start the daemon in background, counting sessions per activity icon
+echo "Starting daemon..."
+LLM_BRIDGE_FORMAT='{?icon_counts}{icon_counts}{/icon_counts}{!icon_counts}{icon} {activity}{/icon_counts} | ${cost:.2}' \
+    $BIN daemon &
 DAEMON_PID=$!
 sleep 0.5  # Give daemon time to start
 